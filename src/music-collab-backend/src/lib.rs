@@ -1,11 +1,20 @@
 // (But for Rust, use: DKeeper-App-Blockchain/src/dkeeper_backend/lib.rs)
 
-use candid::{CandidType, Deserialize};
-use std::collections::HashMap;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext,
+    TransformFunc,
 };
 
+// The system API only exists on the IC; unit tests run against tests::mock instead
+#[cfg(not(test))]
+use ic_cdk::api::management_canister::http_request::http_request;
+#[cfg(not(test))]
+use ic_cdk::api::{caller, canister_balance128, id as canister_id, stable::stable_size, time};
+#[cfg(test)]
+use tests::mock::{caller, canister_balance128, canister_id, http_request, icrc1_fee, icrc1_transfer, stable_size, time};
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct MusicProject {
     pub id: u64,
@@ -14,193 +23,3669 @@ pub struct MusicProject {
     pub owner: String,
     pub contributors: Vec<String>,
     pub tracks: Vec<Track>,
+    pub created_at: u64,
+    // Archived projects keep their data but drop out of the default listings
+    pub is_archived: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Track {
+    pub id: u64,
+    pub name: String,
+    pub ipfs_hash: String,
+    pub uploaded_by: String,
+    pub timestamp: u64,
+    pub duration_secs: Option<u32>,
+    pub genre: Option<String>,
+    pub bpm: Option<u16>,
+    pub play_count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TrackMetadata {
+    pub duration_secs: Option<u32>,
+    pub genre: Option<String>,
+    pub bpm: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NFTMetadata {
+    pub id: u64,
+    pub name: String,
+    pub description: String,
+    pub image_url: String,
+    // creator and royalty_percentage are fixed at mint; transfers and sales only move
+    // current_owner, so every resale's royalty goes to the original creator
+    pub creator: String,
+    pub current_owner: String,
+    pub project_id: u64,
+    pub price: u64,
+    pub category: String,
+    pub royalty_percentage: u8,
+    pub is_for_sale: bool,
+    pub created_at: u64,
+    pub view_count: u64,
+    pub sale_history: Vec<Transaction>,
+    pub animation_url: Option<String>,
+    pub audio_preview_url: Option<String>,
+    // IPFS hash of the track this NFT was minted from
+    pub ipfs_hash: Option<String>,
+    // Hidden by moderation; excluded from listings and purchases
+    pub is_hidden: bool,
+    // Set by the creator at mint; other owners can't sell below it
+    pub min_resale_price: Option<u64>,
+    // When the NFT was last listed or repriced; 0 if it has never been listed
+    pub last_listed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Transaction {
+    pub id: u64,
+    pub nft_id: u64,
+    pub from: String,
+    pub to: String,
+    pub price: u64,
+    pub timestamp: u64,
+    pub transaction_type: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum EventKind {
+    Minted { nft_id: u64 },
+    Sold { nft_id: u64, buyer: String, price: u64 },
+    Purchased { nft_id: u64, seller: String, price: u64 },
+    RoyaltyReceived { nft_id: u64, amount: u64 },
+    TransferReceived { nft_id: u64, from: String },
+}
+
+// index increases by one per event for a user, so clients resume with the last index + 1
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Event {
+    pub index: u64,
+    pub timestamp: u64,
+    pub kind: EventKind,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct EventQueue {
+    next_index: u64,
+    events: VecDeque<Event>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SearchResults {
+    pub projects: Vec<MusicProject>,
+    pub nfts: Vec<NFTMetadata>,
+}
+
+// Full dataset for off-chain backup; settings and secrets are not included
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StateSnapshot {
+    pub projects: Vec<MusicProject>,
+    pub nfts: Vec<NFTMetadata>,
+    pub transactions: Vec<Transaction>,
+    pub royalty_payments: Vec<RoyaltyPayment>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProjectStats {
+    pub project_id: u64,
+    pub track_count: u64,
+    pub contributor_count: u64,
+    pub nft_count: u64,
+    pub sales_volume: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Profile {
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+    pub bio: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HealthReport {
+    pub cycles_balance: u128,
+    pub project_count: u64,
+    pub nft_count: u64,
+    pub transaction_count: u64,
+    pub royalty_payment_count: u64,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub uptime_ns: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SalesSummary {
+    pub count: u64,
+    pub total_volume: u64,
+    pub average_price: u64,
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    pub total: u64,
+}
+
+#[derive(Clone)]
+struct CompletedPurchase {
+    completed_at: u64,
+    nft_id: u64,
+    result: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NftPage {
+    pub nfts: Vec<NFTMetadata>,
+    pub next_cursor: Option<u64>,
+    // The page stopped short of `limit` to stay under the response size budget
+    pub truncated: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EngagementScore {
+    pub views: u64,
+    // How many users have the NFT on their wishlist
+    pub favorites: u64,
+    pub sales: u64,
+    pub score: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct UserSummary {
+    pub created: u64,
+    pub owned: u64,
+    pub owned_for_sale: u64,
+    pub royalties_earned: u64,
+    pub projects_owned: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProvenanceReport {
+    pub nft_id: u64,
+    pub is_consistent: bool,
+    pub transactions_checked: u64,
+    pub issues: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ActivityItem {
+    pub transaction: Transaction,
+    pub nft_name: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PurchasePreview {
+    pub nft_id: u64,
+    pub sale_price: u64,
+    pub royalty_amount: u64,
+    pub seller_amount: u64,
+    pub seller: String,
+    pub royalty_recipient: String,
+    pub platform_fee: u64,
+    pub fee_recipient: Option<String>,
+}
+
+// Failures of the marketplace methods, as variants clients can match on;
+// Display gives the human-readable message
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum MarketError {
+    NotFound,
+    Unauthorized(String),
+    NotForSale,
+    AlreadyOwner,
+    Blocked,
+    PurchaseInProgress,
+    ListingChanged,
+    InvalidPrice(String),
+    InvalidInput(String),
+    PaymentFailed(String),
+}
+
+impl std::fmt::Display for MarketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarketError::NotFound => write!(f, "NFT not found"),
+            MarketError::Unauthorized(message) => write!(f, "{}", message),
+            MarketError::NotForSale => write!(f, "NFT is not for sale"),
+            MarketError::AlreadyOwner => write!(f, "You already own this NFT"),
+            MarketError::Blocked => write!(f, "Your account has been blocked from this action"),
+            MarketError::PurchaseInProgress => write!(f, "Another purchase of this NFT is in progress"),
+            MarketError::ListingChanged => {
+                write!(f, "NFT changed while the payment was in flight; the purchase was cancelled and refunded")
+            }
+            MarketError::InvalidPrice(message) => write!(f, "{}", message),
+            MarketError::InvalidInput(message) => write!(f, "{}", message),
+            MarketError::PaymentFailed(message) => write!(f, "Payment failed: {}", message),
+        }
+    }
+}
+
+// The marketplace's cut of a sale, recorded alongside the sale transaction
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FeePayment {
+    pub transaction_id: u64,
+    pub nft_id: u64,
+    pub recipient: String,
+    pub amount: u64,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PlatformFee {
+    pub percentage: u8,
+    pub recipient: Option<String>,
+}
+
+// A flat charge per mint, in ledger tokens
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct MintFee {
+    pub amount: u64,
+    pub recipient: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct MarketplaceStats {
+    pub total_nfts: u64,
+    pub nfts_for_sale: u64,
+    pub sale_count: u64,
+    pub total_volume: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum ReportAction {
+    Dismiss,
+    HideNft,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Report {
+    pub id: u64,
+    pub nft_id: u64,
+    pub reporter: String,
+    pub reason: String,
+    pub timestamp: u64,
+    // None while the report is open
+    pub resolution: Option<ReportAction>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RoyaltyPayment {
+    pub transaction_id: u64,
+    pub nft_id: u64,
+    pub recipient: String,
+    pub amount: u64,
+    pub timestamp: u64,
+    // "pending" until the ledger transfer, then "paid" or "failed"
+    pub status: String,
+    pub status_updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Collection {
+    pub id: u64,
+    pub name: String,
+    pub description: String,
+    pub creator: String,
+    pub nft_ids: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProjectExport {
+    pub project: MusicProject,
+    pub tracks: Vec<Track>,
+    pub nfts: Vec<NFTMetadata>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum MinPriceIncrement {
+    Absolute(u64),
+    Percentage(u8),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IpfsMeta {
+    pub content_type: Option<String>,
+    pub content_length: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PinStatus {
+    pub pinned: bool,
+    pub size: u64,
+    pub pin_date: Option<String>,
+}
+
+// Private and never returned by any method; CandidType only so it survives upgrades
+#[derive(CandidType, Deserialize, Clone)]
+struct PinataCredentials {
+    api_key: String,
+    secret_key: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UploadRateLimit {
+    pub max_uploads: u32,
+    pub window_secs: u64,
+}
+
+// ICRC-1 ledger types, as defined by the ICRC-1 standard
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+// A payout owed from the settlement account that the ledger refused; kept for a retry
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingPayout {
+    pub nft_id: u64,
+    pub to: Account,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PinataUploadRequest {
+    pub file_data: Vec<u8>,
+    pub file_name: String,
+    pub content_type: String,
+    pub api_key: String,
+    pub secret_key: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PinataUploadResponse {
+    pub success: bool,
+    pub ipfs_hash: String,
+    pub pin_size: u64,
+    pub error: Option<String>,
+}
+
+const MAX_FEATURED_NFTS: usize = 20;
+const DEFAULT_ROYALTY_PERCENTAGE: u8 = 10;
+const MAX_ROYALTY_PERCENTAGE: u8 = 50;
+// A repeat view of the same NFT by the same caller inside this window is not counted
+const DEFAULT_VIEW_DEDUP_WINDOW_NS: u64 = 60 * 60 * 1_000_000_000;
+// How long a completed purchase can be replayed by retrying with its idempotency key
+const IDEMPOTENCY_WINDOW_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+const MAX_QUERY_LIMIT: u64 = 100;
+const DEFAULT_MAX_TRACKS_PER_PROJECT: u64 = 500;
+// NFT listings stop adding records past this many encoded bytes, well inside the 2MB reply limit
+const RESPONSE_SIZE_BUDGET: usize = 1_500_000;
+const ENGAGEMENT_VIEW_WEIGHT: u64 = 1;
+const ENGAGEMENT_FAVORITE_WEIGHT: u64 = 5;
+const ENGAGEMENT_SALE_WEIGHT: u64 = 20;
+const MIN_SEARCH_QUERY_LEN: usize = 2;
+const MAX_INLINE_HISTORY: usize = 50;
+const MAX_EVENTS_PER_USER: usize = 100;
+const MAX_TITLE_LEN: usize = 200;
+const MAX_NAME_LEN: usize = 200;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+const MAX_CID_LEN: usize = 128;
+const MAX_URL_LEN: usize = 2048;
+const MAX_IMAGE_DATA_URL_LEN: usize = 1_000_000;
+const DEFAULT_IPFS_GATEWAY: &str = "https://gateway.pinata.cloud/ipfs/";
+const HTTP_OUTCALL_CYCLES: u128 = 2_000_000_000;
+const TRANSACTION_TYPES: [&str; 3] = ["mint", "sale", "transfer"];
+const DEFAULT_CATEGORIES: [&str; 6] = ["music", "beat", "vocal", "instrumental", "remix", "sample"];
+
+// Trending ranks NFTs by activity inside this window
+const TRENDING_WINDOW_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const TRENDING_VIEW_WEIGHT: u64 = 1;
+const TRENDING_SALE_WEIGHT: u64 = 10;
+// Upper bound on the rolling view log; older views are dropped first
+const MAX_VIEW_LOG_ENTRIES: usize = 10_000;
+
+thread_local! {
+    // Both keyed in id order, so listings are deterministic and cursors can range-scan
+    static PROJECTS: std::cell::RefCell<BTreeMap<u64, MusicProject>> = const { std::cell::RefCell::new(BTreeMap::new()) };
+    static NFTS: std::cell::RefCell<BTreeMap<u64, NFTMetadata>> = const { std::cell::RefCell::new(BTreeMap::new()) };
+    static COLLECTIONS: std::cell::RefCell<HashMap<u64, Collection>> = std::cell::RefCell::new(HashMap::new());
+    static TRANSACTIONS: std::cell::RefCell<Vec<Transaction>> = const { std::cell::RefCell::new(Vec::new()) };
+    static ROYALTY_PAYMENTS: std::cell::RefCell<Vec<RoyaltyPayment>> = const { std::cell::RefCell::new(Vec::new()) };
+    // Maintained incrementally; rebuilt from the restored data after an upgrade
+    static MARKET_STATS: std::cell::RefCell<MarketplaceStats> = std::cell::RefCell::new(MarketplaceStats::default());
+    static NEXT_TRANSACTION_ID: std::cell::RefCell<u64> = const { std::cell::RefCell::new(1) };
+    static RECENT_VIEWS: std::cell::RefCell<HashMap<(u64, String), u64>> = std::cell::RefCell::new(HashMap::new());
+    // RECENT_VIEWS entries oldest first, so expired ones are dropped from the front
+    static RECENT_VIEW_ORDER: std::cell::RefCell<VecDeque<(u64, (u64, String))>> = const { std::cell::RefCell::new(VecDeque::new()) };
+    // Successful purchases keyed by (buyer, idempotency key)
+    static COMPLETED_PURCHASES: std::cell::RefCell<HashMap<(String, String), CompletedPurchase>> = std::cell::RefCell::new(HashMap::new());
+    static VIEW_LOG: std::cell::RefCell<VecDeque<(u64, u64)>> = const { std::cell::RefCell::new(VecDeque::new()) };
+    static NEXT_ID: std::cell::RefCell<u64> = const { std::cell::RefCell::new(1) };
+    static NEXT_NFT_ID: std::cell::RefCell<u64> = const { std::cell::RefCell::new(1) };
+    static NEXT_COLLECTION_ID: std::cell::RefCell<u64> = const { std::cell::RefCell::new(1) };
+    static UPLOAD_RATE_LIMIT: std::cell::RefCell<UploadRateLimit> = const {
+        std::cell::RefCell::new(UploadRateLimit { max_uploads: 5, window_secs: 60 })
+    };
+    // caller -> (window start, uploads in that window)
+    static UPLOAD_COUNTS: std::cell::RefCell<HashMap<String, (u64, u32)>> = std::cell::RefCell::new(HashMap::new());
+    static PINATA_CREDENTIALS: std::cell::RefCell<Option<PinataCredentials>> = const { std::cell::RefCell::new(None) };
+    static PENDING_UNPINS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+    static LEDGER: std::cell::RefCell<Option<Principal>> = const { std::cell::RefCell::new(None) };
+    static ADMIN: std::cell::RefCell<String> = const { std::cell::RefCell::new(String::new()) };
+    static MIN_PRICE_INCREMENT: std::cell::RefCell<Option<MinPriceIncrement>> = const { std::cell::RefCell::new(None) };
+    static CATEGORIES: std::cell::RefCell<Vec<String>> = std::cell::RefCell::new(
+        DEFAULT_CATEGORIES.iter().map(|category| category.to_string()).collect()
+    );
+    static REQUIRE_PROJECT_LINK: std::cell::RefCell<bool> = const { std::cell::RefCell::new(false) };
+    static FEATURED: std::cell::RefCell<Vec<u64>> = const { std::cell::RefCell::new(Vec::new()) };
+    // principal -> wished-for nft ids, in the order they were added
+    static WISHLISTS: std::cell::RefCell<HashMap<String, Vec<u64>>> = std::cell::RefCell::new(HashMap::new());
+    // Pending contributor invites: project id -> invited principals
+    static INVITES: std::cell::RefCell<HashMap<u64, Vec<String>>> = std::cell::RefCell::new(HashMap::new());
+    static EVENTS: std::cell::RefCell<HashMap<String, EventQueue>> = std::cell::RefCell::new(HashMap::new());
+    static REPORTS: std::cell::RefCell<Vec<Report>> = const { std::cell::RefCell::new(Vec::new()) };
+    static NEXT_REPORT_ID: std::cell::RefCell<u64> = const { std::cell::RefCell::new(1) };
+    static BLACKLIST: std::cell::RefCell<HashSet<String>> = std::cell::RefCell::new(HashSet::new());
+    // NFTs whose payment is being collected; transient, like the view dedup state
+    static PURCHASES_IN_FLIGHT: std::cell::RefCell<HashSet<u64>> = std::cell::RefCell::new(HashSet::new());
+    static PENDING_PAYOUTS: std::cell::RefCell<Vec<PendingPayout>> = const { std::cell::RefCell::new(Vec::new()) };
+    static DEFAULT_ROYALTY: std::cell::RefCell<u8> = const { std::cell::RefCell::new(DEFAULT_ROYALTY_PERCENTAGE) };
+    static PLATFORM_FEE: std::cell::RefCell<PlatformFee> = const {
+        std::cell::RefCell::new(PlatformFee { percentage: 0, recipient: None })
+    };
+    static MINT_FEE: std::cell::RefCell<MintFee> = const {
+        std::cell::RefCell::new(MintFee { amount: 0, recipient: None })
+    };
+    static FEE_PAYMENTS: std::cell::RefCell<Vec<FeePayment>> = const { std::cell::RefCell::new(Vec::new()) };
+    static PROFILES: std::cell::RefCell<HashMap<String, Profile>> = std::cell::RefCell::new(HashMap::new());
+    // When this code started running, i.e. the install or the last upgrade
+    static STARTED_AT: std::cell::RefCell<u64> = const { std::cell::RefCell::new(0) };
+    static IPFS_GATEWAY: std::cell::RefCell<String> = std::cell::RefCell::new(DEFAULT_IPFS_GATEWAY.to_string());
+    static MAX_TRACKS_PER_PROJECT: std::cell::RefCell<u64> = const { std::cell::RefCell::new(DEFAULT_MAX_TRACKS_PER_PROJECT) };
+    static VIEW_DEDUP_WINDOW: std::cell::RefCell<u64> = const { std::cell::RefCell::new(DEFAULT_VIEW_DEDUP_WINDOW_NS) };
+}
+
+fn caller_text() -> String {
+    caller().to_text()
+}
+
+// Principals arriving as text are trimmed and re-encoded in canonical form before
+// they are stored or compared, so padded or differently-cased copies still match
+fn normalize_principal(field: &str, principal: &str) -> Result<String, String> {
+    Principal::from_text(principal.trim())
+        .map(|principal| principal.to_text())
+        .map_err(|_| format!("{} is not a valid principal", field))
+}
+
+// Appends a transaction to both the NFT's own history and the global ledger. The
+// NFT keeps only its MAX_INLINE_HISTORY most recent entries; the ledger keeps all.
+fn record_transaction(nft: &mut NFTMetadata, transaction_type: &str, from: String, to: String, price: u64) -> u64 {
+    let id = NEXT_TRANSACTION_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        let current = *id;
+        *id += 1;
+        current
+    });
+    let transaction = Transaction {
+        id,
+        nft_id: nft.id,
+        from,
+        to,
+        price,
+        timestamp: time(),
+        transaction_type: transaction_type.to_string(),
+    };
+    nft.sale_history.push(transaction.clone());
+    if nft.sale_history.len() > MAX_INLINE_HISTORY {
+        let excess = nft.sale_history.len() - MAX_INLINE_HISTORY;
+        nft.sale_history.drain(..excess);
+    }
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().push(transaction));
+    id
+}
+
+// Holds a buyer's payment between collection and payout; escrow subaccounts start
+// with a principal length of at most 29, so this can never collide with one
+const SETTLEMENT_SUBACCOUNT: [u8; 32] = [0xff; 32];
+
+// Escrow subaccount of this canister holding a buyer's deposited funds:
+// the principal's length followed by its bytes, zero padded
+fn escrow_subaccount(principal: &Principal) -> [u8; 32] {
+    let bytes = principal.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+fn own_account(subaccount: [u8; 32]) -> Account {
+    Account { owner: canister_id(), subaccount: Some(subaccount.to_vec()) }
+}
+
+fn principal_account(principal: &str) -> Result<Account, String> {
+    let owner = Principal::from_text(principal).map_err(|_| format!("{} is not a valid principal", principal))?;
+    Ok(Account { owner, subaccount: None })
+}
+
+#[cfg(not(test))]
+async fn icrc1_fee(ledger: Principal) -> Result<u64, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {:?} {}", code, message))?;
+    u64::try_from(fee.0).map_err(|_| "Ledger fee is too large".to_string())
+}
+
+#[cfg(not(test))]
+async fn icrc1_transfer(ledger: Principal, from_subaccount: [u8; 32], to: Account, amount: u64) -> Result<Nat, String> {
+    let arg = TransferArg {
+        from_subaccount: Some(from_subaccount.to_vec()),
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {:?} {}", code, message))?;
+    result.map_err(|error| format!("Ledger rejected transfer: {:?}", error))
+}
+
+// Queues an event for a user, dropping their oldest events beyond MAX_EVENTS_PER_USER
+fn push_event(user: &str, kind: EventKind) {
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let queue = events.entry(user.to_string()).or_default();
+        queue.events.push_back(Event {
+            index: queue.next_index,
+            timestamp: time(),
+            kind,
+        });
+        queue.next_index += 1;
+        while queue.events.len() > MAX_EVENTS_PER_USER {
+            queue.events.pop_front();
+        }
+    });
+}
+
+// The caller's events with index >= since_index, oldest first
+#[ic_cdk::query]
+fn get_events(since_index: u64) -> Vec<Event> {
+    EVENTS.with(|events| {
+        events.borrow()
+            .get(&caller_text())
+            .map(|queue| queue.events.iter().filter(|event| event.index >= since_index).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+#[ic_cdk::update]
+fn report_nft(nft_id: u64, reason: String) -> Result<u64, String> {
+    validate_text("Reason", &reason, MAX_DESCRIPTION_LEN, true)?;
+    if !NFTS.with(|nfts| nfts.borrow().contains_key(&nft_id)) {
+        return Err("NFT not found".to_string());
+    }
+    let reporter = caller_text();
+    REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        if reports.iter().any(|r| r.nft_id == nft_id && r.reporter == reporter && r.resolution.is_none()) {
+            return Err("You already have an open report for this NFT".to_string());
+        }
+        let id = NEXT_REPORT_ID.with(|id| {
+            let mut id = id.borrow_mut();
+            let current = *id;
+            *id += 1;
+            current
+        });
+        reports.push(Report {
+            id,
+            nft_id,
+            reporter,
+            reason: reason.trim().to_string(),
+            timestamp: time(),
+            resolution: None,
+        });
+        Ok(id)
+    })
+}
+
+#[ic_cdk::query]
+fn get_reports() -> Result<Vec<Report>, String> {
+    require_admin()?;
+    Ok(REPORTS.with(|reports| reports.borrow().clone()))
+}
+
+#[ic_cdk::update]
+fn resolve_report(report_id: u64, action: ReportAction) -> Result<String, String> {
+    require_admin()?;
+    let nft_id = REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        let report = reports.iter_mut().find(|r| r.id == report_id).ok_or("Report not found")?;
+        if report.resolution.is_some() {
+            return Err("Report has already been resolved".to_string());
+        }
+        report.resolution = Some(action.clone());
+        Ok(report.nft_id)
+    })?;
+
+    if action == ReportAction::HideNft {
+        NFTS.with(|nfts| {
+            if let Some(nft) = nfts.borrow_mut().get_mut(&nft_id) {
+                nft.is_hidden = true;
+                set_for_sale(nft, false);
+            }
+        });
+        return Ok(format!("Report {} resolved; NFT {} hidden", report_id, nft_id));
+    }
+    Ok(format!("Report {} dismissed", report_id))
+}
+
+// Undoes a HideNft resolution. The NFT stays off the market until its owner lists it again.
+#[ic_cdk::update]
+fn unhide_nft(nft_id: u64) -> Result<String, String> {
+    require_admin()?;
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or("NFT not found")?;
+        if !nft.is_hidden {
+            return Err("NFT is not hidden".to_string());
+        }
+        nft.is_hidden = false;
+        Ok(format!("NFT {} is visible again", nft_id))
+    })
+}
+
+// Guard for privileged methods: only the canister admin may proceed
+fn require_admin() -> Result<(), String> {
+    let caller = caller_text();
+    ADMIN.with(|admin| {
+        if *admin.borrow() == caller {
+            Ok(())
+        } else {
+            Err("Only the canister admin can perform this action".to_string())
+        }
+    })
+}
+
+fn sorted_projects() -> Vec<MusicProject> {
+    PROJECTS.with(|projects| projects.borrow().values().cloned().collect())
+}
+
+fn sorted_nfts() -> Vec<NFTMetadata> {
+    NFTS.with(|nfts| nfts.borrow().values().cloned().collect())
+}
+
+fn page<T: Clone>(items: &[T], offset: u64, limit: u64) -> Vec<T> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    items.iter().skip(offset as usize).take(limit).cloned().collect()
+}
+
+// Everything in one response; use the export_*_chunk methods once this outgrows the reply size limit
+#[ic_cdk::query]
+fn export_state() -> Result<StateSnapshot, String> {
+    require_admin()?;
+    Ok(StateSnapshot {
+        projects: sorted_projects(),
+        nfts: sorted_nfts(),
+        transactions: TRANSACTIONS.with(|transactions| transactions.borrow().clone()),
+        royalty_payments: ROYALTY_PAYMENTS.with(|payments| payments.borrow().clone()),
+    })
+}
+
+#[ic_cdk::query]
+fn export_projects_chunk(offset: u64, limit: u64) -> Result<Vec<MusicProject>, String> {
+    require_admin()?;
+    Ok(page(&sorted_projects(), offset, limit))
+}
+
+#[ic_cdk::query]
+fn export_nfts_chunk(offset: u64, limit: u64) -> Result<Vec<NFTMetadata>, String> {
+    require_admin()?;
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    Ok(NFTS.with(|nfts| within_size_budget(nfts.borrow().values().skip(offset as usize), limit).0))
+}
+
+#[ic_cdk::query]
+fn export_transactions_chunk(offset: u64, limit: u64) -> Result<Vec<Transaction>, String> {
+    require_admin()?;
+    Ok(TRANSACTIONS.with(|transactions| page(&transactions.borrow(), offset, limit)))
+}
+
+#[ic_cdk::query]
+fn export_royalty_payments_chunk(offset: u64, limit: u64) -> Result<Vec<RoyaltyPayment>, String> {
+    require_admin()?;
+    Ok(ROYALTY_PAYMENTS.with(|payments| page(&payments.borrow(), offset, limit)))
+}
+
+// Loads a backup into the canister, replacing what is there. A canister that already
+// holds data is only overwritten when force is set, which also drops the featured list,
+// wishlists, collections, reports, invites, events, view history, fee records and
+// purchase idempotency keys built on the old data. Queued payouts are owed for the old
+// sales, so they have to be paid out before anything can be imported.
+#[ic_cdk::update]
+fn import_state(snapshot: StateSnapshot, force: Option<bool>) -> Result<String, String> {
+    require_admin()?;
+    let has_data = PROJECTS.with(|v| !v.borrow().is_empty())
+        || NFTS.with(|v| !v.borrow().is_empty())
+        || TRANSACTIONS.with(|v| !v.borrow().is_empty())
+        || ROYALTY_PAYMENTS.with(|v| !v.borrow().is_empty());
+    if has_data && !force.unwrap_or(false) {
+        return Err("Canister already holds data; pass force to overwrite it".to_string());
+    }
+    if PENDING_PAYOUTS.with(|v| !v.borrow().is_empty()) {
+        return Err("Payouts are still queued; pay them out with retry_pending_payouts first".to_string());
+    }
+    // Records are keyed by id, so a duplicate would silently drop one of them
+    let unique = |ids: Vec<u64>| ids.len() == ids.iter().collect::<HashSet<_>>().len();
+    if !unique(snapshot.projects.iter().map(|project| project.id).collect()) {
+        return Err("Snapshot contains duplicate project ids".to_string());
+    }
+    if !unique(snapshot.nfts.iter().map(|nft| nft.id).collect()) {
+        return Err("Snapshot contains duplicate NFT ids".to_string());
+    }
+    if !unique(snapshot.transactions.iter().map(|tx| tx.id).collect()) {
+        return Err("Snapshot contains duplicate transaction ids".to_string());
+    }
+
+    // Never hand out an id that the imported data already uses
+    let advance = |counter: &'static std::thread::LocalKey<std::cell::RefCell<u64>>, max: Option<u64>| {
+        if let Some(max) = max {
+            counter.with(|next| {
+                let mut next = next.borrow_mut();
+                *next = (*next).max(max + 1);
+            });
+        }
+    };
+    advance(&NEXT_ID, snapshot.projects.iter().map(|project| project.id).max());
+    advance(&NEXT_NFT_ID, snapshot.nfts.iter().map(|nft| nft.id).max());
+    advance(&NEXT_TRANSACTION_ID, snapshot.transactions.iter().map(|tx| tx.id).max());
+
+    let summary = format!(
+        "Imported {} projects, {} NFTs, {} transactions and {} royalty payments",
+        snapshot.projects.len(),
+        snapshot.nfts.len(),
+        snapshot.transactions.len(),
+        snapshot.royalty_payments.len()
+    );
+    PROJECTS.with(|v| *v.borrow_mut() = snapshot.projects.into_iter().map(|project| (project.id, project)).collect());
+    NFTS.with(|v| *v.borrow_mut() = snapshot.nfts.into_iter().map(|nft| (nft.id, nft)).collect());
+    TRANSACTIONS.with(|v| *v.borrow_mut() = snapshot.transactions);
+    ROYALTY_PAYMENTS.with(|v| *v.borrow_mut() = snapshot.royalty_payments);
+    // Everything else refers to the projects and NFTs that were just replaced
+    FEATURED.with(|v| v.borrow_mut().clear());
+    WISHLISTS.with(|v| v.borrow_mut().clear());
+    COLLECTIONS.with(|v| v.borrow_mut().clear());
+    REPORTS.with(|v| v.borrow_mut().clear());
+    INVITES.with(|v| v.borrow_mut().clear());
+    EVENTS.with(|v| v.borrow_mut().clear());
+    RECENT_VIEWS.with(|v| v.borrow_mut().clear());
+    RECENT_VIEW_ORDER.with(|v| v.borrow_mut().clear());
+    VIEW_LOG.with(|v| v.borrow_mut().clear());
+    FEE_PAYMENTS.with(|v| v.borrow_mut().clear());
+    COMPLETED_PURCHASES.with(|v| v.borrow_mut().clear());
+    let stats = get_marketplace_stats();
+    MARKET_STATS.with(|v| *v.borrow_mut() = stats);
+    Ok(summary)
+}
+
+// Guard for minting, buying and project work: banned callers are turned away
+fn require_not_blacklisted() -> Result<(), String> {
+    if BLACKLIST.with(|blacklist| blacklist.borrow().contains(&caller_text())) {
+        return Err("Your account has been blocked from this action".to_string());
+    }
+    Ok(())
+}
+
+// Layout of StableState; post_upgrade refuses saved state of any other version rather
+// than misread it
+const STATE_VERSION: u32 = 1;
+
+// Everything that must survive an upgrade, saved under STATE_VERSION. Rate-limit windows,
+// view dedup state and purchase idempotency keys are transient and deliberately left out.
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    version: u32,
+    projects: BTreeMap<u64, MusicProject>,
+    nfts: BTreeMap<u64, NFTMetadata>,
+    collections: HashMap<u64, Collection>,
+    transactions: Vec<Transaction>,
+    royalty_payments: Vec<RoyaltyPayment>,
+    next_id: u64,
+    next_nft_id: u64,
+    next_collection_id: u64,
+    next_transaction_id: u64,
+    admin: String,
+    ledger: Option<Principal>,
+    min_price_increment: Option<MinPriceIncrement>,
+    categories: Vec<String>,
+    require_project_link: bool,
+    featured: Vec<u64>,
+    upload_rate_limit: UploadRateLimit,
+    pinata_credentials: Option<PinataCredentials>,
+    pending_unpins: Vec<String>,
+    wishlists: HashMap<String, Vec<u64>>,
+    invites: HashMap<u64, Vec<String>>,
+    events: HashMap<String, EventQueue>,
+    reports: Vec<Report>,
+    next_report_id: u64,
+    blacklist: HashSet<String>,
+    pending_payouts: Vec<PendingPayout>,
+    default_royalty: u8,
+    platform_fee: PlatformFee,
+    mint_fee: MintFee,
+    fee_payments: Vec<FeePayment>,
+    profiles: HashMap<String, Profile>,
+    ipfs_gateway: String,
+    max_tracks_per_project: u64,
+    view_dedup_window_ns: u64,
+}
+
+#[ic_cdk::init]
+fn init() {
+    ADMIN.with(|admin| *admin.borrow_mut() = caller_text());
+    STARTED_AT.with(|started| *started.borrow_mut() = time());
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((collect_state(),)).expect("Failed to save state to stable memory");
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Only a canister that never saved anything keeps the fresh defaults. Saved state that
+    // fails to decode traps instead, which rolls the upgrade back rather than wiping the data
+    if stable_size() > 0 {
+        let (state,) = ic_cdk::storage::stable_restore::<(StableState,)>()
+            .expect("Failed to restore state from stable memory");
+        if state.version != STATE_VERSION {
+            ic_cdk::trap(&format!("Cannot restore state version {}; expected {}", state.version, STATE_VERSION));
+        }
+        restore_state(state);
+    }
+    STARTED_AT.with(|started| *started.borrow_mut() = time());
+    // Keep a transferred admin across upgrades; fall back to the upgrader if none was saved
+    ADMIN.with(|admin| {
+        if admin.borrow().is_empty() {
+            *admin.borrow_mut() = caller_text();
+        }
+    });
+}
+
+fn collect_state() -> StableState {
+    StableState {
+        version: STATE_VERSION,
+        projects: PROJECTS.with(|v| v.borrow().clone()),
+        nfts: NFTS.with(|v| v.borrow().clone()),
+        collections: COLLECTIONS.with(|v| v.borrow().clone()),
+        transactions: TRANSACTIONS.with(|v| v.borrow().clone()),
+        royalty_payments: ROYALTY_PAYMENTS.with(|v| v.borrow().clone()),
+        next_id: NEXT_ID.with(|v| *v.borrow()),
+        next_nft_id: NEXT_NFT_ID.with(|v| *v.borrow()),
+        next_collection_id: NEXT_COLLECTION_ID.with(|v| *v.borrow()),
+        next_transaction_id: NEXT_TRANSACTION_ID.with(|v| *v.borrow()),
+        admin: ADMIN.with(|v| v.borrow().clone()),
+        ledger: LEDGER.with(|v| *v.borrow()),
+        min_price_increment: MIN_PRICE_INCREMENT.with(|v| v.borrow().clone()),
+        categories: CATEGORIES.with(|v| v.borrow().clone()),
+        require_project_link: REQUIRE_PROJECT_LINK.with(|v| *v.borrow()),
+        featured: FEATURED.with(|v| v.borrow().clone()),
+        upload_rate_limit: UPLOAD_RATE_LIMIT.with(|v| v.borrow().clone()),
+        pinata_credentials: PINATA_CREDENTIALS.with(|v| v.borrow().clone()),
+        pending_unpins: PENDING_UNPINS.with(|v| v.borrow().clone()),
+        wishlists: WISHLISTS.with(|v| v.borrow().clone()),
+        invites: INVITES.with(|v| v.borrow().clone()),
+        events: EVENTS.with(|v| v.borrow().clone()),
+        reports: REPORTS.with(|v| v.borrow().clone()),
+        next_report_id: NEXT_REPORT_ID.with(|v| *v.borrow()),
+        blacklist: BLACKLIST.with(|v| v.borrow().clone()),
+        pending_payouts: PENDING_PAYOUTS.with(|v| v.borrow().clone()),
+        default_royalty: DEFAULT_ROYALTY.with(|v| *v.borrow()),
+        platform_fee: PLATFORM_FEE.with(|v| v.borrow().clone()),
+        mint_fee: MINT_FEE.with(|v| v.borrow().clone()),
+        fee_payments: FEE_PAYMENTS.with(|v| v.borrow().clone()),
+        profiles: PROFILES.with(|v| v.borrow().clone()),
+        ipfs_gateway: IPFS_GATEWAY.with(|v| v.borrow().clone()),
+        max_tracks_per_project: MAX_TRACKS_PER_PROJECT.with(|v| *v.borrow()),
+        view_dedup_window_ns: VIEW_DEDUP_WINDOW.with(|v| *v.borrow()),
+    }
+}
+
+fn restore_state(state: StableState) {
+    PROJECTS.with(|v| *v.borrow_mut() = state.projects);
+    NFTS.with(|v| *v.borrow_mut() = state.nfts);
+    COLLECTIONS.with(|v| *v.borrow_mut() = state.collections);
+    TRANSACTIONS.with(|v| *v.borrow_mut() = state.transactions);
+    ROYALTY_PAYMENTS.with(|v| *v.borrow_mut() = state.royalty_payments);
+    NEXT_ID.with(|v| *v.borrow_mut() = state.next_id);
+    NEXT_NFT_ID.with(|v| *v.borrow_mut() = state.next_nft_id);
+    NEXT_COLLECTION_ID.with(|v| *v.borrow_mut() = state.next_collection_id);
+    NEXT_TRANSACTION_ID.with(|v| *v.borrow_mut() = state.next_transaction_id);
+    ADMIN.with(|v| *v.borrow_mut() = state.admin);
+    LEDGER.with(|v| *v.borrow_mut() = state.ledger);
+    MIN_PRICE_INCREMENT.with(|v| *v.borrow_mut() = state.min_price_increment);
+    CATEGORIES.with(|v| *v.borrow_mut() = state.categories);
+    REQUIRE_PROJECT_LINK.with(|v| *v.borrow_mut() = state.require_project_link);
+    FEATURED.with(|v| *v.borrow_mut() = state.featured);
+    UPLOAD_RATE_LIMIT.with(|v| *v.borrow_mut() = state.upload_rate_limit);
+    PINATA_CREDENTIALS.with(|v| *v.borrow_mut() = state.pinata_credentials);
+    PENDING_UNPINS.with(|v| *v.borrow_mut() = state.pending_unpins);
+    WISHLISTS.with(|v| *v.borrow_mut() = state.wishlists);
+    INVITES.with(|v| *v.borrow_mut() = state.invites);
+    EVENTS.with(|v| *v.borrow_mut() = state.events);
+    REPORTS.with(|v| *v.borrow_mut() = state.reports);
+    NEXT_REPORT_ID.with(|v| *v.borrow_mut() = state.next_report_id);
+    BLACKLIST.with(|v| *v.borrow_mut() = state.blacklist);
+    PENDING_PAYOUTS.with(|v| *v.borrow_mut() = state.pending_payouts);
+    DEFAULT_ROYALTY.with(|v| *v.borrow_mut() = state.default_royalty);
+    PLATFORM_FEE.with(|v| *v.borrow_mut() = state.platform_fee);
+    MINT_FEE.with(|v| *v.borrow_mut() = state.mint_fee);
+    FEE_PAYMENTS.with(|v| *v.borrow_mut() = state.fee_payments);
+    PROFILES.with(|v| *v.borrow_mut() = state.profiles);
+    IPFS_GATEWAY.with(|v| *v.borrow_mut() = state.ipfs_gateway);
+    MAX_TRACKS_PER_PROJECT.with(|v| *v.borrow_mut() = state.max_tracks_per_project);
+    VIEW_DEDUP_WINDOW.with(|v| *v.borrow_mut() = state.view_dedup_window_ns);
+    let stats = get_marketplace_stats();
+    MARKET_STATS.with(|v| *v.borrow_mut() = stats);
+}
+
+#[ic_cdk::update]
+fn set_ledger_canister(ledger: Principal) -> Result<String, String> {
+    require_admin()?;
+    LEDGER.with(|current| *current.borrow_mut() = Some(ledger));
+    Ok(format!("Ledger canister set to {}", ledger))
+}
+
+#[ic_cdk::query]
+fn get_ledger_canister() -> Option<Principal> {
+    LEDGER.with(|ledger| *ledger.borrow())
+}
+
+// The account a buyer deposits ledger funds into before calling buy_nft. A purchase
+// draws the price plus one ledger fee per transfer it makes.
+#[ic_cdk::query]
+fn get_escrow_account() -> Account {
+    Account {
+        owner: canister_id(),
+        subaccount: Some(escrow_subaccount(&caller()).to_vec()),
+    }
+}
+
+const WASM_PAGE_BYTES: u64 = 65536;
+
+#[ic_cdk::query]
+fn health() -> HealthReport {
+    #[cfg(target_arch = "wasm32")]
+    let heap_memory_bytes = core::arch::wasm32::memory_size::<0>() as u64 * WASM_PAGE_BYTES;
+    #[cfg(not(target_arch = "wasm32"))]
+    let heap_memory_bytes = 0;
+
+    HealthReport {
+        cycles_balance: canister_balance128(),
+        project_count: PROJECTS.with(|v| v.borrow().len() as u64),
+        nft_count: NFTS.with(|v| v.borrow().len() as u64),
+        transaction_count: TRANSACTIONS.with(|v| v.borrow().len() as u64),
+        royalty_payment_count: ROYALTY_PAYMENTS.with(|v| v.borrow().len() as u64),
+        heap_memory_bytes,
+        stable_memory_bytes: stable_size() * WASM_PAGE_BYTES,
+        uptime_ns: time().saturating_sub(STARTED_AT.with(|started| *started.borrow())),
+    }
+}
+
+#[ic_cdk::query]
+fn whoami() -> String {
+    caller_text()
+}
+
+#[ic_cdk::update]
+fn set_profile(display_name: String, avatar_url: Option<String>, bio: String) -> Result<String, String> {
+    validate_text("Display name", &display_name, MAX_NAME_LEN, true)?;
+    validate_text("Bio", &bio, MAX_DESCRIPTION_LEN, false)?;
+    if let Some(url) = &avatar_url {
+        validate_image_url(url)?;
+    }
+    PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(caller_text(), Profile {
+            display_name: display_name.trim().to_string(),
+            avatar_url,
+            bio,
+        });
+    });
+    Ok("Profile updated".to_string())
+}
+
+#[ic_cdk::query]
+fn get_profile(principal: String) -> Option<Profile> {
+    let principal = normalize_principal("Principal", &principal).ok()?;
+    PROFILES.with(|profiles| profiles.borrow().get(&principal).cloned())
+}
+
+#[ic_cdk::query]
+fn get_admin() -> String {
+    ADMIN.with(|admin| admin.borrow().clone())
+}
+
+#[ic_cdk::update]
+fn transfer_admin(new_admin: String) -> Result<String, String> {
+    require_admin()?;
+    let new_admin = normalize_principal("New admin", &new_admin)?;
+    ADMIN.with(|admin| *admin.borrow_mut() = new_admin.clone());
+    Ok(format!("Admin transferred to {}", new_admin))
+}
+
+#[ic_cdk::update]
+fn blacklist_principal(principal: String) -> Result<String, String> {
+    require_admin()?;
+    let principal = normalize_principal("Principal", &principal)?;
+    if !BLACKLIST.with(|blacklist| blacklist.borrow_mut().insert(principal.clone())) {
+        return Err(format!("{} is already blacklisted", principal));
+    }
+    Ok(format!("{} blacklisted", principal))
+}
+
+#[ic_cdk::update]
+fn unblacklist_principal(principal: String) -> Result<String, String> {
+    require_admin()?;
+    let principal = normalize_principal("Principal", &principal)?;
+    if !BLACKLIST.with(|blacklist| blacklist.borrow_mut().remove(&principal)) {
+        return Err(format!("{} is not blacklisted", principal));
+    }
+    Ok(format!("{} removed from the blacklist", principal))
+}
+
+#[ic_cdk::query]
+fn is_blacklisted(principal: String) -> bool {
+    let Ok(principal) = normalize_principal("Principal", &principal) else {
+        return false;
+    };
+    BLACKLIST.with(|blacklist| blacklist.borrow().contains(&principal))
+}
+
+fn validate_text(field: &str, value: &str, max_len: usize, required: bool) -> Result<(), String> {
+    if required && value.trim().is_empty() {
+        return Err(format!("{} cannot be empty", field));
+    }
+    if value.chars().count() > max_len {
+        return Err(format!("{} cannot be longer than {} characters", field, max_len));
+    }
+    Ok(())
+}
+
+// Accepts web and IPFS links, plus inline images such as the generated waveforms
+fn validate_image_url(url: &str) -> Result<(), String> {
+    if url.starts_with("data:image/") {
+        if url.len() > MAX_IMAGE_DATA_URL_LEN {
+            return Err(format!("Inline image cannot be larger than {} bytes", MAX_IMAGE_DATA_URL_LEN));
+        }
+        return Ok(());
+    }
+    let rest = ["https://", "http://", "ipfs://"]
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme))
+        .ok_or("Image URL must start with https://, http://, ipfs:// or data:image/")?;
+    if rest.is_empty() || rest.chars().any(char::is_whitespace) {
+        return Err("Image URL is malformed".to_string());
+    }
+    if url.len() > MAX_URL_LEN {
+        return Err(format!("Image URL cannot be longer than {} characters", MAX_URL_LEN));
+    }
+    Ok(())
+}
+
+// Basic CID shape check: CIDv0 is "Qm" plus 44 base58 characters, CIDv1 is
+// lowercase base32 starting with "baf"
+fn validate_ipfs_hash(hash: &str) -> Result<(), String> {
+    if hash.is_empty() {
+        return Err("IPFS hash cannot be empty".to_string());
+    }
+    let is_base58 = |c: char| c.is_ascii_alphanumeric() && !matches!(c, '0' | 'O' | 'I' | 'l');
+    let is_base32 = |c: char| c.is_ascii_lowercase() || ('2'..='7').contains(&c);
+    let valid = if hash.starts_with("Qm") {
+        hash.len() == 46 && hash.chars().all(is_base58)
+    } else {
+        hash.starts_with("baf") && (50..=MAX_CID_LEN).contains(&hash.len()) && hash.chars().all(is_base32)
+    };
+    if !valid {
+        return Err(format!("'{}' is not a valid IPFS CID", hash));
+    }
+    Ok(())
+}
+
+// Animation and audio preview links must be web or IPFS URLs; inline data is only allowed for images
+fn validate_media_url(field: &str, url: &str) -> Result<(), String> {
+    let rest = ["https://", "http://", "ipfs://"]
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme))
+        .ok_or(format!("{} must start with https://, http:// or ipfs://", field))?;
+    if rest.is_empty() || rest.chars().any(char::is_whitespace) {
+        return Err(format!("{} is malformed", field));
+    }
+    if url.len() > MAX_URL_LEN {
+        return Err(format!("{} cannot be longer than {} characters", field, MAX_URL_LEN));
+    }
+    Ok(())
+}
+
+fn validate_nft_media(animation_url: &Option<String>, audio_preview_url: &Option<String>) -> Result<(), String> {
+    if let Some(url) = animation_url {
+        validate_media_url("Animation URL", url)?;
+    }
+    if let Some(url) = audio_preview_url {
+        validate_media_url("Audio preview URL", url)?;
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn create_project(title: String, description: String, owner: String) -> Result<u64, String> {
+    require_not_blacklisted()?;
+    let owner = normalize_principal("Owner", &owner)?;
+    validate_text("Title", &title, MAX_TITLE_LEN, true)?;
+    validate_text("Description", &description, MAX_DESCRIPTION_LEN, false)?;
+
+    let id = NEXT_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        let current = *id;
+        *id += 1;
+        current
+    });
+    let project = MusicProject {
+        id,
+        title,
+        description,
+        owner,
+        contributors: vec![],
+        tracks: vec![],
+        created_at: time(),
+        is_archived: false,
+    };
+    PROJECTS.with(|projects| {
+        projects.borrow_mut().insert(id, project);
+    });
+    Ok(id)
+}
+
+// Only the project owner and listed contributors may upload; the uploader is the caller
+#[ic_cdk::update]
+fn add_track(
+    project_id: u64,
+    name: String,
+    ipfs_hash: String,
+    timestamp: u64,
+    metadata: Option<TrackMetadata>
+) -> Result<u64, String> {
+    require_not_blacklisted()?;
+    let ipfs_hash = ipfs_hash.trim().to_string();
+    validate_ipfs_hash(&ipfs_hash)?;
+    let uploaded_by = caller_text();
+    let max_tracks = get_max_tracks_per_project();
+    let metadata = metadata.unwrap_or(TrackMetadata {
+        duration_secs: None,
+        genre: None,
+        bpm: None,
+    });
+
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        if project.owner != uploaded_by && !project.contributors.contains(&uploaded_by) {
+            return Err("Only the project owner or a contributor can add tracks".to_string());
+        }
+        if project.tracks.len() as u64 >= max_tracks {
+            return Err(format!("Project already has the maximum of {} tracks", max_tracks));
+        }
+
+        // Generate unique track ID using timestamp and random component
+        let track_id = timestamp;
+
+        let track = Track {
+            id: track_id,
+            name,
+            ipfs_hash,
+            uploaded_by,
+            timestamp,
+            duration_secs: metadata.duration_secs,
+            genre: metadata.genre,
+            bpm: metadata.bpm,
+            play_count: 0,
+        };
+        project.tracks.push(track);
+        Ok(track_id)
+    })
+}
+
+// Projects already over a lowered cap keep their tracks but cannot add more
+#[ic_cdk::update]
+fn set_max_tracks_per_project(max: u64) -> Result<String, String> {
+    require_admin()?;
+    if max == 0 {
+        return Err("Track limit must be at least 1".to_string());
+    }
+    MAX_TRACKS_PER_PROJECT.with(|limit| *limit.borrow_mut() = max);
+    Ok(format!("Projects are now limited to {} tracks", max))
+}
+
+#[ic_cdk::query]
+fn get_max_tracks_per_project() -> u64 {
+    MAX_TRACKS_PER_PROJECT.with(|limit| *limit.borrow())
+}
+
+// Only the project owner or the track's uploader may edit its metadata
+#[ic_cdk::update]
+fn update_track_metadata(project_id: u64, track_id: u64, metadata: TrackMetadata) -> Result<String, String> {
+    let caller = caller_text();
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        let is_owner = project.owner == caller;
+        let track = project.tracks.iter_mut().find(|track| track.id == track_id).ok_or("Track not found")?;
+        if !is_owner && track.uploaded_by != caller {
+            return Err("Only the project owner or the track uploader can edit this track".to_string());
+        }
+        track.duration_secs = metadata.duration_secs;
+        track.genre = metadata.genre;
+        track.bpm = metadata.bpm;
+        Ok(format!("Track {} metadata updated", track_id))
+    })
+}
+
+// Swaps in new content for a bad upload; the id, position and metadata stay, and the
+// timestamp moves to now. NFTs already minted from the old hash keep pointing at it
+#[ic_cdk::update]
+fn replace_track(project_id: u64, track_id: u64, new_ipfs_hash: String, new_name: String) -> Result<String, String> {
+    let new_ipfs_hash = new_ipfs_hash.trim().to_string();
+    validate_ipfs_hash(&new_ipfs_hash)?;
+    validate_text("Name", &new_name, MAX_NAME_LEN, true)?;
+    let caller = caller_text();
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        let is_owner = project.owner == caller;
+        let track = project.tracks.iter_mut().find(|track| track.id == track_id).ok_or("Track not found")?;
+        if !is_owner && track.uploaded_by != caller {
+            return Err("Only the project owner or the track uploader can replace this track".to_string());
+        }
+        track.ipfs_hash = new_ipfs_hash;
+        track.name = new_name;
+        // Uploads are stamped by the frontend in milliseconds, so keep that unit
+        track.timestamp = time() / 1_000_000;
+        Ok(format!("Track {} replaced", track_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_project(project_id: u64) -> Option<MusicProject> {
+    PROJECTS.with(|projects| projects.borrow().get(&project_id).cloned())
+}
+
+#[ic_cdk::query]
+fn list_projects() -> Vec<MusicProject> {
+    PROJECTS.with(|projects| {
+        projects.borrow().values().filter(|project| !project.is_archived).cloned().collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_archived_projects() -> Vec<MusicProject> {
+    PROJECTS.with(|projects| {
+        projects.borrow().values().filter(|project| project.is_archived).cloned().collect()
+    })
+}
+
+fn set_archived(project_id: u64, archived: bool) -> Result<String, String> {
+    let caller = caller_text();
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        if project.owner != caller {
+            return Err("Only the project owner can archive or unarchive it".to_string());
+        }
+        project.is_archived = archived;
+        Ok(format!("Project {} {}", project_id, if archived { "archived" } else { "unarchived" }))
+    })
+}
+
+#[ic_cdk::update]
+fn archive_project(project_id: u64) -> Result<String, String> {
+    set_archived(project_id, true)
+}
+
+#[ic_cdk::update]
+fn unarchive_project(project_id: u64) -> Result<String, String> {
+    set_archived(project_id, false)
+}
+
+// Newest first; id breaks ties
+#[ic_cdk::query]
+fn get_recent_projects(limit: u64) -> Vec<MusicProject> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    PROJECTS.with(|projects| {
+        let projects = projects.borrow();
+        let mut recent: Vec<&MusicProject> = projects.values().filter(|project| !project.is_archived).collect();
+        recent.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        recent.into_iter().take(limit).cloned().collect()
+    })
+}
+
+// Owner-only, like add_contributors
+#[ic_cdk::update]
+fn add_contributor(project_id: u64, contributor: String) -> Result<String, String> {
+    let contributor = normalize_principal("Contributor", &contributor)?;
+    match add_contributors(project_id, vec![contributor.clone()])? {
+        0 => Ok(format!("{} is already a member", contributor)),
+        _ => Ok(format!("Added {} as a contributor", contributor)),
+    }
+}
+
+// Owner-only; returns how many were new, skipping blanks, the owner and existing members
+#[ic_cdk::update]
+fn add_contributors(project_id: u64, contributors: Vec<String>) -> Result<u64, String> {
+    if contributors.len() > MAX_QUERY_LIMIT as usize {
+        return Err(format!("Cannot add more than {} contributors at once", MAX_QUERY_LIMIT));
+    }
+    // Validate the whole batch up front so a bad entry adds nobody
+    let contributors = contributors
+        .iter()
+        .filter(|contributor| !contributor.trim().is_empty())
+        .map(|contributor| normalize_principal(&format!("Contributor '{}'", contributor.trim()), contributor))
+        .collect::<Result<Vec<_>, _>>()?;
+    let caller = caller_text();
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        if project.owner != caller {
+            return Err("Only the project owner can add contributors".to_string());
+        }
+        let mut added = 0;
+        for contributor in contributors {
+            if contributor == project.owner || project.contributors.contains(&contributor) {
+                continue;
+            }
+            project.contributors.push(contributor);
+            added += 1;
+        }
+        Ok(added)
+    })
+}
+
+// Owner-only; the invitee joins only once they call accept_invite
+#[ic_cdk::update]
+fn invite_contributor(project_id: u64, invitee: String) -> Result<String, String> {
+    let invitee = normalize_principal("Invitee", &invitee)?;
+    let caller = caller_text();
+    PROJECTS.with(|projects| {
+        let projects = projects.borrow();
+        let project = projects.get(&project_id).ok_or("Project not found")?;
+        if project.owner != caller {
+            return Err("Only the project owner can invite contributors".to_string());
+        }
+        if invitee == project.owner || project.contributors.contains(&invitee) {
+            return Err("Already a member of this project".to_string());
+        }
+        Ok(())
+    })?;
+    INVITES.with(|invites| {
+        let mut invites = invites.borrow_mut();
+        let pending = invites.entry(project_id).or_default();
+        if pending.contains(&invitee) {
+            return Err("This principal has already been invited".to_string());
+        }
+        if pending.len() >= MAX_QUERY_LIMIT as usize {
+            return Err(format!("A project cannot have more than {} pending invites", MAX_QUERY_LIMIT));
+        }
+        pending.push(invitee.clone());
+        Ok(format!("Invited {} to project {}", invitee, project_id))
+    })
+}
+
+// Removes a pending invite, reporting whether there was one
+fn take_invite(project_id: u64, invitee: &str) -> bool {
+    INVITES.with(|invites| {
+        let mut invites = invites.borrow_mut();
+        let Some(pending) = invites.get_mut(&project_id) else {
+            return false;
+        };
+        let before = pending.len();
+        pending.retain(|invited| invited != invitee);
+        let taken = pending.len() != before;
+        if pending.is_empty() {
+            invites.remove(&project_id);
+        }
+        taken
+    })
+}
+
+#[ic_cdk::update]
+fn accept_invite(project_id: u64) -> Result<String, String> {
+    let caller = caller_text();
+    if !take_invite(project_id, &caller) {
+        return Err("No pending invite for this project".to_string());
+    }
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        if !project.contributors.contains(&caller) {
+            project.contributors.push(caller);
+        }
+        Ok(format!("Joined project {}", project_id))
+    })
+}
+
+#[ic_cdk::update]
+fn decline_invite(project_id: u64) -> Result<String, String> {
+    if !take_invite(project_id, &caller_text()) {
+        return Err("No pending invite for this project".to_string());
+    }
+    Ok(format!("Declined the invite to project {}", project_id))
+}
+
+// Ids of the projects the caller has been invited to
+#[ic_cdk::query]
+fn get_my_invites() -> Vec<u64> {
+    let caller = caller_text();
+    let mut project_ids: Vec<u64> = INVITES.with(|invites| {
+        invites.borrow()
+            .iter()
+            .filter(|(_, pending)| pending.contains(&caller))
+            .map(|(project_id, _)| *project_id)
+            .collect()
+    });
+    project_ids.sort();
+    project_ids
+}
+
+// Only the project owner or the track's uploader may remove it. With `unpin` set, the
+// removed track's content is queued for unpinning from Pinata unless another track
+// still references the same hash
+#[ic_cdk::update]
+fn remove_track(project_id: u64, track_id: u64, unpin: Option<bool>) -> bool {
+    let caller = caller_text();
+    let removed_hash = PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id)?;
+        let track = project.tracks.iter().find(|track| track.id == track_id)?;
+        if project.owner != caller && track.uploaded_by != caller {
+            return None;
+        }
+        let hash = track.ipfs_hash.clone();
+        project.tracks.retain(|track| track.id != track_id);
+        Some(hash)
+    });
+
+    let Some(hash) = removed_hash else {
+        return false;
+    };
+    if unpin == Some(true) {
+        let still_used = PROJECTS.with(|projects| {
+            projects.borrow().values().any(|project| project.tracks.iter().any(|track| track.ipfs_hash == hash))
+        });
+        if !still_used {
+            queue_unpin(hash);
+        }
+    }
+    true
+}
+
+// Track ids come from the upload timestamp and are not guaranteed unique across
+// projects; if several projects hold the id, the lowest project id wins
+#[ic_cdk::query]
+fn get_project_by_track(track_id: u64) -> Option<MusicProject> {
+    PROJECTS.with(|projects| {
+        projects.borrow()
+            .values()
+            .filter(|project| project.tracks.iter().any(|track| track.id == track_id))
+            .min_by_key(|project| project.id)
+            .cloned()
+    })
+}
+
+#[ic_cdk::query]
+fn get_project_tracks(project_id: u64) -> Vec<Track> {
+    PROJECTS.with(|projects| {
+        projects.borrow()
+            .get(&project_id)
+            .map(|project| project.tracks.clone())
+            .unwrap_or_default()
+    })
+}
+
+#[ic_cdk::update]
+fn record_track_play(project_id: u64, track_id: u64) -> Result<u64, String> {
+    PROJECTS.with(|projects| {
+        let mut projects = projects.borrow_mut();
+        let project = projects.get_mut(&project_id).ok_or("Project not found")?;
+        let track = project.tracks.iter_mut().find(|track| track.id == track_id).ok_or("Track not found")?;
+        track.play_count += 1;
+        Ok(track.play_count)
+    })
+}
+
+// (track id, play count) in the project's track order
+#[ic_cdk::query]
+fn get_project_play_stats(project_id: u64) -> Vec<(u64, u64)> {
+    PROJECTS.with(|projects| {
+        projects.borrow()
+            .get(&project_id)
+            .map(|project| project.tracks.iter().map(|track| (track.id, track.play_count)).collect())
+            .unwrap_or_default()
+    })
+}
+
+// Tracks uploaded per principal, most first, ties by principal
+#[ic_cdk::query]
+fn get_contributor_contributions(project_id: u64) -> Vec<(String, u64)> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    PROJECTS.with(|projects| {
+        if let Some(project) = projects.borrow().get(&project_id) {
+            for track in &project.tracks {
+                *counts.entry(track.uploaded_by.clone()).or_insert(0) += 1;
+            }
+        }
+    });
+    let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+// Everyone the user shares a project with, as owner or contributor, with the number of
+// shared projects; most shared first, ties by principal
+#[ic_cdk::query]
+fn get_collaborator_network(user: String) -> Vec<(String, u64)> {
+    let Ok(user) = normalize_principal("User", &user) else {
+        return vec![];
+    };
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    PROJECTS.with(|projects| {
+        for project in projects.borrow().values() {
+            let members: HashSet<&String> = std::iter::once(&project.owner).chain(project.contributors.iter()).collect();
+            if !members.contains(&user) {
+                continue;
+            }
+            for member in members.into_iter().filter(|member| **member != user) {
+                *counts.entry(member.clone()).or_insert(0) += 1;
+            }
+        }
+    });
+    let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+#[ic_cdk::query]
+fn find_duplicate_tracks(project_id: u64) -> Vec<Vec<u64>> {
+    PROJECTS.with(|projects| {
+        let projects = projects.borrow();
+        let Some(project) = projects.get(&project_id) else {
+            return vec![];
+        };
+
+        // Group track ids by hash, keeping groups in order of first upload
+        let mut groups: Vec<(&str, Vec<u64>)> = Vec::new();
+        for track in &project.tracks {
+            match groups.iter_mut().find(|(hash, _)| *hash == track.ipfs_hash) {
+                Some((_, ids)) => ids.push(track.id),
+                None => groups.push((&track.ipfs_hash, vec![track.id])),
+            }
+        }
+
+        groups
+            .into_iter()
+            .filter(|(_, ids)| ids.len() > 1)
+            .map(|(_, ids)| ids)
+            .collect()
+    })
+}
+
+// Bundles a project with its tracks and every NFT minted from it; owner only
+#[ic_cdk::query]
+fn export_project(project_id: u64) -> Option<ProjectExport> {
+    let project = PROJECTS.with(|projects| projects.borrow().get(&project_id).cloned())?;
+    if project.owner != caller_text() {
+        return None;
+    }
+
+    let nfts = NFTS.with(|nfts| {
+        nfts.borrow()
+            .values()
+            .filter(|nft| nft.project_id == project_id)
+            .cloned()
+            .collect()
+    });
+
+    Some(ProjectExport {
+        tracks: project.tracks.clone(),
+        project,
+        nfts,
+    })
+}
+
+// Visible NFTs minted from a project, oldest first
+#[ic_cdk::query]
+fn get_project_nfts(project_id: u64) -> Vec<NFTMetadata> {
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        within_size_budget(nfts.values().filter(|nft| nft.project_id == project_id && !nft.is_hidden), usize::MAX).0
+    })
+}
+
+#[ic_cdk::query]
+fn get_project_nft_count(project_id: u64) -> u64 {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .values()
+            .filter(|nft| nft.project_id == project_id && !nft.is_hidden)
+            .count() as u64
+    })
+}
+
+// NFTs naming a project that doesn't exist. Projects can't be deleted, but mints made
+// while project links weren't required, or restored snapshots, can point nowhere.
+// Project id 0 means no project and is not an orphan
+#[ic_cdk::query]
+fn find_orphaned_nfts() -> Result<Vec<u64>, String> {
+    require_admin()?;
+    let project_ids: HashSet<u64> = PROJECTS.with(|projects| projects.borrow().keys().copied().collect());
+    Ok(NFTS.with(|nfts| {
+        nfts.borrow()
+            .values()
+            .filter(|nft| nft.project_id != 0 && !project_ids.contains(&nft.project_id))
+            .map(|nft| nft.id)
+            .collect()
+    }))
+}
+
+// Admin repair for orphans; 0 detaches the NFT from any project
+#[ic_cdk::update]
+fn reassign_nft_project(nft_id: u64, new_project_id: u64) -> Result<String, String> {
+    require_admin()?;
+    if new_project_id != 0 && !PROJECTS.with(|projects| projects.borrow().contains_key(&new_project_id)) {
+        return Err("Project not found".to_string());
+    }
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or("NFT not found")?;
+        nft.project_id = new_project_id;
+        Ok(format!("NFT {} moved to project {}", nft_id, new_project_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_project_statistics(project_id: u64) -> Option<ProjectStats> {
+    let (track_count, contributor_count) = PROJECTS.with(|projects| {
+        projects.borrow()
+            .get(&project_id)
+            .map(|project| (project.tracks.len() as u64, project.contributors.len() as u64))
+    })?;
+    let nft_ids: HashSet<u64> = NFTS.with(|nfts| {
+        nfts.borrow()
+            .values()
+            .filter(|nft| nft.project_id == project_id)
+            .map(|nft| nft.id)
+            .collect()
+    });
+    let sales_volume = TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.transaction_type == "sale" && nft_ids.contains(&tx.nft_id))
+            .map(|tx| tx.price)
+            .sum()
+    });
+    Some(ProjectStats {
+        project_id,
+        track_count,
+        contributor_count,
+        nft_count: nft_ids.len() as u64,
+        sales_volume,
+    })
+}
+
+// With a mint fee set, the fee plus one ledger fee is taken from the caller's escrow
+// account once every other check has passed, and the mint is refused if it can't be
+#[ic_cdk::update]
+#[allow(clippy::too_many_arguments)]
+async fn mint_nft(
+    name: String,
+    description: String,
+    image_url: String,
+    creator: String,
+    project_id: u64,
+    price: u64,
+    category: String,
+    animation_url: Option<String>,
+    audio_preview_url: Option<String>,
+    ipfs_hash: Option<String>,
+    royalty_percentage: Option<u8>,
+    min_resale_price: Option<u64>,
+    list_immediately: Option<bool>,
+) -> Result<u64, String> {
+    require_not_blacklisted()?;
+    let creator = normalize_principal("Creator", &creator)?;
+    // Listed by default, as before; only an unlisted NFT may be minted without a price
+    let list_immediately = list_immediately.unwrap_or(true);
+    if list_immediately && price == 0 {
+        return Err("A listed NFT must have a price greater than zero".to_string());
+    }
+    let royalty_percentage = royalty_percentage.unwrap_or_else(get_default_royalty);
+    validate_payout_config(royalty_percentage, get_platform_fee().percentage)?;
+    let ipfs_hash = ipfs_hash.map(|hash| hash.trim().to_string()).filter(|hash| !hash.is_empty());
+    validate_text("Name", &name, MAX_NAME_LEN, true)?;
+    validate_text("Description", &description, MAX_DESCRIPTION_LEN, false)?;
+    validate_image_url(&image_url)?;
+    validate_nft_media(&animation_url, &audio_preview_url)?;
+    if let Some(hash) = &ipfs_hash {
+        validate_ipfs_hash(hash)?;
+    }
+    let category = normalize_category(&category);
+    if !CATEGORIES.with(|categories| categories.borrow().contains(&category)) {
+        return Err(format!("Unknown category '{}'", category));
+    }
+    if REQUIRE_PROJECT_LINK.with(|required| *required.borrow())
+        && !PROJECTS.with(|projects| projects.borrow().contains_key(&project_id))
+    {
+        return Err("NFTs must be minted from an existing project".to_string());
+    }
+    collect_mint_fee(caller()).await?;
+
+    // Every check above runs before an id is taken, so a rejected mint leaves no gap
+    let id = NEXT_NFT_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        let current = *id;
+        *id += 1;
+        current
+    });
+    
+    let now = time();
+    let mut nft = NFTMetadata {
+        id,
+        name,
+        description,
+        image_url,
+        current_owner: creator.clone(),
+        creator: creator.clone(),
+        project_id,
+        price,
+        category,
+        royalty_percentage,
+        is_for_sale: list_immediately,
+        created_at: now,
+        view_count: 0,
+        sale_history: vec![],
+        animation_url,
+        audio_preview_url,
+        ipfs_hash,
+        is_hidden: false,
+        min_resale_price,
+        last_listed_at: if list_immediately { now } else { 0 },
+    };
+    record_transaction(&mut nft, "mint", String::new(), creator.clone(), 0);
+    let listed = nft.is_for_sale;
+    
+    NFTS.with(|nfts| {
+        nfts.borrow_mut().insert(id, nft);
+    });
+    push_event(&creator, EventKind::Minted { nft_id: id });
+    update_stats(|stats| {
+        stats.total_nfts += 1;
+        if listed {
+            stats.nfts_for_sale += 1;
+        }
+    });
+    
+    Ok(id)
+}
+
+// Promotes a project track to a listed NFT created by the caller and linked to the track's hash
+#[ic_cdk::update]
+async fn mint_nft_from_track(
+    project_id: u64,
+    track_id: u64,
+    name: String,
+    description: String,
+    image_url: String,
+    price: u64,
+    category: String,
+) -> Result<u64, String> {
+    let caller = caller_text();
+    let ipfs_hash = PROJECTS.with(|projects| {
+        let projects = projects.borrow();
+        let project = projects.get(&project_id).ok_or("Project not found")?;
+        if project.owner != caller && !project.contributors.contains(&caller) {
+            return Err("Only the project owner or a contributor can mint from its tracks".to_string());
+        }
+        project.tracks
+            .iter()
+            .find(|track| track.id == track_id)
+            .map(|track| track.ipfs_hash.clone())
+            .ok_or_else(|| "Track not found".to_string())
+    })?;
+    mint_nft(name, description, image_url, caller, project_id, price, category, None, None, Some(ipfs_hash), None, None, None).await
+}
+
+async fn collect_mint_fee(payer: Principal) -> Result<(), String> {
+    let MintFee { amount, recipient } = get_mint_fee();
+    let Some(recipient) = recipient.filter(|_| amount > 0) else {
+        return Ok(());
+    };
+    let ledger = LEDGER.with(|ledger| *ledger.borrow()).ok_or("A mint fee is set but no ledger canister is configured")?;
+    let to = principal_account(&recipient)?;
+    icrc1_transfer(ledger, escrow_subaccount(&payer), to, amount)
+        .await
+        .map_err(|error| format!("Mint fee of {} could not be collected: {}", amount, error))?;
+    Ok(())
+}
+
+fn normalize_category(category: &str) -> String {
+    category.trim().to_lowercase()
+}
+
+#[ic_cdk::update]
+fn add_category(category: String) -> Result<String, String> {
+    require_admin()?;
+    let category = normalize_category(&category);
+    if category.is_empty() {
+        return Err("Category cannot be empty".to_string());
+    }
+    CATEGORIES.with(|categories| {
+        let mut categories = categories.borrow_mut();
+        if categories.contains(&category) {
+            return Err(format!("Category '{}' already exists", category));
+        }
+        categories.push(category.clone());
+        Ok(format!("Category '{}' added", category))
+    })
+}
+
+// Existing NFTs keep their category; it just can't be used for new mints
+#[ic_cdk::update]
+fn remove_category(category: String) -> Result<String, String> {
+    require_admin()?;
+    let category = normalize_category(&category);
+    CATEGORIES.with(|categories| {
+        let mut categories = categories.borrow_mut();
+        if !categories.contains(&category) {
+            return Err(format!("Category '{}' does not exist", category));
+        }
+        categories.retain(|existing| *existing != category);
+        Ok(format!("Category '{}' removed", category))
+    })
+}
+
+#[ic_cdk::query]
+fn list_categories() -> Vec<String> {
+    CATEGORIES.with(|categories| categories.borrow().clone())
+}
+
+#[ic_cdk::query]
+fn get_category_counts() -> Vec<(String, u64)> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    NFTS.with(|nfts| {
+        for nft in nfts.borrow().values().filter(|nft| !nft.is_hidden) {
+            *counts.entry(nft.category.clone()).or_insert(0) += 1;
+        }
+    });
+    let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+// Lowest asking price among visible listings in each category, by category name;
+// categories with nothing listed are left out
+#[ic_cdk::query]
+fn get_category_floor_prices() -> Vec<(String, u64)> {
+    let mut floors: BTreeMap<String, u64> = BTreeMap::new();
+    NFTS.with(|nfts| {
+        for nft in nfts.borrow().values().filter(|nft| nft.is_for_sale && !nft.is_hidden) {
+            floors
+                .entry(nft.category.clone())
+                .and_modify(|floor| *floor = (*floor).min(nft.price))
+                .or_insert(nft.price);
+        }
+    });
+    floors.into_iter().collect()
+}
+
+#[ic_cdk::query]
+fn get_category_floor(category: String) -> Option<u64> {
+    let category = normalize_category(&category);
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .values()
+            .filter(|nft| nft.category == category && nft.is_for_sale && !nft.is_hidden)
+            .map(|nft| nft.price)
+            .min()
+    })
+}
+
+#[ic_cdk::query]
+fn get_nfts_by_category(category: String, offset: u64, limit: u64) -> Vec<NFTMetadata> {
+    let category = normalize_category(&category);
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let in_category = nfts.values().filter(|nft| nft.category == category && !nft.is_hidden);
+        within_size_budget(in_category.skip(offset as usize), limit).0
+    })
+}
+
+#[ic_cdk::update]
+fn set_require_project_link(required: bool) -> Result<String, String> {
+    require_admin()?;
+    REQUIRE_PROJECT_LINK.with(|flag| *flag.borrow_mut() = required);
+    Ok(format!("Project link requirement {}", if required { "enabled" } else { "disabled" }))
+}
+
+#[ic_cdk::query]
+fn get_require_project_link() -> bool {
+    REQUIRE_PROJECT_LINK.with(|required| *required.borrow())
+}
+
+// Clones NFTs in order until `limit` is reached or the reply would pass
+// RESPONSE_SIZE_BUDGET, and says whether the budget cut it short. The first NFT is always
+// kept, so a page is never empty. Every NFT listing goes through this; offset-paged callers
+// continue from the number of NFTs they got back.
+fn within_size_budget<'a>(nfts: impl Iterator<Item = &'a NFTMetadata>, limit: usize) -> (Vec<NFTMetadata>, bool) {
+    let mut page: Vec<NFTMetadata> = Vec::new();
+    let mut size = 0;
+    for nft in nfts.take(limit) {
+        // Each record is encoded on its own, header included, so this overestimates
+        let nft_size = candid::encode_one(nft).map_or(0, |bytes| bytes.len());
+        if !page.is_empty() && size + nft_size > RESPONSE_SIZE_BUDGET {
+            return (page, true);
+        }
+        size += nft_size;
+        page.push(nft.clone());
+    }
+    (page, false)
+}
+
+// Stops at RESPONSE_SIZE_BUDGET; list_nfts_after pages through everything
+#[ic_cdk::query]
+fn list_nfts() -> Vec<NFTMetadata> {
+    NFTS.with(|nfts| within_size_budget(nfts.borrow().values().filter(|nft| !nft.is_hidden), usize::MAX).0)
+}
+
+// Visible NFTs with an id above the cursor, in id order. next_cursor is the last id
+// returned, or None once the end is reached. A page ends early, with `truncated` set, once
+// it reaches RESPONSE_SIZE_BUDGET; it always holds at least one NFT so the cursor moves
+#[ic_cdk::query]
+fn list_nfts_after(cursor_id: Option<u64>, limit: u64) -> NftPage {
+    let limit = limit.clamp(1, MAX_QUERY_LIMIT) as usize;
+    let start = match cursor_id {
+        Some(cursor) => std::ops::Bound::Excluded(cursor),
+        None => std::ops::Bound::Unbounded,
+    };
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let mut visible = nfts.range((start, std::ops::Bound::Unbounded)).map(|(_, nft)| nft).filter(|nft| !nft.is_hidden);
+        let (page, truncated) = within_size_budget(visible.by_ref(), limit);
+        let has_more = truncated || visible.next().is_some();
+        let next_cursor = if has_more { page.last().map(|nft| nft.id) } else { None };
+        NftPage { nfts: page, next_cursor, truncated }
+    })
+}
+
+// Pure read in the order given; ids that don't resolve or are hidden are skipped
+#[ic_cdk::query]
+fn get_nfts_by_ids(ids: Vec<u64>) -> Vec<NFTMetadata> {
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let found = ids.iter().take(MAX_QUERY_LIMIT as usize).filter_map(|id| nfts.get(id).filter(|nft| !nft.is_hidden));
+        within_size_budget(found, usize::MAX).0
+    })
+}
+
+#[ic_cdk::query]
+fn get_recent_nfts(limit: u64) -> Vec<NFTMetadata> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let mut recent: Vec<&NFTMetadata> = nfts.values().filter(|nft| !nft.is_hidden).collect();
+        recent.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        within_size_budget(recent.into_iter(), limit).0
+    })
+}
+
+// Several NFTs can be minted from the same track, so this returns all visible ones
+#[ic_cdk::query]
+fn get_nfts_by_ipfs_hash(ipfs_hash: String) -> Vec<NFTMetadata> {
+    let ipfs_hash = ipfs_hash.trim();
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        within_size_budget(nfts.values().filter(|nft| !nft.is_hidden && nft.ipfs_hash.as_deref() == Some(ipfs_hash)), usize::MAX).0
+    })
+}
+
+// Case-insensitive substring match on project titles/descriptions and NFT names/descriptions
+#[ic_cdk::query]
+fn search_all(query: String) -> Result<SearchResults, String> {
+    let query = query.trim().to_lowercase();
+    if query.chars().count() < MIN_SEARCH_QUERY_LEN {
+        return Err(format!("Search query must be at least {} characters", MIN_SEARCH_QUERY_LEN));
+    }
+    let matches = |fields: [&str; 2]| fields.iter().any(|field| field.to_lowercase().contains(&query));
+
+    let mut projects: Vec<MusicProject> = PROJECTS.with(|projects| {
+        projects.borrow()
+            .values()
+            .filter(|project| !project.is_archived && matches([&project.title, &project.description]))
+            .cloned()
+            .collect()
+    });
+    projects.truncate(MAX_QUERY_LIMIT as usize);
+
+    let nfts = NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let matching = nfts.values().filter(|nft| !nft.is_hidden && matches([&nft.name, &nft.description]));
+        within_size_budget(matching, MAX_QUERY_LIMIT as usize).0
+    });
+
+    Ok(SearchResults { projects, nfts })
+}
+
+// Case-insensitive name lookup over visible NFTs: the whole name when `exact` is set,
+// otherwise any part of it, which needs the same minimum length as search_all
+#[ic_cdk::query]
+fn find_nfts_by_name(name_query: String, exact: bool) -> Result<Vec<NFTMetadata>, String> {
+    let query = name_query.trim().to_lowercase();
+    if query.is_empty() {
+        return Err("Name query cannot be empty".to_string());
+    }
+    if !exact && query.chars().count() < MIN_SEARCH_QUERY_LEN {
+        return Err(format!("Search query must be at least {} characters", MIN_SEARCH_QUERY_LEN));
+    }
+    Ok(NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let matching = nfts.values().filter(|nft| {
+            let name = nft.name.trim().to_lowercase();
+            !nft.is_hidden && if exact { name == query } else { name.contains(&query) }
+        });
+        within_size_budget(matching, MAX_QUERY_LIMIT as usize).0
+    }))
+}
+
+#[ic_cdk::query]
+fn get_nft(nft_id: u64) -> Option<NFTMetadata> {
+    NFTS.with(|nfts| nfts.borrow().get(&nft_id).filter(|nft| !nft.is_hidden).cloned())
+}
+
+// Counts a view unless the same viewer already viewed this NFT within the dedup window
+fn count_view(nft: &mut NFTMetadata, viewer: &str, now: u64) -> bool {
+    let window = get_view_dedup_window();
+    RECENT_VIEWS.with(|views| {
+        let mut views = views.borrow_mut();
+        RECENT_VIEW_ORDER.with(|order| {
+            let mut order = order.borrow_mut();
+            // Forget views that have aged out of the window so the map stays small
+            while order.front().is_some_and(|(at, _)| now.saturating_sub(*at) >= window) {
+                let (_, key) = order.pop_front().unwrap();
+                views.remove(&key);
+            }
+            let key = (nft.id, viewer.to_string());
+            if let Some(last) = views.get(&key) {
+                if now.saturating_sub(*last) < window {
+                    return false;
+                }
+            }
+            views.insert(key.clone(), now);
+            order.push_back((now, key));
+            nft.view_count += 1;
+            log_view(nft.id, now);
+            true
+        })
+    })
+}
+
+// Rolling (nft_id, timestamp) log of counted views, used for trending
+fn log_view(nft_id: u64, now: u64) {
+    VIEW_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        while log.front().is_some_and(|(_, ts)| now.saturating_sub(*ts) > TRENDING_WINDOW_NS)
+            || log.len() >= MAX_VIEW_LOG_ENTRIES
+        {
+            log.pop_front();
+        }
+        log.push_back((nft_id, now));
+    });
+}
+
+#[ic_cdk::query]
+fn get_trending_nfts(limit: u64) -> Vec<NFTMetadata> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    let since = time().saturating_sub(TRENDING_WINDOW_NS);
+
+    let mut recent_views: HashMap<u64, u64> = HashMap::new();
+    VIEW_LOG.with(|log| {
+        for (nft_id, ts) in log.borrow().iter() {
+            if *ts >= since {
+                *recent_views.entry(*nft_id).or_insert(0) += 1;
+            }
+        }
+    });
+
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let mut scored: Vec<(u64, &NFTMetadata)> = nfts
+            .values()
+            .filter(|nft| !nft.is_hidden)
+            .map(|nft| {
+                let views = recent_views.get(&nft.id).copied().unwrap_or(0);
+                let sales = nft.sale_history
+                    .iter()
+                    .filter(|tx| tx.transaction_type == "sale" && tx.timestamp >= since)
+                    .count() as u64;
+                (views * TRENDING_VIEW_WEIGHT + sales * TRENDING_SALE_WEIGHT, nft)
+            })
+            .filter(|(score, _)| *score > 0)
+            .collect();
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.id.cmp(&b.1.id)));
+        within_size_budget(scored.into_iter().map(|(_, nft)| nft), limit).0
+    })
+}
+
+// 0 turns deduplication off, so every view counts
+#[ic_cdk::update]
+fn set_view_dedup_window(window_ns: u64) -> Result<String, String> {
+    require_admin()?;
+    VIEW_DEDUP_WINDOW.with(|window| *window.borrow_mut() = window_ns);
+    Ok(format!("View dedup window set to {} ns", window_ns))
+}
+
+#[ic_cdk::query]
+fn get_view_dedup_window() -> u64 {
+    VIEW_DEDUP_WINDOW.with(|window| *window.borrow())
+}
+
+// Returns the NFT's view count after this view (which may have been deduplicated)
+#[ic_cdk::update]
+fn record_nft_view(nft_id: u64) -> Result<u64, String> {
+    let viewer = caller_text();
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).filter(|nft| !nft.is_hidden).ok_or("NFT not found")?;
+        count_view(nft, &viewer, time());
+        Ok(nft.view_count)
+    })
+}
+
+// Listed NFTs by price; equal prices keep id order in either direction
+#[ic_cdk::query]
+fn get_nfts_for_sale(offset: u64, limit: u64, ascending: bool) -> Vec<NFTMetadata> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let mut listed: Vec<&NFTMetadata> = nfts.values().filter(|nft| nft.is_for_sale && !nft.is_hidden).collect();
+        listed.sort_by(|a, b| {
+            let by_price = if ascending { a.price.cmp(&b.price) } else { b.price.cmp(&a.price) };
+            by_price.then(a.id.cmp(&b.id))
+        });
+        within_size_budget(listed.into_iter().skip(offset as usize), limit).0
+    })
+}
+
+// Other NFTs ranked by how many of category and project they share, then by views.
+// Project ids start at 1, so NFTs minted without a project (0) don't match on it.
+#[ic_cdk::query]
+fn get_similar_nfts(nft_id: u64, limit: u64) -> Vec<NFTMetadata> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let Some(target) = nfts.get(&nft_id) else {
+            return vec![];
+        };
+        let mut similar: Vec<(u8, &NFTMetadata)> = nfts
+            .values()
+            .filter(|nft| nft.id != nft_id && !nft.is_hidden)
+            .map(|nft| {
+                let same_category = nft.category == target.category;
+                let same_project = target.project_id != 0 && nft.project_id == target.project_id;
+                (same_category as u8 + same_project as u8, nft)
+            })
+            .filter(|(shared, _)| *shared > 0)
+            .collect();
+        similar.sort_by(|a, b| {
+            b.0.cmp(&a.0)
+                .then(b.1.view_count.cmp(&a.1.view_count))
+                .then(a.1.id.cmp(&b.1.id))
+        });
+        within_size_budget(similar.into_iter().map(|(_, nft)| nft), limit).0
+    })
+}
+
+#[ic_cdk::query]
+fn get_most_viewed_nfts(limit: u64) -> Vec<NFTMetadata> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let mut viewed: Vec<&NFTMetadata> = nfts.values().filter(|nft| !nft.is_hidden).collect();
+        viewed.sort_by(|a, b| b.view_count.cmp(&a.view_count).then(a.id.cmp(&b.id)));
+        within_size_budget(viewed.into_iter(), limit).0
+    })
+}
+
+// Listed NFTs not listed or repriced within the last `older_than_ns`, oldest first
+#[ic_cdk::query]
+fn get_stale_listings(older_than_ns: u64) -> Vec<NFTMetadata> {
+    let cutoff = time().saturating_sub(older_than_ns);
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let mut stale: Vec<&NFTMetadata> = nfts
+            .values()
+            .filter(|nft| nft.is_for_sale && !nft.is_hidden && nft.last_listed_at < cutoff)
+            .collect();
+        stale.sort_by_key(|nft| (nft.last_listed_at, nft.id));
+        within_size_budget(stale.into_iter(), MAX_QUERY_LIMIT as usize).0
+    })
+}
+
+// (rank, category size) by view count among visible NFTs of the same category, using
+// the same ordering as get_most_viewed_nfts: ties go to the lower id
+#[ic_cdk::query]
+fn get_nft_category_rank(nft_id: u64) -> Option<(u64, u64)> {
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let target = nfts.get(&nft_id).filter(|nft| !nft.is_hidden)?;
+        let mut rank = 1;
+        let mut total = 0;
+        for nft in nfts.values().filter(|nft| nft.category == target.category && !nft.is_hidden) {
+            total += 1;
+            if (nft.view_count, std::cmp::Reverse(nft.id)) > (target.view_count, std::cmp::Reverse(target.id)) {
+                rank += 1;
+            }
+        }
+        Some((rank, total))
+    })
+}
+
+#[ic_cdk::query]
+fn get_view_count(nft_id: u64) -> Option<u64> {
+    NFTS.with(|nfts| nfts.borrow().get(&nft_id).filter(|nft| !nft.is_hidden).map(|nft| nft.view_count))
+}
+
+// get_nft stays a pure query; this is for detail pages that should count as a view
+#[ic_cdk::update]
+fn get_nft_and_record_view(nft_id: u64) -> Option<NFTMetadata> {
+    record_nft_view(nft_id).ok()?;
+    get_nft(nft_id)
+}
+
+// Only the first MAX_QUERY_LIMIT ids of a batch are recorded
+#[ic_cdk::update]
+fn record_views(nft_ids: Vec<u64>) -> u64 {
+    let viewer = caller_text();
+    let now = time();
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let mut counted = 0;
+        for nft_id in nft_ids.into_iter().take(MAX_QUERY_LIMIT as usize) {
+            if let Some(nft) = nfts.get_mut(&nft_id).filter(|nft| !nft.is_hidden) {
+                if count_view(nft, &viewer, now) {
+                    counted += 1;
+                }
+            }
+        }
+        counted
+    })
+}
+
+// Every change to is_for_sale goes through here so the cached for-sale count stays right
+fn set_for_sale(nft: &mut NFTMetadata, for_sale: bool) {
+    if for_sale {
+        nft.last_listed_at = time();
+    }
+    if nft.is_for_sale != for_sale {
+        nft.is_for_sale = for_sale;
+        update_stats(|stats| {
+            if for_sale {
+                stats.nfts_for_sale += 1;
+            } else {
+                stats.nfts_for_sale -= 1;
+            }
+        });
+    }
+}
+
+fn update_stats(update: impl FnOnce(&mut MarketplaceStats)) {
+    MARKET_STATS.with(|stats| update(&mut stats.borrow_mut()));
+}
+
+// Full scan over every NFT and transaction; the cached version should always agree with it
+#[ic_cdk::query]
+fn get_marketplace_stats() -> MarketplaceStats {
+    let (total_nfts, nfts_for_sale) = NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        (nfts.len() as u64, nfts.values().filter(|nft| nft.is_for_sale).count() as u64)
+    });
+    let (sale_count, total_volume) = TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.transaction_type == "sale")
+            .fold((0, 0), |(count, volume), tx| (count + 1, volume + tx.price))
+    });
+    MarketplaceStats {
+        total_nfts,
+        nfts_for_sale,
+        sale_count,
+        total_volume,
+    }
+}
+
+#[ic_cdk::query]
+fn get_marketplace_stats_cached() -> MarketplaceStats {
+    MARKET_STATS.with(|stats| stats.borrow().clone())
+}
+
+#[ic_cdk::update]
+fn set_nft_for_sale(nft_id: u64, for_sale: bool) -> Result<String, MarketError> {
+    let caller = caller_text();
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or(MarketError::NotFound)?;
+        if nft.current_owner != caller {
+            return Err(MarketError::Unauthorized("Only the NFT owner can change its sale status".to_string()));
+        }
+        if for_sale && nft.price == 0 {
+            return Err(MarketError::InvalidPrice("Set a price before listing this NFT".to_string()));
+        }
+        if for_sale && nft.is_hidden {
+            return Err(MarketError::InvalidInput("A hidden NFT can't be listed".to_string()));
+        }
+        set_for_sale(nft, for_sale);
+        Ok(format!("NFT {} is {}", nft_id, if for_sale { "now for sale" } else { "no longer for sale" }))
+    })
+}
+
+// Per-id outcome of listing or unlisting each NFT; ids past MAX_QUERY_LIMIT are
+// rejected without being touched
+#[ic_cdk::update]
+fn batch_set_for_sale(nft_ids: Vec<u64>, for_sale: bool) -> Vec<(u64, Result<(), MarketError>)> {
+    let caller = caller_text();
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        nft_ids
+            .into_iter()
+            .enumerate()
+            .map(|(index, nft_id)| {
+                if index >= MAX_QUERY_LIMIT as usize {
+                    let error = format!("Batch is limited to {} NFTs", MAX_QUERY_LIMIT);
+                    return (nft_id, Err(MarketError::InvalidInput(error)));
+                }
+                let result = match nfts.get_mut(&nft_id) {
+                    None => Err(MarketError::NotFound),
+                    Some(nft) if nft.current_owner != caller => {
+                        Err(MarketError::Unauthorized("Only the NFT owner can change its sale status".to_string()))
+                    }
+                    Some(nft) if for_sale && nft.price == 0 => {
+                        Err(MarketError::InvalidPrice("Set a price before listing this NFT".to_string()))
+                    }
+                    Some(nft) if for_sale && nft.is_hidden => {
+                        Err(MarketError::InvalidInput("A hidden NFT can't be listed".to_string()))
+                    }
+                    Some(nft) => {
+                        set_for_sale(nft, for_sale);
+                        Ok(())
+                    }
+                };
+                (nft_id, result)
+            })
+            .collect()
+    })
+}
+
+// The creator may sell at any price; everyone else is held to the resale floor
+fn check_resale_floor(nft: &NFTMetadata, seller: &str, price: u64) -> Result<(), MarketError> {
+    match nft.min_resale_price {
+        Some(floor) if price < floor && seller != nft.creator => Err(MarketError::InvalidPrice(format!(
+            "Price {} is below the creator's minimum resale price of {}",
+            price, floor
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// The single place the split of a sale is worked out, shared by buy_nft and preview_purchase
+fn calculate_purchase(nft: &NFTMetadata, buyer: &str) -> Result<PurchasePreview, MarketError> {
+    // Hidden NFTs are treated as unlisted
+    if nft.is_hidden || !nft.is_for_sale {
+        return Err(MarketError::NotForSale);
+    }
+    if nft.current_owner == buyer {
+        return Err(MarketError::AlreadyOwner);
+    }
+    check_resale_floor(nft, &nft.current_owner, nft.price)?;
+
+    // The creator selling their own work does not owe themselves a royalty
+    let royalty_amount = if nft.creator == nft.current_owner {
+        0
+    } else {
+        (nft.price as u128 * nft.royalty_percentage as u128 / 100) as u64
+    };
+    let PlatformFee { percentage, recipient: fee_recipient } = PLATFORM_FEE.with(|fee| fee.borrow().clone());
+    let platform_fee = (nft.price as u128 * percentage as u128 / 100) as u64;
+    let seller_amount = nft.price
+        .checked_sub(royalty_amount)
+        .and_then(|rest| rest.checked_sub(platform_fee))
+        .ok_or(MarketError::InvalidPrice("Royalty and platform fee exceed the sale price".to_string()))?;
+    Ok(PurchasePreview {
+        nft_id: nft.id,
+        sale_price: nft.price,
+        royalty_amount,
+        seller_amount,
+        seller: nft.current_owner.clone(),
+        royalty_recipient: nft.creator.clone(),
+        platform_fee,
+        fee_recipient,
+    })
+}
+
+#[ic_cdk::query]
+fn preview_purchase(nft_id: u64) -> Result<PurchasePreview, MarketError> {
+    let buyer = caller_text();
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let nft = nfts.get(&nft_id).ok_or(MarketError::NotFound)?;
+        calculate_purchase(nft, &buyer)
+    })
+}
+
+// Buyers pay from their escrow subaccount on this canister; get_escrow_account says how
+// much it must hold. Without a configured ledger the sale is bookkeeping only.
+#[ic_cdk::update]
+async fn buy_nft(nft_id: u64, idempotency_key: Option<String>) -> Result<String, MarketError> {
+    require_not_blacklisted().map_err(|_| MarketError::Blocked)?;
+    let buyer = caller_text();
+    let now = time();
+
+    // A retry of a purchase that already went through gets the original result back
+    let idempotency_key = idempotency_key.map(|key| key.trim().to_string());
+    if let Some(key) = &idempotency_key {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(MarketError::InvalidInput(format!(
+                "Idempotency key must be 1 to {} characters",
+                MAX_IDEMPOTENCY_KEY_LEN
+            )));
+        }
+        let completed = COMPLETED_PURCHASES.with(|completed| {
+            let mut completed = completed.borrow_mut();
+            completed.retain(|_, purchase| now.saturating_sub(purchase.completed_at) < IDEMPOTENCY_WINDOW_NS);
+            completed.get(&(buyer.clone(), key.clone())).cloned()
+        });
+        if let Some(purchase) = completed {
+            if purchase.nft_id != nft_id {
+                return Err(MarketError::InvalidInput(format!(
+                    "Idempotency key was already used to buy NFT {}",
+                    purchase.nft_id
+                )));
+            }
+            return Ok(purchase.result);
+        }
+    }
+
+    // Validate and snapshot under one borrow. Ownership stays with the seller until
+    // the payment lands; the in-flight mark keeps a second buyer out meanwhile.
+    let preview = NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let nft = nfts.get(&nft_id).ok_or(MarketError::NotFound)?;
+        calculate_purchase(nft, &buyer)
+    })?;
+    let in_flight = PurchaseInFlight::mark(nft_id)?;
+    let result = settle_purchase(caller(), preview).await;
+    drop(in_flight);
+    // Only successes are remembered, so a failed attempt can be retried with the same key
+    if let (Some(key), Ok(message)) = (idempotency_key, &result) {
+        COMPLETED_PURCHASES.with(|completed| {
+            let purchase = CompletedPurchase { completed_at: time(), nft_id, result: message.clone() };
+            completed.borrow_mut().insert((buyer, key), purchase);
+        });
+    }
+    result
+}
+
+// Holds an NFT's in-flight mark until dropped. A trap after an await still drops it,
+// since ic-cdk cleans up the suspended call, so a failed settlement can't leave the NFT
+// locked against every later buyer.
+struct PurchaseInFlight(u64);
+
+impl PurchaseInFlight {
+    fn mark(nft_id: u64) -> Result<Self, MarketError> {
+        if PURCHASES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(nft_id)) {
+            Ok(PurchaseInFlight(nft_id))
+        } else {
+            Err(MarketError::PurchaseInProgress)
+        }
+    }
+}
+
+impl Drop for PurchaseInFlight {
+    fn drop(&mut self) {
+        PURCHASES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
+}
+
+async fn settle_purchase(buyer_principal: Principal, preview: PurchasePreview) -> Result<String, MarketError> {
+    let buyer = buyer_principal.to_text();
+    let PurchasePreview {
+        nft_id,
+        sale_price: price,
+        royalty_amount,
+        seller_amount,
+        seller,
+        royalty_recipient,
+        platform_fee,
+        fee_recipient,
+    } = preview;
+    // (recipient, amount, is the royalty)
+    let mut payouts = vec![(seller.clone(), seller_amount, false)];
+    if royalty_amount > 0 {
+        payouts.push((royalty_recipient.clone(), royalty_amount, true));
+    }
+    // A fee is only charged while a recipient is configured
+    let fee_recipient = fee_recipient.filter(|_| platform_fee > 0);
+    if let Some(recipient) = &fee_recipient {
+        payouts.push((recipient.clone(), platform_fee, false));
+    }
+
+    // Hold the buyer's funds in the settlement account, together with the fees for
+    // paying them out, so they can be refunded if the sale can't go ahead
+    let ledger = LEDGER.with(|ledger| *ledger.borrow());
+    let mut fee = 0;
+    let mut payout_accounts = Vec::new();
+    if let Some(ledger) = ledger {
+        for (recipient, amount, is_royalty) in &payouts {
+            let account = principal_account(recipient).map_err(MarketError::InvalidInput)?;
+            payout_accounts.push((account, *amount, *is_royalty));
+        }
+        fee = icrc1_fee(ledger).await.map_err(MarketError::PaymentFailed)?;
+        let held = price + fee * payouts.len() as u64;
+        icrc1_transfer(ledger, escrow_subaccount(&buyer_principal), own_account(SETTLEMENT_SUBACCOUNT), held)
+            .await
+            .map_err(|error| MarketError::PaymentFailed(format!("collecting {}: {}", held, error)))?;
+    }
+
+    // Commit only if the NFT is still listed by the same seller at the same price
+    let transaction_id = NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).filter(|nft| {
+            nft.current_owner == seller && nft.price == price && nft.is_for_sale && !nft.is_hidden
+        })?;
+        nft.current_owner = buyer.clone();
+        set_for_sale(nft, false);
+        Some(record_transaction(nft, "sale", seller.clone(), buyer.clone(), price))
+    });
+    let Some(transaction_id) = transaction_id else {
+        if let Some(ledger) = ledger {
+            // The refund transfer's own fee comes out of the held amount
+            let refund = price + fee * (payouts.len() as u64 - 1);
+            let to = own_account(escrow_subaccount(&buyer_principal));
+            if icrc1_transfer(ledger, SETTLEMENT_SUBACCOUNT, to.clone(), refund).await.is_err() {
+                queue_payout(nft_id, to, refund);
+            }
+        }
+        return Err(MarketError::ListingChanged);
+    };
+
+    update_stats(|stats| {
+        stats.sale_count += 1;
+        stats.total_volume += price;
+    });
+    if let Some(recipient) = fee_recipient {
+        FEE_PAYMENTS.with(|payments| {
+            payments.borrow_mut().push(FeePayment {
+                transaction_id,
+                nft_id,
+                recipient,
+                amount: platform_fee,
+                timestamp: time(),
+            });
+        });
+    }
+    if royalty_amount > 0 {
+        // Without a ledger the sale is bookkeeping only, so there is no transfer to wait for
+        let status = if ledger.is_some() { "pending" } else { "paid" };
+        let now = time();
+        ROYALTY_PAYMENTS.with(|payments| {
+            payments.borrow_mut().push(RoyaltyPayment {
+                transaction_id,
+                nft_id,
+                recipient: royalty_recipient.clone(),
+                amount: royalty_amount,
+                timestamp: now,
+                status: status.to_string(),
+                status_updated_at: now,
+            });
+        });
+    }
+    push_event(&seller, EventKind::Sold { nft_id, buyer: buyer.clone(), price });
+    push_event(&buyer, EventKind::Purchased { nft_id, seller: seller.clone(), price });
+    if royalty_amount > 0 {
+        push_event(&royalty_recipient, EventKind::RoyaltyReceived { nft_id, amount: royalty_amount });
+    }
+
+    // The sale stands from here. A refused royalty is marked failed for retry_failed_royalty;
+    // other refused payouts wait in the payout queue.
+    if let Some(ledger) = ledger {
+        for (to, amount, is_royalty) in payout_accounts {
+            let sent = icrc1_transfer(ledger, SETTLEMENT_SUBACCOUNT, to.clone(), amount).await.is_ok();
+            if is_royalty {
+                set_royalty_status(transaction_id, if sent { "paid" } else { "failed" });
+            } else if !sent {
+                queue_payout(nft_id, to, amount);
+            }
+        }
+    }
+
+    Ok(format!("NFT {} purchased by {} for {}", nft_id, buyer, price))
+}
+
+fn set_royalty_status(transaction_id: u64, status: &str) {
+    ROYALTY_PAYMENTS.with(|payments| {
+        if let Some(payment) = payments.borrow_mut().iter_mut().find(|p| p.transaction_id == transaction_id) {
+            payment.status = status.to_string();
+            payment.status_updated_at = time();
+        }
+    });
+}
+
+// Every royalty this NFT's sales have generated, whatever their payout status
+#[ic_cdk::query]
+fn get_nft_royalty_payments(nft_id: u64) -> Vec<RoyaltyPayment> {
+    ROYALTY_PAYMENTS.with(|payments| {
+        payments.borrow().iter().filter(|payment| payment.nft_id == nft_id).cloned().collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_nft_total_royalties(nft_id: u64) -> u64 {
+    ROYALTY_PAYMENTS.with(|payments| {
+        payments.borrow()
+            .iter()
+            .filter(|payment| payment.nft_id == nft_id)
+            .map(|payment| payment.amount)
+            .fold(0u64, u64::saturating_add)
+    })
+}
+
+// Royalties owed to a user that haven't reached them yet, whether pending or failed
+#[ic_cdk::query]
+fn get_pending_royalties(user: String) -> Vec<RoyaltyPayment> {
+    let Ok(user) = normalize_principal("User", &user) else {
+        return vec![];
+    };
+    ROYALTY_PAYMENTS.with(|payments| {
+        payments.borrow()
+            .iter()
+            .filter(|p| p.recipient == user && p.status != "paid")
+            .cloned()
+            .collect()
+    })
+}
+
+// Resends a failed royalty from the settlement account; the recipient or the admin may ask
+#[ic_cdk::update]
+async fn retry_failed_royalty(transaction_id: u64) -> Result<String, MarketError> {
+    let ledger = LEDGER.with(|ledger| *ledger.borrow())
+        .ok_or_else(|| MarketError::PaymentFailed("No ledger canister configured".to_string()))?;
+    let caller = caller_text();
+    let is_admin = require_admin().is_ok();
+    let (recipient, to, amount) = ROYALTY_PAYMENTS.with(|payments| {
+        let mut payments = payments.borrow_mut();
+        let payment = payments
+            .iter_mut()
+            .find(|p| p.transaction_id == transaction_id)
+            .ok_or_else(|| MarketError::InvalidInput("Royalty payment not found".to_string()))?;
+        if payment.recipient != caller && !is_admin {
+            return Err(MarketError::Unauthorized("Only the recipient or the admin can retry this royalty".to_string()));
+        }
+        if payment.status != "failed" {
+            return Err(MarketError::InvalidInput(format!("Royalty payment is {}, not failed", payment.status)));
+        }
+        let to = principal_account(&payment.recipient).map_err(MarketError::InvalidInput)?;
+        // Marked pending while in flight so a second retry can't pay it twice
+        payment.status = "pending".to_string();
+        payment.status_updated_at = time();
+        Ok((payment.recipient.clone(), to, payment.amount))
+    })?;
+
+    match icrc1_transfer(ledger, SETTLEMENT_SUBACCOUNT, to, amount).await {
+        Ok(_) => {
+            set_royalty_status(transaction_id, "paid");
+            Ok(format!("Royalty of {} paid to {}", amount, recipient))
+        }
+        Err(error) => {
+            set_royalty_status(transaction_id, "failed");
+            Err(MarketError::PaymentFailed(error))
+        }
+    }
+}
+
+fn queue_payout(nft_id: u64, to: Account, amount: u64) {
+    PENDING_PAYOUTS.with(|pending| pending.borrow_mut().push(PendingPayout { nft_id, to, amount }));
+}
+
+#[ic_cdk::query]
+fn get_pending_payouts() -> Result<Vec<PendingPayout>, String> {
+    require_admin()?;
+    Ok(PENDING_PAYOUTS.with(|pending| pending.borrow().clone()))
+}
+
+// Retries every queued payout from the settlement account, keeping the ones that fail again
+#[ic_cdk::update]
+async fn retry_pending_payouts() -> Result<String, MarketError> {
+    require_admin().map_err(MarketError::Unauthorized)?;
+    let ledger = LEDGER.with(|ledger| *ledger.borrow())
+        .ok_or_else(|| MarketError::PaymentFailed("No ledger canister configured".to_string()))?;
+    let pending = PENDING_PAYOUTS.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    let total = pending.len();
+    let mut failed = 0;
+    for payout in pending {
+        if icrc1_transfer(ledger, SETTLEMENT_SUBACCOUNT, payout.to.clone(), payout.amount).await.is_err() {
+            failed += 1;
+            PENDING_PAYOUTS.with(|pending| pending.borrow_mut().push(payout));
+        }
+    }
+    Ok(format!("{} of {} payouts sent", total - failed, total))
+}
+
+#[ic_cdk::update]
+fn transfer_nft(nft_id: u64, to: String) -> Result<String, MarketError> {
+    let caller = caller_text();
+    let to = normalize_principal("Recipient", &to).map_err(MarketError::InvalidInput)?;
+
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or(MarketError::NotFound)?;
+        if nft.current_owner != caller {
+            return Err(MarketError::Unauthorized("Only the NFT owner can transfer it".to_string()));
+        }
+        if to == caller {
+            return Err(MarketError::InvalidInput("Cannot transfer an NFT to yourself".to_string()));
+        }
+
+        record_transaction(nft, "transfer", caller.clone(), to.clone(), 0);
+        push_event(&to, EventKind::TransferReceived { nft_id, from: caller });
+        nft.current_owner = to.clone();
+        set_for_sale(nft, false);
+        Ok(format!("NFT {} transferred to {}", nft_id, to))
+    })
+}
+
+#[ic_cdk::query]
+fn get_all_transactions_paged(offset: u64, limit: u64) -> TransactionPage {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        TransactionPage {
+            transactions: transactions.iter().skip(offset as usize).take(limit).cloned().collect(),
+            total: transactions.len() as u64,
+        }
+    })
+}
+
+// Every transaction the user sent or received, newest first
+#[ic_cdk::query]
+fn get_user_transactions(user: String) -> Vec<Transaction> {
+    let Ok(user) = normalize_principal("User", &user) else {
+        return vec![];
+    };
+    let mut history: Vec<Transaction> = TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.from == user || tx.to == user)
+            .cloned()
+            .collect()
+    });
+    history.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(b.id.cmp(&a.id)));
+    history
+}
+
+#[ic_cdk::query]
+fn get_user_transactions_paged(user: String, offset: u64, limit: u64) -> TransactionPage {
+    let history = get_user_transactions(user);
+    TransactionPage {
+        transactions: page(&history, offset, limit),
+        total: history.len() as u64,
+    }
+}
+
+#[ic_cdk::query]
+fn get_transactions_by_type(transaction_type: String) -> Result<Vec<Transaction>, String> {
+    if !TRANSACTION_TYPES.contains(&transaction_type.as_str()) {
+        return Err(format!(
+            "Unknown transaction type '{}', expected one of: {}",
+            transaction_type,
+            TRANSACTION_TYPES.join(", ")
+        ));
+    }
+    Ok(TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.transaction_type == transaction_type)
+            .cloned()
+            .collect()
+    }))
+}
+
+// Mints, sales and transfers across the marketplace, newest first: TRANSACTIONS is kept
+// in time order, so this walks it backwards. Activity for hidden NFTs is left out; an NFT
+// missing from the store is reported without a name
+#[ic_cdk::query]
+fn get_global_activity(limit: u64) -> Vec<ActivityItem> {
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    TRANSACTIONS.with(|transactions| {
+        NFTS.with(|nfts| {
+            let nfts = nfts.borrow();
+            transactions.borrow()
+                .iter()
+                .rev()
+                .filter(|tx| TRANSACTION_TYPES.contains(&tx.transaction_type.as_str()))
+                .filter_map(|tx| match nfts.get(&tx.nft_id) {
+                    Some(nft) if nft.is_hidden => None,
+                    Some(nft) => Some(ActivityItem { nft_name: Some(nft.name.clone()), transaction: tx.clone() }),
+                    None => Some(ActivityItem { nft_name: None, transaction: tx.clone() }),
+                })
+                .take(limit)
+                .collect()
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_nft_transactions(nft_id: u64) -> Vec<Transaction> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_id)
+            .filter(|nft| !nft.is_hidden)
+            .map(|nft| nft.sale_history.clone())
+            .unwrap_or_default()
+    })
+}
+
+// Every transaction of the NFT from the global ledger, oldest first, including
+// those that have aged out of its inline sale_history
+#[ic_cdk::query]
+fn get_full_nft_history(nft_id: u64) -> Vec<Transaction> {
+    TRANSACTIONS.with(|transactions| {
+        transactions.borrow().iter().filter(|tx| tx.nft_id == nft_id).cloned().collect()
+    })
+}
+
+// Walks the NFT's full ownership chain: it must open with a mint to the creator, each
+// transaction must start where the previous ended, and it must end at the current owner
+#[ic_cdk::query]
+fn verify_nft_provenance(nft_id: u64) -> Option<ProvenanceReport> {
+    let (creator, current_owner) = NFTS.with(|nfts| {
+        nfts.borrow().get(&nft_id).map(|nft| (nft.creator.clone(), nft.current_owner.clone()))
+    })?;
+    let chain: Vec<Transaction> = get_full_nft_history(nft_id)
+        .into_iter()
+        .filter(|tx| TRANSACTION_TYPES.contains(&tx.transaction_type.as_str()))
+        .collect();
+
+    let mut issues = Vec::new();
+    match chain.first() {
+        None => issues.push("No mint transaction recorded".to_string()),
+        Some(first) if first.transaction_type != "mint" => {
+            issues.push(format!("Transaction {} is a {} but the chain must start with a mint", first.id, first.transaction_type));
+        }
+        Some(first) if first.to != creator => {
+            issues.push(format!("Mint transaction {} went to {} rather than the creator {}", first.id, first.to, creator));
+        }
+        Some(_) => {}
+    }
+    for pair in chain.windows(2) {
+        let (previous, next) = (&pair[0], &pair[1]);
+        if next.transaction_type == "mint" {
+            issues.push(format!("Transaction {} is a second mint", next.id));
+        }
+        if previous.to != next.from {
+            issues.push(format!(
+                "Transaction {} ends with {} but transaction {} starts from {}",
+                previous.id, previous.to, next.id, next.from
+            ));
+        }
+    }
+    if let Some(last) = chain.last() {
+        if last.to != current_owner {
+            issues.push(format!(
+                "Last transaction {} ends with {} but the current owner is {}",
+                last.id, last.to, current_owner
+            ));
+        }
+    }
+
+    Some(ProvenanceReport {
+        nft_id,
+        is_consistent: issues.is_empty(),
+        transactions_checked: chain.len() as u64,
+        issues,
+    })
+}
+
+// Each owner of the NFT with the time they acquired it, starting from the minter
+#[ic_cdk::query]
+fn get_nft_ownership_history(nft_id: u64) -> Vec<(String, u64)> {
+    get_full_nft_history(nft_id)
+        .into_iter()
+        .filter(|tx| TRANSACTION_TYPES.contains(&tx.transaction_type.as_str()))
+        .map(|tx| (tx.to, tx.timestamp))
+        .collect()
+}
+
+// (timestamp, price) for each sale of the NFT, oldest first
+#[ic_cdk::query]
+fn get_nft_price_history(nft_id: u64) -> Vec<(u64, u64)> {
+    let mut history: Vec<(u64, u64)> = get_full_nft_history(nft_id)
+        .into_iter()
+        .filter(|tx| tx.transaction_type == "sale")
+        .map(|tx| (tx.timestamp, tx.price))
+        .collect();
+    history.sort_by_key(|(timestamp, _)| *timestamp);
+    history
+}
+
+// Earliest sale timestamp for every NFT that has sold
+fn first_sale_times() -> HashMap<u64, u64> {
+    let mut first_sales: HashMap<u64, u64> = HashMap::new();
+    TRANSACTIONS.with(|transactions| {
+        for tx in transactions.borrow().iter().filter(|tx| tx.transaction_type == "sale") {
+            first_sales
+                .entry(tx.nft_id)
+                .and_modify(|first| *first = (*first).min(tx.timestamp))
+                .or_insert(tx.timestamp);
+        }
+    });
+    first_sales
+}
+
+// Nanoseconds from mint to the first sale; None if the NFT has never sold
+#[ic_cdk::query]
+fn get_nft_time_to_first_sale(nft_id: u64) -> Option<u64> {
+    let created_at = NFTS.with(|nfts| nfts.borrow().get(&nft_id).map(|nft| nft.created_at))?;
+    let first_sale = TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.nft_id == nft_id && tx.transaction_type == "sale")
+            .map(|tx| tx.timestamp)
+            .min()
+    })?;
+    Some(first_sale.saturating_sub(created_at))
+}
+
+// Mean of get_nft_time_to_first_sale over every NFT that has sold
+#[ic_cdk::query]
+fn get_average_time_to_sale() -> Option<u64> {
+    let first_sales = first_sale_times();
+    let durations: Vec<u64> = NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        first_sales
+            .iter()
+            .filter_map(|(nft_id, first_sale)| nfts.get(nft_id).map(|nft| first_sale.saturating_sub(nft.created_at)))
+            .collect()
+    });
+    if durations.is_empty() {
+        return None;
+    }
+    let total: u128 = durations.iter().map(|duration| *duration as u128).sum();
+    Some((total / durations.len() as u128) as u64)
+}
+
+// Lowest and highest sale price of the NFT, if it has ever sold
+#[ic_cdk::query]
+fn get_nft_floor_and_ceiling(nft_id: u64) -> Option<(u64, u64)> {
+    let prices: Vec<u64> = get_nft_price_history(nft_id).into_iter().map(|(_, price)| price).collect();
+    Some((*prices.iter().min()?, *prices.iter().max()?))
+}
+
+// Rough value of a user's holdings: a listed NFT counts at its asking price, an
+// unlisted one at the price it last sold for, and one that never sold counts as 0
+#[ic_cdk::query]
+fn get_portfolio_value(user: String) -> u64 {
+    let Ok(user) = normalize_principal("User", &user) else {
+        return 0;
+    };
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .values()
+            .filter(|nft| nft.current_owner == user)
+            .map(|nft| {
+                if nft.is_for_sale {
+                    nft.price
+                } else {
+                    nft.sale_history
+                        .iter()
+                        .rev()
+                        .find(|tx| tx.transaction_type == "sale")
+                        .map_or(0, |tx| tx.price)
+                }
+            })
+            .fold(0u64, u64::saturating_add)
+    })
+}
+
+// Profile page counts in one call. Royalties count only payments that have been paid
+#[ic_cdk::query]
+fn get_user_summary(user: String) -> UserSummary {
+    let Ok(user) = normalize_principal("User", &user) else {
+        return UserSummary::default();
+    };
+    let mut summary = UserSummary::default();
+    NFTS.with(|nfts| {
+        for nft in nfts.borrow().values() {
+            if nft.creator == user {
+                summary.created += 1;
+            }
+            if nft.current_owner == user {
+                summary.owned += 1;
+                if nft.is_for_sale {
+                    summary.owned_for_sale += 1;
+                }
+            }
+        }
+    });
+    summary.royalties_earned = ROYALTY_PAYMENTS.with(|payments| {
+        payments.borrow()
+            .iter()
+            .filter(|payment| payment.recipient == user && payment.status == "paid")
+            .map(|payment| payment.amount)
+            .fold(0u64, u64::saturating_add)
+    });
+    summary.projects_owned = PROJECTS.with(|projects| {
+        projects.borrow().values().filter(|project| project.owner == user).count() as u64
+    });
+    summary
+}
+
+// NFTs held per owner, most first, ties by principal
+#[ic_cdk::query]
+fn get_ownership_distribution() -> Vec<(String, u64)> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    NFTS.with(|nfts| {
+        for nft in nfts.borrow().values() {
+            *counts.entry(nft.current_owner.clone()).or_insert(0) += 1;
+        }
+    });
+    let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+#[ic_cdk::query]
+fn get_unique_owner_count() -> u64 {
+    NFTS.with(|nfts| {
+        nfts.borrow().values().map(|nft| &nft.current_owner).collect::<HashSet<_>>().len() as u64
+    })
+}
+
+// Sales with start_ns <= timestamp <= end_ns; the average is rounded down
+#[ic_cdk::query]
+fn get_sales_in_range(start_ns: u64, end_ns: u64) -> Result<SalesSummary, String> {
+    if start_ns > end_ns {
+        return Err("Start of the range must not be after its end".to_string());
+    }
+    let prices: Vec<u64> = TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.transaction_type == "sale" && (start_ns..=end_ns).contains(&tx.timestamp))
+            .map(|tx| tx.price)
+            .collect()
+    });
+    let count = prices.len() as u64;
+    let total_volume: u64 = prices.iter().sum();
+    Ok(SalesSummary {
+        count,
+        total_volume,
+        average_price: total_volume.checked_div(count).unwrap_or(0),
+        min_price: prices.iter().min().copied(),
+        max_price: prices.iter().max().copied(),
+    })
+}
+
+// Total value of resales of a creator's works, which excludes the creator's own
+// primary sales and is independent of the royalties they earned
+#[ic_cdk::query]
+fn get_creator_volume(creator: String) -> u64 {
+    let Ok(creator) = normalize_principal("Creator", &creator) else {
+        return 0;
+    };
+    let works: HashSet<u64> = NFTS.with(|nfts| {
+        nfts.borrow().values().filter(|nft| nft.creator == creator).map(|nft| nft.id).collect()
+    });
+    TRANSACTIONS.with(|transactions| {
+        transactions.borrow()
+            .iter()
+            .filter(|tx| tx.transaction_type == "sale" && tx.from != creator && works.contains(&tx.nft_id))
+            .map(|tx| tx.price)
+            .sum()
+    })
+}
+
+// Visible NFTs the creator still holds that have never changed hands since the mint
+#[ic_cdk::query]
+fn get_creator_unsold_nfts(creator: String) -> Vec<NFTMetadata> {
+    let Ok(creator) = normalize_principal("Creator", &creator) else {
+        return vec![];
+    };
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        let unsold = nfts.values().filter(|nft| {
+            !nft.is_hidden
+                && nft.creator == creator
+                && nft.current_owner == creator
+                && nft.sale_history.iter().all(|tx| tx.transaction_type == "mint")
+        });
+        within_size_budget(unsold, usize::MAX).0
+    })
+}
+
+// Replaces both media links; pass None to clear one
+#[ic_cdk::update]
+fn update_nft_media(
+    nft_id: u64,
+    animation_url: Option<String>,
+    audio_preview_url: Option<String>,
+) -> Result<String, MarketError> {
+    validate_nft_media(&animation_url, &audio_preview_url).map_err(MarketError::InvalidInput)?;
+    let caller = caller_text();
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or(MarketError::NotFound)?;
+        if nft.current_owner != caller {
+            return Err(MarketError::Unauthorized("Only the NFT owner can update its media".to_string()));
+        }
+        nft.animation_url = animation_url;
+        nft.audio_preview_url = audio_preview_url;
+        Ok(format!("NFT {} media updated", nft_id))
+    })
+}
+
+#[ic_cdk::update]
+fn update_nft_price(nft_id: u64, new_price: u64) -> Result<String, MarketError> {
+    if new_price == 0 {
+        return Err(MarketError::InvalidPrice("Price must be greater than zero".to_string()));
+    }
+    let caller = caller_text();
+    let min_increment = MIN_PRICE_INCREMENT.with(|min| min.borrow().clone());
+
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or(MarketError::NotFound)?;
+        if nft.current_owner != caller {
+            return Err(MarketError::Unauthorized("Only the NFT owner can update its price".to_string()));
+        }
+        check_resale_floor(nft, &caller, new_price)?;
+        check_price_increment(nft.price, new_price, &min_increment)?;
+
+        nft.price = new_price;
+        nft.last_listed_at = time();
+        Ok(format!("NFT {} price updated to {}", nft_id, new_price))
+    })
+}
+
+fn check_price_increment(old_price: u64, new_price: u64, min_increment: &Option<MinPriceIncrement>) -> Result<(), MarketError> {
+    let delta = old_price.abs_diff(new_price);
+    match *min_increment {
+        Some(MinPriceIncrement::Absolute(min)) if delta < min => Err(MarketError::InvalidPrice(format!(
+            "Price change of {} is below the minimum increment of {}",
+            delta, min
+        ))),
+        Some(MinPriceIncrement::Percentage(pct)) if (delta as u128) * 100 < (old_price as u128) * (pct as u128) => {
+            Err(MarketError::InvalidPrice(format!(
+                "Price change of {} is below the minimum increment of {}%",
+                delta, pct
+            )))
+        }
+        _ => Ok(()),
+    }
 }
 
-#[derive(CandidType, Deserialize, Clone)]
-pub struct Track {
-    pub id: u64,
-    pub name: String,
-    pub ipfs_hash: String,
-    pub uploaded_by: String,
-    pub timestamp: u64,
+// Sets the price and lists the NFT in one step, so it is never listed at a stale price.
+// Relisting at the current price skips the minimum increment check
+#[ic_cdk::update]
+fn relist_nft(nft_id: u64, new_price: u64) -> Result<String, MarketError> {
+    if new_price == 0 {
+        return Err(MarketError::InvalidPrice("Price must be greater than zero".to_string()));
+    }
+    let caller = caller_text();
+    let min_increment = MIN_PRICE_INCREMENT.with(|min| min.borrow().clone());
+
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&nft_id).ok_or(MarketError::NotFound)?;
+        if nft.current_owner != caller {
+            return Err(MarketError::Unauthorized("Only the NFT owner can relist it".to_string()));
+        }
+        if nft.is_hidden {
+            return Err(MarketError::InvalidInput("A hidden NFT can't be listed".to_string()));
+        }
+        check_resale_floor(nft, &caller, new_price)?;
+        if new_price != nft.price {
+            check_price_increment(nft.price, new_price, &min_increment)?;
+        }
+
+        nft.price = new_price;
+        set_for_sale(nft, true);
+        Ok(format!("NFT {} listed for sale at {}", nft_id, new_price))
+    })
 }
 
-#[derive(CandidType, Deserialize, Clone)]
-pub struct NFTMetadata {
-    pub id: u64,
-    pub name: String,
-    pub description: String,
-    pub image_url: String,
-    pub creator: String,
-    pub project_id: u64,
-    pub price: u64,
+// The one check on how a sale is divided: the royalty is capped, and the royalty plus
+// the platform fee must still leave the seller something
+fn validate_payout_config(royalty_percentage: u8, platform_fee: u8) -> Result<(), String> {
+    if royalty_percentage > MAX_ROYALTY_PERCENTAGE {
+        return Err(format!(
+            "Royalty of {}% exceeds the maximum of {}%",
+            royalty_percentage, MAX_ROYALTY_PERCENTAGE
+        ));
+    }
+    if royalty_percentage as u16 + platform_fee as u16 >= 100 {
+        return Err(format!(
+            "A {}% royalty plus the {}% platform fee would leave the seller nothing",
+            royalty_percentage, platform_fee
+        ));
+    }
+    Ok(())
 }
 
-#[derive(CandidType, Deserialize, Clone)]
-pub struct PinataUploadRequest {
-    pub file_data: Vec<u8>,
-    pub file_name: String,
-    pub content_type: String,
-    pub api_key: String,
-    pub secret_key: String,
+// Applies to mints that don't name a royalty; existing NFTs keep theirs
+#[ic_cdk::update]
+fn set_default_royalty(pct: u8) -> Result<String, String> {
+    require_admin()?;
+    validate_payout_config(pct, get_platform_fee().percentage)?;
+    DEFAULT_ROYALTY.with(|royalty| *royalty.borrow_mut() = pct);
+    Ok(format!("Default royalty set to {}%", pct))
 }
 
-#[derive(CandidType, Deserialize, Clone)]
-pub struct PinataUploadResponse {
-    pub success: bool,
-    pub ipfs_hash: String,
-    pub pin_size: u64,
-    pub error: Option<String>,
+#[ic_cdk::query]
+fn get_default_royalty() -> u8 {
+    DEFAULT_ROYALTY.with(|royalty| *royalty.borrow())
 }
 
-thread_local! {
-    static PROJECTS: std::cell::RefCell<HashMap<u64, MusicProject>> = std::cell::RefCell::new(HashMap::new());
-    static NFTS: std::cell::RefCell<HashMap<u64, NFTMetadata>> = std::cell::RefCell::new(HashMap::new());
-    static NEXT_ID: std::cell::RefCell<u64> = std::cell::RefCell::new(1);
-    static NEXT_NFT_ID: std::cell::RefCell<u64> = std::cell::RefCell::new(1);
+// Checked against the largest allowed royalty, since existing NFTs may carry it
+#[ic_cdk::update]
+fn set_platform_fee(percentage: u8, recipient: Option<String>) -> Result<String, String> {
+    require_admin()?;
+    validate_payout_config(MAX_ROYALTY_PERCENTAGE, percentage).map_err(|err| {
+        format!("{}; the platform fee must be below {}%", err, 100 - MAX_ROYALTY_PERCENTAGE)
+    })?;
+    let recipient = recipient
+        .map(|recipient| normalize_principal("Fee recipient", &recipient))
+        .transpose()?;
+    if percentage > 0 && recipient.is_none() {
+        return Err("A fee recipient is required for a non-zero fee".to_string());
+    }
+    PLATFORM_FEE.with(|fee| *fee.borrow_mut() = PlatformFee { percentage, recipient });
+    Ok(format!("Platform fee set to {}%", percentage))
 }
 
+#[ic_cdk::query]
+fn get_platform_fee() -> PlatformFee {
+    PLATFORM_FEE.with(|fee| fee.borrow().clone())
+}
+
+// 0 (the default) makes minting free
 #[ic_cdk::update]
-fn create_project(title: String, description: String, owner: String) -> u64 {
-    let id = NEXT_ID.with(|id| {
-        let mut id = id.borrow_mut();
-        let current = *id;
-        *id += 1;
-        current
-    });
-    let project = MusicProject {
-        id,
-        title,
-        description,
-        owner,
-        contributors: vec![],
-        tracks: vec![],
-    };
-    PROJECTS.with(|projects| {
-        projects.borrow_mut().insert(id, project);
-    });
-    id
+fn set_mint_fee(amount: u64, recipient: Option<String>) -> Result<String, String> {
+    require_admin()?;
+    let recipient = recipient
+        .map(|recipient| normalize_principal("Fee recipient", &recipient))
+        .transpose()?;
+    if amount > 0 && recipient.is_none() {
+        return Err("A fee recipient is required for a non-zero fee".to_string());
+    }
+    if amount > 0 && LEDGER.with(|ledger| ledger.borrow().is_none()) {
+        return Err("A ledger canister must be configured before charging a mint fee".to_string());
+    }
+    MINT_FEE.with(|fee| *fee.borrow_mut() = MintFee { amount, recipient });
+    Ok(format!("Mint fee set to {}", amount))
+}
+
+#[ic_cdk::query]
+fn get_mint_fee() -> MintFee {
+    MINT_FEE.with(|fee| fee.borrow().clone())
+}
+
+#[ic_cdk::query]
+fn get_fee_payments() -> Result<Vec<FeePayment>, String> {
+    require_admin()?;
+    Ok(FEE_PAYMENTS.with(|payments| payments.borrow().clone()))
 }
 
 #[ic_cdk::update]
-fn add_track(
-    project_id: u64, 
-    name: String, 
-    ipfs_hash: String, 
-    uploaded_by: String, 
-    timestamp: u64
-) -> bool {
-    PROJECTS.with(|projects| {
-        let mut projects = projects.borrow_mut();
-        if let Some(project) = projects.get_mut(&project_id) {
-            // Generate unique track ID using timestamp and random component
-            let track_id = timestamp;
-            
-            let track = Track {
-                id: track_id,
-                name,
-                ipfs_hash,
-                uploaded_by,
-                timestamp,
-            };
-            project.tracks.push(track);
-            true
-        } else {
-            false
+fn set_min_price_increment(increment: Option<MinPriceIncrement>) -> Result<String, String> {
+    require_admin()?;
+    if let Some(MinPriceIncrement::Percentage(pct)) = increment {
+        if pct > 100 {
+            return Err("Percentage increment cannot exceed 100".to_string());
         }
-    })
+    }
+    MIN_PRICE_INCREMENT.with(|min| *min.borrow_mut() = increment);
+    Ok("Minimum price increment updated".to_string())
 }
 
 #[ic_cdk::query]
-fn get_project(project_id: u64) -> Option<MusicProject> {
-    PROJECTS.with(|projects| projects.borrow().get(&project_id).cloned())
+fn get_min_price_increment() -> Option<MinPriceIncrement> {
+    MIN_PRICE_INCREMENT.with(|min| min.borrow().clone())
 }
 
-#[ic_cdk::query]
-fn list_projects() -> Vec<MusicProject> {
-    PROJECTS.with(|projects| {
-        projects.borrow().values().cloned().collect()
+#[ic_cdk::update]
+fn feature_nft(nft_id: u64) -> Result<String, String> {
+    require_admin()?;
+    if !NFTS.with(|nfts| nfts.borrow().contains_key(&nft_id)) {
+        return Err("NFT not found".to_string());
+    }
+
+    FEATURED.with(|featured| {
+        let mut featured = featured.borrow_mut();
+        if featured.contains(&nft_id) {
+            return Ok(format!("NFT {} is already featured", nft_id));
+        }
+        if featured.len() >= MAX_FEATURED_NFTS {
+            return Err(format!("Featured list is full ({} NFTs)", MAX_FEATURED_NFTS));
+        }
+        featured.push(nft_id);
+        Ok(format!("NFT {} featured", nft_id))
     })
 }
 
 #[ic_cdk::update]
-fn add_contributor(project_id: u64, contributor: String) -> bool {
-    PROJECTS.with(|projects| {
-        let mut projects = projects.borrow_mut();
-        if let Some(project) = projects.get_mut(&project_id) {
-            if !project.contributors.contains(&contributor) {
-                project.contributors.push(contributor);
-            }
-            true
-        } else {
-            false
+fn unfeature_nft(nft_id: u64) -> Result<String, String> {
+    require_admin()?;
+    FEATURED.with(|featured| {
+        let mut featured = featured.borrow_mut();
+        if !featured.contains(&nft_id) {
+            return Err("NFT is not featured".to_string());
         }
+        featured.retain(|id| *id != nft_id);
+        Ok(format!("NFT {} unfeatured", nft_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_featured_nfts() -> Vec<NFTMetadata> {
+    let featured = FEATURED.with(|featured| featured.borrow().clone());
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        // Keep the curated order and skip NFTs that no longer exist
+        within_size_budget(featured.iter().filter_map(|id| nfts.get(id)).filter(|nft| !nft.is_hidden), usize::MAX).0
     })
 }
 
 #[ic_cdk::update]
-fn remove_track(project_id: u64, track_id: u64) -> bool {
-    PROJECTS.with(|projects| {
-        let mut projects = projects.borrow_mut();
-        if let Some(project) = projects.get_mut(&project_id) {
-            project.tracks.retain(|track| track.id != track_id);
-            true
-        } else {
-            false
+fn add_to_wishlist(nft_id: u64) -> Result<String, String> {
+    if !NFTS.with(|nfts| nfts.borrow().contains_key(&nft_id)) {
+        return Err("NFT not found".to_string());
+    }
+    WISHLISTS.with(|wishlists| {
+        let mut wishlists = wishlists.borrow_mut();
+        let wishlist = wishlists.entry(caller_text()).or_default();
+        if !wishlist.contains(&nft_id) {
+            wishlist.push(nft_id);
         }
+    });
+    Ok(format!("NFT {} added to wishlist", nft_id))
+}
+
+#[ic_cdk::update]
+fn remove_from_wishlist(nft_id: u64) -> Result<String, String> {
+    WISHLISTS.with(|wishlists| {
+        let mut wishlists = wishlists.borrow_mut();
+        let wishlist = wishlists.get_mut(&caller_text()).filter(|wishlist| wishlist.contains(&nft_id))
+            .ok_or("NFT is not in your wishlist")?;
+        wishlist.retain(|id| *id != nft_id);
+        Ok(format!("NFT {} removed from wishlist", nft_id))
     })
 }
 
+// The caller's wishlist resolved to current metadata; NFTs that no longer exist or are
+// hidden are skipped
 #[ic_cdk::query]
-fn get_project_tracks(project_id: u64) -> Vec<Track> {
-    PROJECTS.with(|projects| {
-        projects.borrow()
-            .get(&project_id)
-            .map(|project| project.tracks.clone())
-            .unwrap_or_default()
+fn get_wishlist() -> Vec<NFTMetadata> {
+    let ids = WISHLISTS.with(|wishlists| wishlists.borrow().get(&caller_text()).cloned().unwrap_or_default());
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        within_size_budget(ids.iter().filter_map(|id| nfts.get(id).filter(|nft| !nft.is_hidden)), usize::MAX).0
     })
 }
 
+// Composite engagement: views plus weighted wishlist saves and sales. There are no
+// comments to count, so they don't contribute
+#[ic_cdk::query]
+fn get_nft_engagement(nft_id: u64) -> Option<EngagementScore> {
+    let views = get_view_count(nft_id)?;
+    let favorites = WISHLISTS.with(|wishlists| {
+        wishlists.borrow().values().filter(|wishlist| wishlist.contains(&nft_id)).count() as u64
+    });
+    let sales = get_full_nft_history(nft_id).iter().filter(|tx| tx.transaction_type == "sale").count() as u64;
+    let score = views
+        .saturating_mul(ENGAGEMENT_VIEW_WEIGHT)
+        .saturating_add(favorites.saturating_mul(ENGAGEMENT_FAVORITE_WEIGHT))
+        .saturating_add(sales.saturating_mul(ENGAGEMENT_SALE_WEIGHT));
+    Some(EngagementScore { views, favorites, sales, score })
+}
+
 #[ic_cdk::update]
-fn mint_nft(name: String, description: String, image_url: String, creator: String, project_id: u64, price: u64) -> u64 {
-    let id = NEXT_NFT_ID.with(|id| {
+fn create_collection(name: String, description: String) -> u64 {
+    let id = NEXT_COLLECTION_ID.with(|id| {
         let mut id = id.borrow_mut();
         let current = *id;
         *id += 1;
         current
     });
-    
-    let nft = NFTMetadata {
+
+    let collection = Collection {
         id,
         name,
         description,
-        image_url,
-        creator,
-        project_id,
-        price,
+        creator: caller_text(),
+        nft_ids: vec![],
     };
-    
-    NFTS.with(|nfts| {
-        nfts.borrow_mut().insert(id, nft);
+
+    COLLECTIONS.with(|collections| {
+        collections.borrow_mut().insert(id, collection);
     });
-    
+
     id
 }
 
+#[ic_cdk::update]
+fn add_nft_to_collection(collection_id: u64, nft_id: u64) -> Result<String, String> {
+    let caller = caller_text();
+
+    let nft_owner = NFTS.with(|nfts| nfts.borrow().get(&nft_id).map(|nft| nft.current_owner.clone()))
+        .ok_or("NFT not found")?;
+    if nft_owner != caller {
+        return Err("Only the NFT owner can add it to a collection".to_string());
+    }
+
+    COLLECTIONS.with(|collections| {
+        let mut collections = collections.borrow_mut();
+        let collection = collections.get_mut(&collection_id).ok_or("Collection not found")?;
+        if collection.creator != caller {
+            return Err("Only the collection creator can modify it".to_string());
+        }
+        // Adding an NFT that is already present is a no-op
+        if !collection.nft_ids.contains(&nft_id) {
+            collection.nft_ids.push(nft_id);
+        }
+        Ok(format!("NFT {} added to collection {}", nft_id, collection_id))
+    })
+}
+
+#[ic_cdk::update]
+fn remove_nft_from_collection(collection_id: u64, nft_id: u64) -> Result<String, String> {
+    let caller = caller_text();
+
+    COLLECTIONS.with(|collections| {
+        let mut collections = collections.borrow_mut();
+        let collection = collections.get_mut(&collection_id).ok_or("Collection not found")?;
+        if collection.creator != caller {
+            return Err("Only the collection creator can modify it".to_string());
+        }
+        if !collection.nft_ids.contains(&nft_id) {
+            return Err("NFT is not in this collection".to_string());
+        }
+        collection.nft_ids.retain(|id| *id != nft_id);
+        Ok(format!("NFT {} removed from collection {}", nft_id, collection_id))
+    })
+}
+
 #[ic_cdk::query]
-fn list_nfts() -> Vec<NFTMetadata> {
-    NFTS.with(|nfts| {
-        nfts.borrow().values().cloned().collect()
+fn get_collection(collection_id: u64) -> Option<Collection> {
+    COLLECTIONS.with(|collections| collections.borrow().get(&collection_id).cloned())
+}
+
+// Fixed-window limiter: each caller gets max_uploads per window_secs
+fn check_upload_rate_limit(caller: &str, now: u64) -> Result<(), String> {
+    let limit = UPLOAD_RATE_LIMIT.with(|limit| limit.borrow().clone());
+    let window_ns = limit.window_secs.saturating_mul(1_000_000_000);
+
+    UPLOAD_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        counts.retain(|_, (start, _)| now.saturating_sub(*start) < window_ns);
+
+        let (_, count) = counts.entry(caller.to_string()).or_insert((now, 0));
+        if *count >= limit.max_uploads {
+            return Err(format!(
+                "Upload rate limit exceeded: at most {} uploads per {} seconds",
+                limit.max_uploads, limit.window_secs
+            ));
+        }
+        *count += 1;
+        Ok(())
     })
 }
 
+#[ic_cdk::update]
+fn set_upload_rate_limit(max_uploads: u32, window_secs: u64) -> Result<String, String> {
+    require_admin()?;
+    if window_secs == 0 {
+        return Err("Rate limit window must be at least one second".to_string());
+    }
+    UPLOAD_RATE_LIMIT.with(|limit| *limit.borrow_mut() = UploadRateLimit { max_uploads, window_secs });
+    Ok(format!("Upload rate limit set to {} per {} seconds", max_uploads, window_secs))
+}
+
 #[ic_cdk::query]
-fn get_nft(nft_id: u64) -> Option<NFTMetadata> {
-    NFTS.with(|nfts| nfts.borrow().get(&nft_id).cloned())
+fn get_upload_rate_limit() -> UploadRateLimit {
+    UPLOAD_RATE_LIMIT.with(|limit| limit.borrow().clone())
+}
+
+fn upload_failure(error: String) -> PinataUploadResponse {
+    PinataUploadResponse {
+        success: false,
+        ipfs_hash: String::new(),
+        pin_size: 0,
+        error: Some(error),
+    }
+}
+
+// Stores the Pinata keys used by upload_to_pinata_stored; they are never returned by any method
+#[ic_cdk::update]
+fn set_pinata_credentials(api_key: String, secret_key: String) -> Result<String, String> {
+    require_admin()?;
+    if api_key.trim().is_empty() || secret_key.trim().is_empty() {
+        return Err("Pinata API key and secret key are required".to_string());
+    }
+    PINATA_CREDENTIALS.with(|credentials| {
+        *credentials.borrow_mut() = Some(PinataCredentials {
+            api_key: api_key.trim().to_string(),
+            secret_key: secret_key.trim().to_string(),
+        });
+    });
+    Ok("Pinata credentials stored".to_string())
+}
+
+#[ic_cdk::query]
+fn has_pinata_credentials() -> bool {
+    PINATA_CREDENTIALS.with(|credentials| credentials.borrow().is_some())
+}
+
+fn queue_unpin(ipfs_hash: String) {
+    PENDING_UNPINS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if !pending.contains(&ipfs_hash) {
+            pending.push(ipfs_hash);
+        }
+    });
+}
+
+// HTTP outcalls only support GET, HEAD and POST, so the canister can't issue Pinata's
+// DELETE /pinning/unpin/{hash} itself. Hashes are queued here and an off-chain worker
+// holding the Pinata keys drains the queue and calls confirm_unpinned.
+#[ic_cdk::update]
+fn unpin_from_pinata(ipfs_hash: String) -> Result<String, String> {
+    require_admin()?;
+    let ipfs_hash = ipfs_hash.trim().to_string();
+    if ipfs_hash.is_empty() {
+        return Err("IPFS hash cannot be empty".to_string());
+    }
+    queue_unpin(ipfs_hash.clone());
+    Ok(format!("{} queued for unpinning", ipfs_hash))
+}
+
+#[ic_cdk::query]
+fn get_pending_unpins() -> Result<Vec<String>, String> {
+    require_admin()?;
+    Ok(PENDING_UNPINS.with(|pending| pending.borrow().clone()))
+}
+
+#[ic_cdk::update]
+fn confirm_unpinned(ipfs_hashes: Vec<String>) -> Result<String, String> {
+    require_admin()?;
+    let removed = PENDING_UNPINS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let before = pending.len();
+        pending.retain(|hash| !ipfs_hashes.contains(hash));
+        before - pending.len()
+    });
+    Ok(format!("{} hashes removed from the unpin queue", removed))
 }
 
 #[ic_cdk::update]
 async fn upload_to_pinata(request: PinataUploadRequest) -> PinataUploadResponse {
+    if let Err(error) = check_upload_rate_limit(&caller_text(), time()) {
+        return upload_failure(error);
+    }
+    pin_file_to_ipfs(request).await
+}
+
+#[ic_cdk::update]
+async fn upload_to_pinata_stored(file_data: Vec<u8>, file_name: String, content_type: String) -> PinataUploadResponse {
+    if let Err(error) = check_upload_rate_limit(&caller_text(), time()) {
+        return upload_failure(error);
+    }
+    let Some(credentials) = PINATA_CREDENTIALS.with(|credentials| credentials.borrow().clone()) else {
+        return upload_failure("Pinata credentials have not been configured".to_string());
+    };
+    pin_file_to_ipfs(PinataUploadRequest {
+        file_data,
+        file_name,
+        content_type,
+        api_key: credentials.api_key,
+        secret_key: credentials.secret_key,
+    })
+    .await
+}
+
+async fn pin_file_to_ipfs(request: PinataUploadRequest) -> PinataUploadResponse {
     // Create multipart/form-data body
     let boundary = "----WebKitFormBoundary7MA4YWxkTrZu0gW";
     let mut body = Vec::new();
@@ -218,7 +3703,7 @@ async fn upload_to_pinata(request: PinataUploadRequest) -> PinataUploadResponse
     let metadata = format!(
         r#"{{"name":"{}","keyvalues":{{"type":"audio","uploadedVia":"IC-Backend","timestamp":"{}"}}}}"#,
         request.file_name,
-        ic_cdk::api::time()
+        time()
     );
     body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
     body.extend_from_slice(b"Content-Disposition: form-data; name=\"pinataMetadata\"\r\n\r\n");
@@ -252,9 +3737,9 @@ async fn upload_to_pinata(request: PinataUploadRequest) -> PinataUploadResponse
         headers,
     };
     
-    match http_request(request_args, 2_000_000_000).await {
+    match http_request(request_args, HTTP_OUTCALL_CYCLES).await {
         Ok((response,)) => {
-            if response.status == candid::Nat::from(200u8) {
+            if response.status == 200u8 {
                 // Parse JSON response
                 if let Ok(response_text) = String::from_utf8(response.body) {
                     // Simple JSON parsing for IPFS hash
@@ -311,8 +3796,212 @@ async fn upload_to_pinata(request: PinataUploadRequest) -> PinataUploadResponse
     }
 }
 
-// Transform function for HTTP outcalls (required by IC)
+// TransformContext::from_name, but taking the canister id from the swappable system API
+fn transform_context(method: &str) -> TransformContext {
+    TransformContext {
+        function: TransformFunc(candid::Func { principal: canister_id(), method: method.to_string() }),
+        context: vec![],
+    }
+}
+
+// Minimal JSON field lookups for the fixed shapes of Pinata's responses
+fn json_string_field(json: &str, key: &str) -> Option<String> {
+    let pattern = format!("\"{}\":\"", key);
+    let start = json.find(&pattern)? + pattern.len();
+    let end = json[start..].find('"')?;
+    Some(json[start..start + end].to_string())
+}
+
+fn json_number_field(json: &str, key: &str) -> Option<u64> {
+    let pattern = format!("\"{}\":", key);
+    let start = json.find(&pattern)? + pattern.len();
+    let digits: String = json[start..].trim_start().chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+// An HTTP outcall, so this has to be an update call even though it only reads. Each
+// check spends cycles like an upload, so it counts against the same rate limit
+#[ic_cdk::update]
+async fn check_pin_status(ipfs_hash: String) -> Result<PinStatus, String> {
+    let ipfs_hash = ipfs_hash.trim().to_string();
+    validate_ipfs_hash(&ipfs_hash)?;
+    let credentials = PINATA_CREDENTIALS.with(|credentials| credentials.borrow().clone())
+        .ok_or("Pinata credentials have not been configured")?;
+    check_upload_rate_limit(&caller_text(), time())?;
+
+    let request_args = CanisterHttpRequestArgument {
+        url: format!("https://api.pinata.cloud/data/pinList?status=pinned&hashContains={}", ipfs_hash),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(4096),
+        transform: Some(transform_context("transform_response")),
+        headers: vec![
+            HttpHeader {
+                name: "pinata_api_key".to_string(),
+                value: credentials.api_key,
+            },
+            HttpHeader {
+                name: "pinata_secret_api_key".to_string(),
+                value: credentials.secret_key,
+            },
+        ],
+    };
+
+    let (response,) = http_request(request_args, HTTP_OUTCALL_CYCLES)
+        .await
+        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+    if response.status != 200u8 {
+        return Err(format!("Pinata API error: {}", response.status));
+    }
+    let body = String::from_utf8(response.body).map_err(|_| "Failed to parse Pinata response".to_string())?;
+
+    // Only an exact hash match counts; hashContains is a substring filter
+    let found = json_number_field(&body, "count").unwrap_or(0) > 0
+        && json_string_field(&body, "ipfs_pin_hash").as_deref() == Some(ipfs_hash.as_str());
+    if !found {
+        return Ok(PinStatus {
+            pinned: false,
+            size: 0,
+            pin_date: None,
+        });
+    }
+    Ok(PinStatus {
+        pinned: true,
+        size: json_number_field(&body, "size").unwrap_or(0),
+        pin_date: json_string_field(&body, "date_pinned"),
+    })
+}
+
+// Checks the stored keys against Pinata's testAuthentication endpoint. Admin-only,
+// since each call is a paid HTTP outcall
+#[ic_cdk::update]
+async fn test_pinata_credentials() -> Result<String, String> {
+    require_admin()?;
+    let credentials = PINATA_CREDENTIALS.with(|credentials| credentials.borrow().clone())
+        .ok_or("Pinata credentials have not been configured")?;
+
+    let request_args = CanisterHttpRequestArgument {
+        url: "https://api.pinata.cloud/data/testAuthentication".to_string(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(2048),
+        transform: Some(transform_context("transform_response")),
+        headers: vec![
+            HttpHeader {
+                name: "pinata_api_key".to_string(),
+                value: credentials.api_key,
+            },
+            HttpHeader {
+                name: "pinata_secret_api_key".to_string(),
+                value: credentials.secret_key,
+            },
+        ],
+    };
+
+    let (response,) = http_request(request_args, HTTP_OUTCALL_CYCLES)
+        .await
+        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+    let body = String::from_utf8(response.body).unwrap_or_default();
+    if response.status == 200u8 {
+        return Ok(json_string_field(&body, "message").unwrap_or_else(|| "Pinata credentials are valid".to_string()));
+    }
+    // Failures look like {"error":{"reason":"INVALID_API_KEYS","details":"..."}}
+    let reason = json_string_field(&body, "details").or_else(|| json_string_field(&body, "reason"));
+    Err(match reason {
+        Some(reason) => format!("Pinata API error {}: {}", response.status, reason),
+        None => format!("Pinata API error: {}", response.status),
+    })
+}
+
+// Base URL that a CID is appended to, so it must end with a slash
+#[ic_cdk::update]
+fn set_ipfs_gateway(url: String) -> Result<String, String> {
+    require_admin()?;
+    let url = url.trim().to_string();
+    let rest = url.strip_prefix("https://").ok_or("Gateway URL must start with https://")?;
+    if rest.len() <= 1 || rest.chars().any(char::is_whitespace) {
+        return Err("Gateway URL is malformed".to_string());
+    }
+    if !url.ends_with('/') {
+        return Err("Gateway URL must end with a slash".to_string());
+    }
+    if url.len() > MAX_URL_LEN {
+        return Err(format!("Gateway URL cannot be longer than {} characters", MAX_URL_LEN));
+    }
+    IPFS_GATEWAY.with(|gateway| *gateway.borrow_mut() = url.clone());
+    Ok(format!("IPFS gateway set to {}", url))
+}
+
+#[ic_cdk::query]
+fn get_ipfs_gateway() -> String {
+    IPFS_GATEWAY.with(|gateway| gateway.borrow().clone())
+}
+
+// Size and type of a file as reported by the IPFS gateway, from a HEAD request. Each
+// lookup spends cycles like an upload, so it counts against the same rate limit
+#[ic_cdk::update]
+async fn get_ipfs_metadata(ipfs_hash: String) -> Result<IpfsMeta, String> {
+    let ipfs_hash = ipfs_hash.trim().to_string();
+    validate_ipfs_hash(&ipfs_hash)?;
+    check_upload_rate_limit(&caller_text(), time())?;
+
+    let request_args = CanisterHttpRequestArgument {
+        url: format!("{}{}", get_ipfs_gateway(), ipfs_hash),
+        method: HttpMethod::HEAD,
+        body: None,
+        max_response_bytes: Some(4096),
+        transform: Some(transform_context("transform_ipfs_headers")),
+        headers: vec![],
+    };
+
+    let (response,) = http_request(request_args, HTTP_OUTCALL_CYCLES)
+        .await
+        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+    if response.status != 200u8 {
+        return Err(format!("IPFS gateway error: {}", response.status));
+    }
+    Ok(ipfs_meta_from_headers(&response.headers))
+}
+
+fn ipfs_meta_from_headers(headers: &[HttpHeader]) -> IpfsMeta {
+    let header = |name: &str| {
+        headers.iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.trim().to_string())
+    };
+    IpfsMeta {
+        content_type: header("content-type"),
+        content_length: header("content-length").and_then(|length| length.parse().ok()),
+    }
+}
+
+// Keeps only the headers get_ipfs_metadata reads, which are the same on every replica
+#[ic_cdk::query]
+fn transform_ipfs_headers(args: TransformArgs) -> HttpResponse {
+    let headers = args.response.headers
+        .into_iter()
+        .filter(|header| {
+            header.name.eq_ignore_ascii_case("content-type") || header.name.eq_ignore_ascii_case("content-length")
+        })
+        .map(|header| HttpHeader { name: header.name.to_lowercase(), value: header.value })
+        .collect();
+    HttpResponse {
+        status: args.response.status,
+        headers,
+        body: vec![],
+    }
+}
+
+// Transform function for HTTP outcalls (required by IC). Headers carry per-replica
+// values such as dates, so they are dropped to let replicas reach consensus.
 #[ic_cdk::query]
 fn transform_response(args: TransformArgs) -> HttpResponse {
-    args.response
-}
\ No newline at end of file
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,3625 @@
+use super::*;
+
+// Stand-ins for the IC system API. Every #[test] runs on its own thread, so each one
+// starts from fresh thread-local state, both here and in the canister.
+pub(crate) mod mock {
+    use crate::Account;
+    use candid::{Nat, Principal};
+    use ic_cdk::api::call::{CallResult, RejectionCode};
+    use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpResponse};
+    use std::cell::RefCell;
+
+    pub const START_TIME: u64 = 1_700_000_000_000_000_000;
+    pub const CYCLES_BALANCE: u128 = 4_000_000_000_000;
+    pub const STABLE_PAGES: u64 = 3;
+
+    thread_local! {
+        static CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+        static TIME: RefCell<u64> = const { RefCell::new(START_TIME) };
+        static LEDGER_FEE: RefCell<u64> = const { RefCell::new(10) };
+        static TRANSFERS: RefCell<Vec<Transfer>> = const { RefCell::new(Vec::new()) };
+        static REFUSE_TRANSFER: RefCell<Option<TransferCheck>> = const { RefCell::new(None) };
+        static ON_TRANSFER: RefCell<Option<TransferHook>> = const { RefCell::new(None) };
+        static HTTP_REQUESTS: RefCell<Vec<CanisterHttpRequestArgument>> = const { RefCell::new(Vec::new()) };
+        static HTTP_RESPONDER: RefCell<Option<HttpResponder>> = const { RefCell::new(None) };
+    }
+
+    // A transfer the mock ledger accepted
+    pub struct Transfer {
+        pub from_subaccount: [u8; 32],
+        pub to: Account,
+        pub amount: u64,
+    }
+
+    type TransferCheck = Box<dyn Fn(&Transfer) -> bool>;
+    type TransferHook = Box<dyn FnMut(&Transfer)>;
+    type HttpResponder = Box<dyn Fn(&CanisterHttpRequestArgument) -> HttpResponse>;
+
+    pub fn caller() -> Principal {
+        CALLER.with(|caller| *caller.borrow())
+    }
+
+    pub fn canister_id() -> Principal {
+        Principal::from_slice(&[0xCA; 10])
+    }
+
+    pub fn time() -> u64 {
+        TIME.with(|time| *time.borrow())
+    }
+
+    pub fn canister_balance128() -> u128 {
+        CYCLES_BALANCE
+    }
+
+    pub fn stable_size() -> u64 {
+        STABLE_PAGES
+    }
+
+    pub fn set_caller(principal: Principal) {
+        CALLER.with(|caller| *caller.borrow_mut() = principal);
+    }
+
+    pub fn advance_time(ns: u64) {
+        TIME.with(|time| *time.borrow_mut() += ns);
+    }
+
+    pub async fn icrc1_fee(_ledger: Principal) -> Result<u64, String> {
+        Ok(ledger_fee())
+    }
+
+    pub async fn icrc1_transfer(_ledger: Principal, from_subaccount: [u8; 32], to: Account, amount: u64) -> Result<Nat, String> {
+        let transfer = Transfer { from_subaccount, to, amount };
+        // Runs while the caller is suspended at the await, like another message would.
+        // The hook is taken out while it runs so it may call back into the canister
+        if let Some(mut hook) = ON_TRANSFER.with(|hook| hook.borrow_mut().take()) {
+            hook(&transfer);
+            ON_TRANSFER.with(|slot| *slot.borrow_mut() = Some(hook));
+        }
+        if REFUSE_TRANSFER.with(|refuse| refuse.borrow().as_ref().is_some_and(|refuse| refuse(&transfer))) {
+            return Err("Ledger rejected transfer: TemporarilyUnavailable".to_string());
+        }
+        TRANSFERS.with(|transfers| {
+            let mut transfers = transfers.borrow_mut();
+            transfers.push(transfer);
+            Ok(Nat::from(transfers.len()))
+        })
+    }
+
+    pub fn ledger_fee() -> u64 {
+        LEDGER_FEE.with(|fee| *fee.borrow())
+    }
+
+    pub fn take_transfers() -> Vec<Transfer> {
+        TRANSFERS.with(|transfers| std::mem::take(&mut *transfers.borrow_mut()))
+    }
+
+    pub fn refuse_transfers(check: impl Fn(&Transfer) -> bool + 'static) {
+        REFUSE_TRANSFER.with(|refuse| *refuse.borrow_mut() = Some(Box::new(check)));
+    }
+
+    pub fn accept_transfers() {
+        REFUSE_TRANSFER.with(|refuse| *refuse.borrow_mut() = None);
+    }
+
+    pub fn on_transfer(hook: impl FnMut(&Transfer) + 'static) {
+        ON_TRANSFER.with(|slot| *slot.borrow_mut() = Some(Box::new(hook)));
+    }
+
+    // Without a responder every outcall is rejected, as if the remote host were down
+    pub async fn http_request(request: CanisterHttpRequestArgument, _cycles: u128) -> CallResult<(HttpResponse,)> {
+        let response = HTTP_RESPONDER.with(|responder| responder.borrow().as_ref().map(|respond| respond(&request)));
+        HTTP_REQUESTS.with(|requests| requests.borrow_mut().push(request));
+        response.map(|response| (response,)).ok_or((RejectionCode::SysTransient, "no responder".to_string()))
+    }
+
+    pub fn respond_with(respond: impl Fn(&CanisterHttpRequestArgument) -> HttpResponse + 'static) {
+        HTTP_RESPONDER.with(|responder| *responder.borrow_mut() = Some(Box::new(respond)));
+    }
+
+    pub fn take_http_requests() -> Vec<CanisterHttpRequestArgument> {
+        HTTP_REQUESTS.with(|requests| std::mem::take(&mut *requests.borrow_mut()))
+    }
+}
+
+// The mocks never suspend, so a future finishes on its first poll
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    match future.as_mut().poll(&mut context) {
+        std::task::Poll::Ready(output) => output,
+        std::task::Poll::Pending => panic!("future was not ready"),
+    }
+}
+
+const ADMIN_USER: u8 = 0xAD;
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const IMAGE: &str = "https://example.com/cover.png";
+
+fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 10])
+}
+
+fn user(n: u8) -> String {
+    principal(n).to_text()
+}
+
+fn call_as(n: u8) {
+    mock::set_caller(principal(n));
+}
+
+// Installs the canister as ADMIN_USER, who stays the caller
+fn install() {
+    call_as(ADMIN_USER);
+    init();
+}
+
+struct MintArgs {
+    name: String,
+    description: String,
+    project_id: u64,
+    price: u64,
+    category: String,
+    ipfs_hash: Option<String>,
+    royalty_percentage: Option<u8>,
+    min_resale_price: Option<u64>,
+    list_immediately: Option<bool>,
+}
+
+impl Default for MintArgs {
+    fn default() -> Self {
+        MintArgs {
+            name: "Song".to_string(),
+            description: String::new(),
+            project_id: 0,
+            price: 100,
+            category: "music".to_string(),
+            ipfs_hash: None,
+            royalty_percentage: None,
+            min_resale_price: None,
+            list_immediately: None,
+        }
+    }
+}
+
+fn mint_with(creator: u8, args: MintArgs) -> Result<u64, String> {
+    call_as(creator);
+    block_on(mint_nft(
+        args.name,
+        args.description,
+        IMAGE.to_string(),
+        user(creator),
+        args.project_id,
+        args.price,
+        args.category,
+        None,
+        None,
+        args.ipfs_hash,
+        args.royalty_percentage,
+        args.min_resale_price,
+        args.list_immediately,
+    ))
+}
+
+fn mint(creator: u8) -> u64 {
+    mint_with(creator, MintArgs::default()).unwrap()
+}
+
+fn buy(buyer: u8, nft_id: u64) -> Result<String, MarketError> {
+    call_as(buyer);
+    block_on(buy_nft(nft_id, None))
+}
+
+fn relist(owner: u8, nft_id: u64, price: u64) {
+    call_as(owner);
+    relist_nft(nft_id, price).unwrap();
+}
+
+// Collections
+
+#[test]
+fn create_collection_records_the_caller_as_creator() {
+    call_as(1);
+    let id = create_collection("Album".to_string(), "B-sides".to_string());
+    let collection = get_collection(id).unwrap();
+    assert_eq!(collection.name, "Album");
+    assert_eq!(collection.creator, user(1));
+    assert!(collection.nft_ids.is_empty());
+    assert!(get_collection(id + 1).is_none());
+}
+
+#[test]
+fn adding_an_nft_twice_keeps_one_entry() {
+    let nft_id = mint(1);
+    let id = create_collection("Album".to_string(), String::new());
+    add_nft_to_collection(id, nft_id).unwrap();
+    add_nft_to_collection(id, nft_id).unwrap();
+    assert_eq!(get_collection(id).unwrap().nft_ids, vec![nft_id]);
+}
+
+#[test]
+fn removing_an_nft_from_a_collection() {
+    let first = mint(1);
+    let second = mint(1);
+    let id = create_collection("Album".to_string(), String::new());
+    add_nft_to_collection(id, first).unwrap();
+    add_nft_to_collection(id, second).unwrap();
+    remove_nft_from_collection(id, first).unwrap();
+    assert_eq!(get_collection(id).unwrap().nft_ids, vec![second]);
+    assert!(remove_nft_from_collection(id, first).is_err());
+}
+
+#[test]
+fn collections_only_take_the_creators_own_nfts() {
+    let own_nft = mint(1);
+    let other_nft = mint(2);
+    call_as(1);
+    let id = create_collection("Album".to_string(), String::new());
+    assert!(add_nft_to_collection(id, other_nft).is_err());
+
+    // Someone else's collection can't be changed, even with an NFT they own
+    call_as(2);
+    assert!(add_nft_to_collection(id, other_nft).is_err());
+    call_as(1);
+    add_nft_to_collection(id, own_nft).unwrap();
+    call_as(2);
+    assert!(remove_nft_from_collection(id, own_nft).is_err());
+    assert_eq!(get_collection(id).unwrap().nft_ids, vec![own_nft]);
+}
+
+// A distinct, valid CIDv0 for each n
+fn cid(n: u8) -> String {
+    const BASE58: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    let suffix = [BASE58[n as usize / BASE58.len()], BASE58[n as usize % BASE58.len()]];
+    format!("Qm{}{}", "Yw".repeat(21), String::from_utf8_lossy(&suffix))
+}
+
+fn project_of(owner: u8) -> u64 {
+    call_as(owner);
+    create_project("Project".to_string(), String::new(), user(owner)).unwrap()
+}
+
+fn upload(uploader: u8, project_id: u64, track_id: u64, ipfs_hash: &str) -> Result<u64, String> {
+    call_as(uploader);
+    add_track(project_id, format!("Track {}", track_id), ipfs_hash.to_string(), track_id, None)
+}
+
+// Duplicate tracks
+
+#[test]
+fn tracks_sharing_a_hash_are_grouped() {
+    let project_id = project_of(1);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(1, project_id, 11, &cid(2)).unwrap();
+    upload(1, project_id, 12, &cid(1)).unwrap();
+    assert_eq!(find_duplicate_tracks(project_id), vec![vec![10, 12]]);
+    assert!(find_duplicate_tracks(project_id + 1).is_empty());
+}
+
+// Admin
+
+#[test]
+fn init_makes_the_installer_admin() {
+    install();
+    assert_eq!(get_admin(), user(ADMIN_USER));
+}
+
+#[test]
+fn only_the_current_admin_can_transfer_admin() {
+    install();
+    call_as(1);
+    assert!(transfer_admin(user(1)).is_err());
+    assert_eq!(get_admin(), user(ADMIN_USER));
+
+    call_as(ADMIN_USER);
+    transfer_admin(user(2)).unwrap();
+    assert_eq!(get_admin(), user(2));
+    assert!(transfer_admin(user(ADMIN_USER)).is_err());
+}
+
+#[test]
+fn guarded_methods_reject_non_admins() {
+    install();
+    let nft_id = mint(1);
+    call_as(1);
+    assert!(feature_nft(nft_id).is_err());
+    assert!(get_reports().is_err());
+    call_as(ADMIN_USER);
+    assert!(feature_nft(nft_id).is_ok());
+}
+
+// Minimum price increment
+
+#[test]
+fn price_changes_are_unrestricted_by_default() {
+    let nft_id = mint(1);
+    update_nft_price(nft_id, 101).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().price, 101);
+}
+
+#[test]
+fn sub_threshold_price_changes_are_rejected() {
+    install();
+    set_min_price_increment(Some(MinPriceIncrement::Absolute(10))).unwrap();
+    let nft_id = mint(1);
+    assert!(matches!(update_nft_price(nft_id, 105), Err(MarketError::InvalidPrice(_))));
+    assert!(matches!(update_nft_price(nft_id, 95), Err(MarketError::InvalidPrice(_))));
+    update_nft_price(nft_id, 110).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().price, 110);
+}
+
+#[test]
+fn percentage_increments_scale_with_the_old_price() {
+    let five_percent = Some(MinPriceIncrement::Percentage(5));
+    assert!(check_price_increment(1000, 1049, &five_percent).is_err());
+    assert!(check_price_increment(1000, 1050, &five_percent).is_ok());
+    assert!(check_price_increment(1000, 950, &five_percent).is_ok());
+    assert!(check_price_increment(1000, 1001, &None).is_ok());
+
+    install();
+    assert!(set_min_price_increment(Some(MinPriceIncrement::Percentage(101))).is_err());
+}
+
+// Featured NFTs
+
+#[test]
+fn featured_nfts_keep_the_curated_order() {
+    install();
+    let first = mint(1);
+    let second = mint(1);
+    let third = mint(1);
+    call_as(ADMIN_USER);
+    feature_nft(third).unwrap();
+    feature_nft(first).unwrap();
+    feature_nft(second).unwrap();
+    unfeature_nft(first).unwrap();
+    let ids: Vec<u64> = get_featured_nfts().iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![third, second]);
+    assert!(unfeature_nft(first).is_err());
+}
+
+#[test]
+fn featuring_is_admin_only_and_capped() {
+    install();
+    let ids: Vec<u64> = (0..=MAX_FEATURED_NFTS).map(|_| mint(1)).collect();
+    call_as(1);
+    assert!(feature_nft(ids[0]).is_err());
+    call_as(ADMIN_USER);
+    for id in &ids[..MAX_FEATURED_NFTS] {
+        feature_nft(*id).unwrap();
+    }
+    assert!(feature_nft(ids[MAX_FEATURED_NFTS]).is_err());
+    assert!(feature_nft(9999).is_err());
+}
+
+#[test]
+fn featured_nfts_skip_removed_and_hidden_ones() {
+    install();
+    let kept = mint(1);
+    let removed = mint(1);
+    let hidden = mint(1);
+    call_as(ADMIN_USER);
+    for id in [kept, removed, hidden] {
+        feature_nft(id).unwrap();
+    }
+    NFTS.with(|nfts| nfts.borrow_mut().remove(&removed));
+    NFTS.with(|nfts| nfts.borrow_mut().get_mut(&hidden).unwrap().is_hidden = true);
+    let ids: Vec<u64> = get_featured_nfts().iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![kept]);
+}
+
+// Creator volume
+
+#[test]
+fn creator_volume_sums_resales_only() {
+    let first = mint(1);
+    let second = mint(1);
+    buy(2, first).unwrap();
+    relist(2, first, 300);
+    buy(3, first).unwrap();
+    buy(4, second).unwrap();
+    relist(4, second, 500);
+    buy(5, second).unwrap();
+
+    // Both primary sales by the creator are left out
+    assert_eq!(get_creator_volume(user(1)), 800);
+    assert_eq!(get_creator_volume(user(2)), 0);
+    assert_eq!(get_creator_volume("not a principal".to_string()), 0);
+}
+
+// Batch views
+
+#[test]
+fn batch_views_count_each_nft_once_per_window() {
+    let first = mint(1);
+    let second = mint(1);
+    call_as(2);
+    assert_eq!(record_views(vec![first, second, first, 9999, second]), 2);
+    assert_eq!(record_views(vec![first]), 0);
+    assert_eq!(get_view_count(first), Some(1));
+
+    mock::advance_time(DEFAULT_VIEW_DEDUP_WINDOW_NS);
+    assert_eq!(record_views(vec![first, first]), 1);
+    assert_eq!(get_view_count(first), Some(2));
+    assert_eq!(get_view_count(second), Some(1));
+}
+
+#[test]
+fn batch_views_stop_at_the_query_limit() {
+    let ids: Vec<u64> = (0..=MAX_QUERY_LIMIT).map(|_| mint(1)).collect();
+    call_as(2);
+    assert_eq!(record_views(ids.clone()), MAX_QUERY_LIMIT);
+    assert_eq!(get_view_count(*ids.last().unwrap()), Some(0));
+}
+
+// Trending
+
+fn view_as(viewer: u8, nft_id: u64) {
+    call_as(viewer);
+    record_nft_view(nft_id).unwrap();
+}
+
+#[test]
+fn trending_ranks_recent_sales_above_views() {
+    let stale = mint(1);
+    buy(2, stale).unwrap();
+    mock::advance_time(8 * DAY);
+
+    let viewed = mint(1);
+    let sold = mint(1);
+    let quiet = mint(1);
+    for viewer in [10, 11, 12] {
+        view_as(viewer, viewed);
+    }
+    view_as(10, stale);
+    buy(3, sold).unwrap();
+
+    // sold: one sale (10), viewed: three views (3), stale: its sale aged out, one view (1)
+    let ids: Vec<u64> = get_trending_nfts(10).iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![sold, viewed, stale]);
+    assert!(!ids.contains(&quiet));
+    assert_eq!(get_trending_nfts(2).len(), 2);
+}
+
+#[test]
+fn views_older_than_the_window_stop_counting() {
+    let nft_id = mint(1);
+    view_as(10, nft_id);
+    mock::advance_time(TRENDING_WINDOW_NS + 1);
+    assert!(get_trending_nfts(10).is_empty());
+}
+
+// Project export
+
+#[test]
+fn project_export_bundles_tracks_and_nfts() {
+    let project_id = project_of(1);
+    let other_project = project_of(1);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(1, project_id, 11, &cid(2)).unwrap();
+    let in_project = mint_with(1, MintArgs { project_id, ..Default::default() }).unwrap();
+    mint_with(1, MintArgs { project_id: other_project, ..Default::default() }).unwrap();
+
+    call_as(1);
+    let export = export_project(project_id).unwrap();
+    assert_eq!(export.project.id, project_id);
+    let track_ids: Vec<u64> = export.tracks.iter().map(|track| track.id).collect();
+    assert_eq!(track_ids, vec![10, 11]);
+    let nft_ids: Vec<u64> = export.nfts.iter().map(|nft| nft.id).collect();
+    assert_eq!(nft_ids, vec![in_project]);
+
+    call_as(2);
+    assert!(export_project(project_id).is_none());
+}
+
+// Global transaction ledger
+
+#[test]
+fn global_transactions_page_and_filter_by_type() {
+    let first = mint(1);
+    let second = mint(1);
+    mint(1);
+    buy(2, first).unwrap();
+    call_as(1);
+    transfer_nft(second, user(3)).unwrap();
+
+    let page = get_all_transactions_paged(1, 2);
+    assert_eq!(page.total, 5);
+    let ids: Vec<u64> = page.transactions.iter().map(|tx| tx.id).collect();
+    assert_eq!(ids, vec![2, 3]);
+    assert!(get_all_transactions_paged(5, 10).transactions.is_empty());
+
+    assert_eq!(get_transactions_by_type("mint".to_string()).unwrap().len(), 3);
+    let sales = get_transactions_by_type("sale".to_string()).unwrap();
+    assert_eq!((sales.len(), sales[0].nft_id), (1, first));
+    let transfers = get_transactions_by_type("transfer".to_string()).unwrap();
+    assert_eq!((transfers.len(), transfers[0].nft_id), (1, second));
+    assert!(get_transactions_by_type("Sale".to_string()).is_err());
+}
+
+// Required project link
+
+#[test]
+fn project_link_is_only_required_when_enabled() {
+    let project_id = project_of(1);
+    mint_with(1, MintArgs { project_id: 0, ..Default::default() }).unwrap();
+    mint_with(1, MintArgs { project_id: 99, ..Default::default() }).unwrap();
+
+    install();
+    set_require_project_link(true).unwrap();
+    assert!(get_require_project_link());
+    assert!(mint_with(1, MintArgs { project_id: 0, ..Default::default() }).is_err());
+    assert!(mint_with(1, MintArgs { project_id: 99, ..Default::default() }).is_err());
+    mint_with(1, MintArgs { project_id, ..Default::default() }).unwrap();
+
+    call_as(1);
+    assert!(set_require_project_link(false).is_err());
+}
+
+// Price history
+
+#[test]
+fn price_history_lists_sales_in_order() {
+    let nft_id = mint(1);
+    let sold_at = |owner: u8, buyer: u8, price: u64| {
+        relist(owner, nft_id, price);
+        mock::advance_time(DAY);
+        buy(buyer, nft_id).unwrap();
+        time()
+    };
+    let first = sold_at(1, 2, 300);
+    call_as(2);
+    transfer_nft(nft_id, user(3)).unwrap();
+    let second = sold_at(3, 4, 150);
+    let third = sold_at(4, 5, 450);
+
+    assert_eq!(get_nft_price_history(nft_id), vec![(first, 300), (second, 150), (third, 450)]);
+    assert_eq!(get_nft_floor_and_ceiling(nft_id), Some((150, 450)));
+}
+
+#[test]
+fn an_unsold_nft_has_no_price_history() {
+    let nft_id = mint(1);
+    assert!(get_nft_price_history(nft_id).is_empty());
+    assert_eq!(get_nft_floor_and_ceiling(nft_id), None);
+}
+
+// Categories
+
+fn mint_in(creator: u8, category: &str) -> Result<u64, String> {
+    mint_with(creator, MintArgs { category: category.to_string(), ..Default::default() })
+}
+
+#[test]
+fn mints_must_use_a_registered_category() {
+    let nft_id = mint_in(1, "beat").unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().category, "beat");
+    assert!(mint_in(1, "polka").is_err());
+}
+
+#[test]
+fn categories_match_case_insensitively() {
+    let nft_id = mint_in(1, "  Music ").unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().category, "music");
+
+    install();
+    add_category("Lo-Fi".to_string()).unwrap();
+    assert!(add_category("LO-FI".to_string()).is_err());
+    assert!(list_categories().contains(&"lo-fi".to_string()));
+    mint_in(1, "lo-fi").unwrap();
+}
+
+#[test]
+fn the_category_registry_is_admin_managed() {
+    install();
+    call_as(1);
+    assert!(add_category("polka".to_string()).is_err());
+    assert!(remove_category("music".to_string()).is_err());
+
+    // Removing a category keeps existing NFTs in it but blocks new mints
+    let nft_id = mint_in(1, "remix").unwrap();
+    call_as(ADMIN_USER);
+    remove_category("REMIX".to_string()).unwrap();
+    assert!(mint_in(1, "remix").is_err());
+    assert_eq!(get_nft(nft_id).unwrap().category, "remix");
+    assert!(add_category(" ".to_string()).is_err());
+}
+
+// Category browsing
+
+#[test]
+fn category_counts_are_sorted_by_size() {
+    for category in ["beat", "music", "beat", "vocal", "music", "beat"] {
+        mint_in(1, category).unwrap();
+    }
+    let expected = [("beat", 3), ("music", 2), ("vocal", 1)];
+    let expected: Vec<(String, u64)> = expected.iter().map(|(name, count)| (name.to_string(), *count)).collect();
+    assert_eq!(get_category_counts(), expected);
+}
+
+#[test]
+fn category_listing_is_paged() {
+    let beats: Vec<u64> = (0..5).map(|_| mint_in(1, "beat").unwrap()).collect();
+    mint_in(1, "music").unwrap();
+    let page: Vec<u64> = get_nfts_by_category("Beat".to_string(), 1, 3).iter().map(|nft| nft.id).collect();
+    assert_eq!(page, beats[1..4]);
+    assert_eq!(get_nfts_by_category("beat".to_string(), 4, 3).len(), 1);
+    assert!(get_nfts_by_category("sample".to_string(), 0, 10).is_empty());
+}
+
+// Escrow-settled purchases
+
+const LEDGER_CANISTER: u8 = 0x1E;
+
+fn use_ledger() {
+    install();
+    set_ledger_canister(principal(LEDGER_CANISTER)).unwrap();
+}
+
+fn paid_to(transfer: &mock::Transfer, n: u8) -> bool {
+    transfer.to.owner == principal(n) && transfer.to.subaccount.is_none()
+}
+
+fn from_escrow_of(transfer: &mock::Transfer, n: u8) -> bool {
+    transfer.from_subaccount == escrow_subaccount(&principal(n))
+}
+
+fn into_settlement(transfer: &mock::Transfer) -> bool {
+    transfer.to.owner == canister_id() && transfer.to.subaccount == Some(SETTLEMENT_SUBACCOUNT.to_vec())
+}
+
+#[test]
+fn a_purchase_moves_funds_through_the_settlement_account() {
+    use_ledger();
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 2000);
+    mock::take_transfers();
+
+    buy(3, nft_id).unwrap();
+    let fee = mock::ledger_fee();
+    let transfers = mock::take_transfers();
+    assert_eq!(transfers.len(), 3);
+    assert!(from_escrow_of(&transfers[0], 3) && into_settlement(&transfers[0]));
+    assert_eq!(transfers[0].amount, 2000 + 2 * fee);
+    assert!(transfers[1..].iter().all(|transfer| transfer.from_subaccount == SETTLEMENT_SUBACCOUNT));
+    assert!(paid_to(&transfers[1], 2) && transfers[1].amount == 1800);
+    assert!(paid_to(&transfers[2], 1) && transfers[2].amount == 200);
+
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(3));
+    assert_eq!(get_nft_royalty_payments(nft_id)[0].status, "paid");
+}
+
+#[test]
+fn a_refused_payment_leaves_the_nft_with_the_seller() {
+    use_ledger();
+    let nft_id = mint(1);
+    mock::refuse_transfers(into_settlement);
+
+    assert!(matches!(buy(2, nft_id), Err(MarketError::PaymentFailed(_))));
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!(nft.current_owner, user(1));
+    assert!(nft.is_for_sale);
+    assert_eq!(get_transactions_by_type("sale".to_string()).unwrap().len(), 0);
+    assert!(mock::take_transfers().is_empty());
+
+    // The failed attempt released the NFT for the next one
+    mock::accept_transfers();
+    buy(2, nft_id).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(2));
+}
+
+#[test]
+fn refused_payouts_after_the_sale_are_kept_for_retry() {
+    use_ledger();
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 1000);
+    mock::refuse_transfers(|transfer| transfer.from_subaccount == SETTLEMENT_SUBACCOUNT);
+
+    buy(3, nft_id).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(3));
+    call_as(ADMIN_USER);
+    let pending = get_pending_payouts().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!((pending[0].to.owner, pending[0].amount), (principal(2), 900));
+    assert_eq!(get_nft_royalty_payments(nft_id)[0].status, "failed");
+
+    mock::accept_transfers();
+    call_as(2);
+    assert!(matches!(block_on(retry_pending_payouts()), Err(MarketError::Unauthorized(_))));
+    call_as(ADMIN_USER);
+    assert_eq!(block_on(retry_pending_payouts()).unwrap(), "1 of 1 payouts sent");
+    assert!(get_pending_payouts().unwrap().is_empty());
+}
+
+#[test]
+fn queued_payouts_survive_an_upgrade() {
+    use_ledger();
+    let nft_id = mint_with(1, MintArgs { price: 1000, ..Default::default() }).unwrap();
+    mock::refuse_transfers(|transfer| transfer.from_subaccount == SETTLEMENT_SUBACCOUNT);
+    buy(2, nft_id).unwrap();
+    let amounts = after_upgrade(|| {
+        call_as(ADMIN_USER);
+        get_pending_payouts().unwrap().into_iter().map(|payout| payout.amount).collect::<Vec<_>>()
+    });
+    assert_eq!(amounts, vec![1000]);
+}
+
+#[test]
+fn without_a_ledger_a_sale_is_bookkeeping_only() {
+    let nft_id = mint(1);
+    buy(2, nft_id).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(2));
+    assert!(mock::take_transfers().is_empty());
+
+    install();
+    call_as(1);
+    assert!(set_ledger_canister(principal(LEDGER_CANISTER)).is_err());
+}
+
+// Ownership history
+
+#[test]
+fn ownership_history_follows_mints_sales_and_transfers() {
+    let nft_id = mint(1);
+    let minted = time();
+    mock::advance_time(DAY);
+    buy(2, nft_id).unwrap();
+    let sold = time();
+    mock::advance_time(DAY);
+    call_as(2);
+    transfer_nft(nft_id, user(3)).unwrap();
+    let transferred = time();
+
+    assert_eq!(
+        get_nft_ownership_history(nft_id),
+        vec![(user(1), minted), (user(2), sold), (user(3), transferred)]
+    );
+    assert!(get_nft_ownership_history(9999).is_empty());
+}
+
+// Upload rate limiting
+
+fn http_response(status: u16, body: &str) -> HttpResponse {
+    HttpResponse { status: Nat::from(status), headers: vec![], body: body.as_bytes().to_vec() }
+}
+
+fn pin_response() -> HttpResponse {
+    http_response(200, &format!(r#"{{"IpfsHash":"{}","PinSize":2048,"Timestamp":"2024-01-01T00:00:00Z"}}"#, cid(1)))
+}
+
+fn upload_as(n: u8) -> PinataUploadResponse {
+    call_as(n);
+    block_on(upload_to_pinata(PinataUploadRequest {
+        file_data: b"audio".to_vec(),
+        file_name: "take.wav".to_string(),
+        content_type: "audio/wav".to_string(),
+        api_key: "key".to_string(),
+        secret_key: "secret".to_string(),
+    }))
+}
+
+#[test]
+fn uploads_past_the_limit_are_rejected_per_caller() {
+    mock::respond_with(|_| pin_response());
+    for _ in 0..5 {
+        let response = upload_as(1);
+        assert!(response.success);
+        assert_eq!((response.ipfs_hash, response.pin_size), (cid(1), 2048));
+    }
+    let rejected = upload_as(1);
+    assert!(!rejected.success);
+    assert!(rejected.error.unwrap().contains("rate limit"));
+    assert_eq!(mock::take_http_requests().len(), 5);
+
+    assert!(upload_as(2).success);
+    mock::advance_time(60 * 1_000_000_000);
+    assert!(upload_as(1).success);
+}
+
+#[test]
+fn the_upload_limit_is_admin_configurable() {
+    install();
+    call_as(1);
+    assert!(set_upload_rate_limit(1, 60).is_err());
+    call_as(ADMIN_USER);
+    assert!(set_upload_rate_limit(1, 0).is_err());
+    set_upload_rate_limit(1, 10).unwrap();
+
+    mock::respond_with(|_| pin_response());
+    assert!(upload_as(1).success);
+    assert!(!upload_as(1).success);
+    mock::advance_time(10 * 1_000_000_000);
+    assert!(upload_as(1).success);
+}
+
+// Stored Pinata credentials
+
+const SECRET: &str = "pinata-s3cret-value";
+
+fn header<'a>(request: &'a CanisterHttpRequestArgument, name: &str) -> Option<&'a str> {
+    request.headers.iter().find(|header| header.name == name).map(|header| header.value.as_str())
+}
+
+#[test]
+fn stored_credentials_are_used_for_uploads() {
+    install();
+    let upload = || block_on(upload_to_pinata_stored(b"audio".to_vec(), "take.wav".to_string(), "audio/wav".to_string()));
+    mock::respond_with(|_| pin_response());
+    assert!(!upload().success);
+    assert!(mock::take_http_requests().is_empty());
+
+    call_as(1);
+    assert!(set_pinata_credentials("key".to_string(), SECRET.to_string()).is_err());
+    call_as(ADMIN_USER);
+    assert!(set_pinata_credentials(" ".to_string(), SECRET.to_string()).is_err());
+    set_pinata_credentials(" key ".to_string(), SECRET.to_string()).unwrap();
+    assert!(has_pinata_credentials());
+
+    assert!(upload().success);
+    let requests = mock::take_http_requests();
+    assert_eq!(header(&requests[0], "pinata_api_key"), Some("key"));
+    assert_eq!(header(&requests[0], "pinata_secret_api_key"), Some(SECRET));
+}
+
+#[test]
+fn no_query_returns_the_stored_secret() {
+    install();
+    set_pinata_credentials("key".to_string(), SECRET.to_string()).unwrap();
+    let replies = [
+        candid::encode_one(export_state()).unwrap(),
+        candid::encode_one(get_admin()).unwrap(),
+        candid::encode_one(get_upload_rate_limit()).unwrap(),
+        candid::encode_one(has_pinata_credentials()).unwrap(),
+    ];
+    for reply in replies {
+        assert!(!reply.windows(SECRET.len()).any(|window| window == SECRET.as_bytes()));
+    }
+}
+
+// Track removal and unpinning
+
+fn join(project_id: u64, owner: u8, contributor: u8) {
+    call_as(owner);
+    add_contributors(project_id, vec![user(contributor)]).unwrap();
+}
+
+#[test]
+fn only_the_owner_or_uploader_can_remove_a_track() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    join(project_id, 1, 3);
+    upload(2, project_id, 10, &cid(1)).unwrap();
+    upload(2, project_id, 11, &cid(2)).unwrap();
+
+    call_as(3);
+    assert!(!remove_track(project_id, 10, None));
+    call_as(4);
+    assert!(!remove_track(project_id, 10, None));
+    call_as(2);
+    assert!(remove_track(project_id, 10, None));
+    call_as(1);
+    assert!(remove_track(project_id, 11, None));
+    assert!(!remove_track(project_id, 11, None));
+    assert!(get_project_tracks(project_id).is_empty());
+}
+
+#[test]
+fn removing_with_unpin_queues_content_no_longer_referenced() {
+    install();
+    let project_id = project_of(1);
+    let other_project = project_of(2);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(1, project_id, 11, &cid(2)).unwrap();
+    upload(1, project_id, 12, &cid(3)).unwrap();
+    upload(2, other_project, 20, &cid(2)).unwrap();
+
+    call_as(1);
+    assert!(remove_track(project_id, 10, Some(true)));
+    assert!(remove_track(project_id, 11, Some(true)));
+    assert!(remove_track(project_id, 12, Some(false)));
+    call_as(ADMIN_USER);
+    assert_eq!(get_pending_unpins().unwrap(), vec![cid(1)]);
+}
+
+#[test]
+fn the_unpin_queue_is_admin_managed() {
+    install();
+    call_as(1);
+    assert!(unpin_from_pinata(cid(1)).is_err());
+    assert!(get_pending_unpins().is_err());
+
+    call_as(ADMIN_USER);
+    unpin_from_pinata(cid(1)).unwrap();
+    unpin_from_pinata(cid(1)).unwrap();
+    unpin_from_pinata(cid(2)).unwrap();
+    assert!(unpin_from_pinata(" ".to_string()).is_err());
+    assert_eq!(get_pending_unpins().unwrap(), vec![cid(1), cid(2)]);
+
+    // Confirming a hash that was already unpinned is harmless
+    assert_eq!(confirm_unpinned(vec![cid(1), cid(3)]).unwrap(), "1 hashes removed from the unpin queue");
+    assert_eq!(get_pending_unpins().unwrap(), vec![cid(2)]);
+}
+
+// Pin status
+
+fn pin_list(count: u64, hash: &str) -> HttpResponse {
+    let rows = if count == 0 {
+        String::new()
+    } else {
+        format!(r#"{{"id":"1","ipfs_pin_hash":"{}","size":5120,"date_pinned":"2024-01-02T03:04:05.000Z"}}"#, hash)
+    };
+    http_response(200, &format!(r#"{{"count":{},"rows":[{}]}}"#, count, rows))
+}
+
+fn with_credentials() {
+    install();
+    set_pinata_credentials("key".to_string(), SECRET.to_string()).unwrap();
+}
+
+#[test]
+fn a_found_pin_reports_its_size_and_date() {
+    with_credentials();
+    mock::respond_with(|_| pin_list(1, &cid(1)));
+    let status = block_on(check_pin_status(format!(" {} ", cid(1)))).unwrap();
+    assert!(status.pinned);
+    assert_eq!(status.size, 5120);
+    assert_eq!(status.pin_date.as_deref(), Some("2024-01-02T03:04:05.000Z"));
+
+    let requests = mock::take_http_requests();
+    assert!(requests[0].url.ends_with(&format!("hashContains={}", cid(1))));
+    assert_eq!(header(&requests[0], "pinata_secret_api_key"), Some(SECRET));
+}
+
+#[test]
+fn a_missing_or_partial_match_is_not_pinned() {
+    with_credentials();
+    mock::respond_with(|_| pin_list(0, ""));
+    assert!(!block_on(check_pin_status(cid(1))).unwrap().pinned);
+
+    // hashContains also matches longer hashes that merely contain the one asked for
+    mock::respond_with(|_| pin_list(1, &format!("{}x", cid(1))));
+    let status = block_on(check_pin_status(cid(1))).unwrap();
+    assert!(!status.pinned);
+    assert_eq!(status.size, 0);
+
+    mock::respond_with(|_| http_response(401, ""));
+    assert!(block_on(check_pin_status(cid(1))).is_err());
+}
+
+#[test]
+fn pin_checks_validate_the_hash_and_are_rate_limited() {
+    with_credentials();
+    mock::respond_with(|_| pin_list(0, ""));
+    call_as(1);
+    assert!(block_on(check_pin_status("Qm&status=unpinned".to_string())).is_err());
+    assert!(block_on(check_pin_status(String::new())).is_err());
+    assert!(mock::take_http_requests().is_empty());
+
+    for _ in 0..5 {
+        block_on(check_pin_status(cid(1))).unwrap();
+    }
+    let error = block_on(check_pin_status(cid(1))).err().unwrap();
+    assert!(error.contains("rate limit"));
+    assert_eq!(mock::take_http_requests().len(), 5);
+}
+
+#[test]
+fn json_fields_are_read_from_pinatas_response_shape() {
+    let json = r#"{"count": 2,"rows":[{"ipfs_pin_hash":"QmA","size":5120,"date_pinned":""}]}"#;
+    assert_eq!(json_number_field(json, "count"), Some(2));
+    assert_eq!(json_number_field(json, "size"), Some(5120));
+    assert_eq!(json_string_field(json, "ipfs_pin_hash").as_deref(), Some("QmA"));
+    assert_eq!(json_string_field(json, "date_pinned").as_deref(), Some(""));
+
+    // The first occurrence wins, and a field of the wrong kind reads as missing
+    assert_eq!(json_string_field(r#"{"id":"1","rows":[{"id":"2"}]}"#, "id").as_deref(), Some("1"));
+    assert_eq!(json_number_field(json, "ipfs_pin_hash"), None);
+    assert_eq!(json_string_field(json, "size"), None);
+    assert_eq!(json_number_field(json, "missing"), None);
+    assert_eq!(json_string_field(r#"{"hash":"unterminated"#, "hash"), None);
+}
+
+// Track metadata
+
+// Runs `after` on a fresh thread, standing in for the upgraded canister's empty heap,
+// once this thread's state has been through the same encoding as stable memory
+fn after_upgrade<T: Send + 'static>(after: impl FnOnce() -> T + Send + 'static) -> T {
+    let saved = candid::encode_one(collect_state()).unwrap();
+    std::thread::spawn(move || {
+        restore_state(candid::decode_one(&saved).unwrap());
+        after()
+    })
+    .join()
+    .unwrap()
+}
+
+fn metadata(duration_secs: u32, genre: &str, bpm: u16) -> TrackMetadata {
+    TrackMetadata { duration_secs: Some(duration_secs), genre: Some(genre.to_string()), bpm: Some(bpm) }
+}
+
+#[test]
+fn tracks_keep_the_metadata_they_were_added_with() {
+    let project_id = project_of(1);
+    call_as(1);
+    add_track(project_id, "Intro".to_string(), cid(1), 10, Some(metadata(180, "house", 124))).unwrap();
+    upload(1, project_id, 11, &cid(2)).unwrap();
+
+    let tracks = get_project_tracks(project_id);
+    assert_eq!((tracks[0].duration_secs, tracks[0].genre.as_deref(), tracks[0].bpm), (Some(180), Some("house"), Some(124)));
+    assert_eq!((tracks[1].duration_secs, tracks[1].genre.as_deref(), tracks[1].bpm), (None, None, None));
+
+    let genre = after_upgrade(move || get_project_tracks(project_id)[0].genre.clone());
+    assert_eq!(genre.as_deref(), Some("house"));
+}
+
+#[test]
+fn only_the_owner_or_uploader_can_edit_track_metadata() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    join(project_id, 1, 3);
+    upload(2, project_id, 10, &cid(1)).unwrap();
+
+    call_as(3);
+    assert!(update_track_metadata(project_id, 10, metadata(1, "jazz", 90)).is_err());
+    call_as(2);
+    update_track_metadata(project_id, 10, metadata(200, "techno", 130)).unwrap();
+    call_as(1);
+    update_track_metadata(project_id, 10, metadata(210, "techno", 132)).unwrap();
+    assert!(update_track_metadata(project_id, 99, metadata(1, "jazz", 90)).is_err());
+    assert_eq!(get_project_tracks(project_id)[0].bpm, Some(132));
+}
+
+#[test]
+fn tracks_encoded_without_metadata_decode_with_none() {
+    #[derive(CandidType)]
+    struct LegacyTrack {
+        id: u64,
+        name: String,
+        ipfs_hash: String,
+        uploaded_by: String,
+        timestamp: u64,
+        play_count: u64,
+    }
+    let legacy = LegacyTrack { id: 7, name: "Old".to_string(), ipfs_hash: cid(1), uploaded_by: user(1), timestamp: 5, play_count: 0 };
+    let track: Track = candid::decode_one(&candid::encode_one(legacy).unwrap()).unwrap();
+    assert_eq!((track.id, track.timestamp), (7, 5));
+    assert_eq!((track.duration_secs, track.genre, track.bpm), (None, None, None));
+}
+
+// Recent feeds
+
+#[test]
+fn recent_feeds_put_the_newest_first() {
+    let first = mint(1);
+    mock::advance_time(1);
+    let second = mint(1);
+    mock::advance_time(1);
+    let third = mint(1);
+    let ids: Vec<u64> = get_recent_nfts(10).iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![third, second, first]);
+
+    let older = project_of(1);
+    mock::advance_time(1);
+    let newer = project_of(2);
+    let ids: Vec<u64> = get_recent_projects(10).iter().map(|project| project.id).collect();
+    assert_eq!(ids, vec![newer, older]);
+}
+
+#[test]
+fn recent_feeds_respect_the_capped_limit() {
+    for _ in 0..MAX_QUERY_LIMIT + 1 {
+        mint(1);
+        mock::advance_time(1);
+    }
+    project_of(1);
+    project_of(1);
+    assert_eq!(get_recent_nfts(2).len(), 2);
+    assert_eq!(get_recent_nfts(0).len(), 0);
+    assert_eq!(get_recent_nfts(u64::MAX).len(), MAX_QUERY_LIMIT as usize);
+    assert_eq!(get_recent_projects(1).len(), 1);
+}
+
+// Project timestamps
+
+#[test]
+fn projects_are_stamped_in_creation_order() {
+    let first = project_of(1);
+    let second = project_of(1);
+    mock::advance_time(DAY);
+    let third = project_of(1);
+    let stamp = |id| get_project(id).unwrap().created_at;
+    assert_eq!(stamp(first), mock::START_TIME);
+    assert!(stamp(first) <= stamp(second) && stamp(second) < stamp(third));
+
+    let restored = after_upgrade(move || get_project(third).map(|project| project.created_at));
+    assert_eq!(restored, Some(stamp(third)));
+}
+
+// Cached marketplace stats
+
+#[test]
+fn cached_stats_track_every_change() {
+    let check = || assert_eq!(get_marketplace_stats_cached(), get_marketplace_stats());
+    let listed = mint(1);
+    let unlisted = mint_with(1, MintArgs { list_immediately: Some(false), ..MintArgs::default() }).unwrap();
+    check();
+    assert_eq!(get_marketplace_stats_cached().nfts_for_sale, 1);
+
+    call_as(1);
+    set_nft_for_sale(unlisted, true).unwrap();
+    set_nft_for_sale(unlisted, true).unwrap();
+    check();
+    buy(2, listed).unwrap();
+    check();
+    relist(2, listed, 250);
+    buy(3, listed).unwrap();
+    call_as(1);
+    set_nft_for_sale(unlisted, false).unwrap();
+    transfer_nft(unlisted, user(4)).unwrap();
+    check();
+
+    let stats = get_marketplace_stats_cached();
+    assert_eq!((stats.total_nfts, stats.nfts_for_sale, stats.sale_count, stats.total_volume), (2, 0, 2, 350));
+    assert_eq!(after_upgrade(get_marketplace_stats_cached), stats);
+}
+
+// Bulk NFT lookup
+
+#[test]
+fn bulk_lookup_keeps_the_order_and_skips_missing_ids() {
+    let first = mint(1);
+    let second = mint(1);
+    let third = mint(1);
+    let ids: Vec<u64> = get_nfts_by_ids(vec![third, 9999, first, second]).iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![third, first, second]);
+    assert!(get_nfts_by_ids(Vec::new()).is_empty());
+    assert!([first, second, third].iter().all(|id| get_view_count(*id) == Some(0)));
+}
+
+// Recording views
+
+fn recent_view_entries() -> usize {
+    RECENT_VIEWS.with(|views| views.borrow().len())
+}
+
+#[test]
+fn get_nft_never_counts_a_view() {
+    let nft_id = mint(1);
+    call_as(2);
+    get_nft(nft_id).unwrap();
+    get_nft(nft_id).unwrap();
+    assert_eq!(get_view_count(nft_id), Some(0));
+
+    assert_eq!(record_nft_view(nft_id), Ok(1));
+    assert_eq!(get_nft_and_record_view(nft_id).map(|nft| nft.view_count), Some(1));
+    call_as(3);
+    assert_eq!(get_nft_and_record_view(nft_id).map(|nft| nft.view_count), Some(2));
+    assert!(record_nft_view(9999).is_err());
+}
+
+#[test]
+fn single_views_forget_expired_dedup_entries() {
+    let nft_id = mint(1);
+    for viewer in 2..12 {
+        call_as(viewer);
+        record_nft_view(nft_id).unwrap();
+    }
+    assert_eq!(recent_view_entries(), 10);
+
+    mock::advance_time(DEFAULT_VIEW_DEDUP_WINDOW_NS);
+    call_as(2);
+    assert_eq!(record_nft_view(nft_id), Ok(11));
+    assert_eq!(recent_view_entries(), 1);
+}
+
+// Input validation
+
+#[test]
+fn project_text_is_checked_at_each_boundary() {
+    call_as(1);
+    let create = |title: String, description: String| create_project(title, description, user(1));
+    assert!(create("  ".to_string(), String::new()).is_err());
+    assert!(create("t".repeat(MAX_TITLE_LEN), "d".repeat(MAX_DESCRIPTION_LEN)).is_ok());
+    assert!(create("t".repeat(MAX_TITLE_LEN + 1), String::new()).is_err());
+    assert!(create("Title".to_string(), "d".repeat(MAX_DESCRIPTION_LEN + 1)).is_err());
+    // Limits count characters, not bytes
+    assert!(create("é".repeat(MAX_TITLE_LEN), String::new()).is_ok());
+}
+
+#[test]
+fn nft_names_and_descriptions_are_checked_at_each_boundary() {
+    let with_text = |name: String, description: String| mint_with(1, MintArgs { name, description, ..MintArgs::default() });
+    assert!(with_text(String::new(), String::new()).is_err());
+    assert!(with_text("n".repeat(MAX_NAME_LEN), "d".repeat(MAX_DESCRIPTION_LEN)).is_ok());
+    assert!(with_text("n".repeat(MAX_NAME_LEN + 1), String::new()).is_err());
+    assert!(with_text("Song".to_string(), "d".repeat(MAX_DESCRIPTION_LEN + 1)).is_err());
+}
+
+#[test]
+fn image_urls_need_a_known_scheme_and_a_sane_size() {
+    assert!(validate_image_url(IMAGE).is_ok());
+    assert!(validate_image_url("ipfs://QmHash").is_ok());
+    assert!(validate_image_url("data:image/png;base64,AAAA").is_ok());
+    assert!(validate_image_url("").is_err());
+    assert!(validate_image_url("ftp://example.com/a.png").is_err());
+    assert!(validate_image_url("https://").is_err());
+    assert!(validate_image_url("https://example.com/a b.png").is_err());
+
+    let long = |len: usize| format!("https://{}", "a".repeat(len - "https://".len()));
+    assert!(validate_image_url(&long(MAX_URL_LEN)).is_ok());
+    assert!(validate_image_url(&long(MAX_URL_LEN + 1)).is_err());
+
+    // The mint rejects a bad URL before it takes an id
+    call_as(1);
+    let minted = block_on(mint_nft(
+        "Song".to_string(),
+        String::new(),
+        "not a url".to_string(),
+        user(1),
+        0,
+        100,
+        "music".to_string(),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    ));
+    assert!(minted.is_err());
+    assert_eq!(mint(1), 1);
+}
+
+// Purchase preview
+
+fn paid_total(transfers: &[mock::Transfer], n: u8) -> u64 {
+    transfers.iter().filter(|transfer| paid_to(transfer, n)).map(|transfer| transfer.amount).sum()
+}
+
+#[test]
+fn the_preview_matches_the_purchase() {
+    use_ledger();
+    set_platform_fee(5, Some(user(9))).unwrap();
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 2001);
+    mock::take_transfers();
+
+    call_as(3);
+    let preview = preview_purchase(nft_id).unwrap();
+    assert_eq!((preview.seller.as_str(), preview.royalty_recipient.as_str()), (user(2).as_str(), user(1).as_str()));
+    assert_eq!((preview.sale_price, preview.royalty_amount, preview.platform_fee), (2001, 200, 100));
+    assert_eq!(preview.seller_amount, 2001 - 200 - 100);
+    // Previewing is a pure read
+    assert_eq!(preview_purchase(nft_id).unwrap().seller_amount, preview.seller_amount);
+
+    buy(3, nft_id).unwrap();
+    let transfers = mock::take_transfers();
+    assert_eq!(paid_total(&transfers, 2), preview.seller_amount);
+    assert_eq!(paid_total(&transfers, 1), preview.royalty_amount);
+    assert_eq!(paid_total(&transfers, 9), preview.platform_fee);
+}
+
+#[test]
+fn calculate_purchase_rejects_what_buy_would_reject() {
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    let mut nft = get_nft(nft_id).unwrap();
+    assert_eq!(calculate_purchase(&nft, &user(2)).unwrap().royalty_amount, 0);
+    assert_eq!(calculate_purchase(&nft, &user(1)).err(), Some(MarketError::AlreadyOwner));
+
+    nft.is_hidden = true;
+    assert_eq!(calculate_purchase(&nft, &user(2)).err(), Some(MarketError::NotForSale));
+    nft.is_hidden = false;
+    nft.is_for_sale = false;
+    assert_eq!(calculate_purchase(&nft, &user(2)).err(), Some(MarketError::NotForSale));
+    assert_eq!(preview_purchase(9999).err(), Some(MarketError::NotFound));
+}
+
+// Track upload membership
+
+#[test]
+fn only_members_can_upload_tracks() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(2, project_id, 11, &cid(2)).unwrap();
+    assert!(upload(3, project_id, 12, &cid(3)).is_err());
+    assert!(upload(1, 9999, 13, &cid(4)).is_err());
+
+    let uploaders: Vec<String> = get_project_tracks(project_id).into_iter().map(|track| track.uploaded_by).collect();
+    assert_eq!(uploaders, vec![user(1), user(2)]);
+}
+
+#[test]
+fn only_the_owner_can_add_a_contributor() {
+    let project_id = project_of(1);
+    call_as(2);
+    assert!(add_contributor(project_id, user(3)).is_err());
+    call_as(1);
+    assert!(add_contributor(project_id, "not a principal".to_string()).is_err());
+    assert!(add_contributor(9999, user(3)).is_err());
+    add_contributor(project_id, user(2)).unwrap();
+    add_contributor(project_id, user(2)).unwrap();
+    assert_eq!(get_project(project_id).unwrap().contributors.iter().filter(|c| **c == user(2)).count(), 1);
+    upload(2, project_id, 10, &cid(1)).unwrap();
+}
+
+// Wishlists
+
+fn wishlist_of(n: u8) -> Vec<u64> {
+    call_as(n);
+    get_wishlist().iter().map(|nft| nft.id).collect()
+}
+
+#[test]
+fn wishlists_are_per_caller_and_idempotent() {
+    let first = mint(1);
+    let second = mint(1);
+    call_as(2);
+    add_to_wishlist(second).unwrap();
+    add_to_wishlist(first).unwrap();
+    add_to_wishlist(second).unwrap();
+    assert!(add_to_wishlist(9999).is_err());
+    assert_eq!(wishlist_of(2), vec![second, first]);
+    assert!(wishlist_of(3).is_empty());
+
+    call_as(2);
+    remove_from_wishlist(second).unwrap();
+    assert!(remove_from_wishlist(second).is_err());
+    assert_eq!(wishlist_of(2), vec![first]);
+    assert_eq!(after_upgrade(|| wishlist_of(2)), vec![first]);
+}
+
+#[test]
+fn wishlists_skip_nfts_that_no_longer_exist() {
+    let kept = mint(1);
+    let removed = mint(1);
+    call_as(2);
+    add_to_wishlist(kept).unwrap();
+    add_to_wishlist(removed).unwrap();
+    NFTS.with(|nfts| nfts.borrow_mut().remove(&removed));
+    assert_eq!(wishlist_of(2), vec![kept]);
+}
+
+// Event log
+
+fn events_of(n: u8, since_index: u64) -> Vec<EventKind> {
+    call_as(n);
+    get_events(since_index).into_iter().map(|event| event.kind).collect()
+}
+
+#[test]
+fn a_resale_notifies_the_seller_and_the_creator() {
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 2000);
+    buy(3, nft_id).unwrap();
+
+    let seller = events_of(2, 0);
+    assert!(matches!(seller.as_slice(), [EventKind::Purchased { .. }, EventKind::Sold { nft_id: id, price: 2000, buyer }]
+        if *id == nft_id && *buyer == user(3)));
+    let creator = events_of(1, 0);
+    assert!(matches!(creator.first(), Some(EventKind::Minted { .. })));
+    assert!(matches!(creator.last(), Some(EventKind::RoyaltyReceived { nft_id: id, amount: 200 }) if *id == nft_id));
+    assert!(matches!(events_of(3, 0).as_slice(), [EventKind::Purchased { price: 2000, .. }]));
+}
+
+#[test]
+fn events_drain_incrementally_and_are_capped() {
+    let nft_id = mint(1);
+    call_as(1);
+    let last = get_events(0).last().unwrap().index;
+    assert!(events_of(1, last + 1).is_empty());
+    transfer_nft(nft_id, user(2)).unwrap();
+    assert!(matches!(events_of(2, 0).as_slice(), [EventKind::TransferReceived { from, .. }] if *from == user(1)));
+
+    for _ in 0..MAX_EVENTS_PER_USER {
+        mint(1);
+    }
+    call_as(1);
+    let events = get_events(0);
+    assert_eq!(events.len(), MAX_EVENTS_PER_USER);
+    assert_eq!(events.last().unwrap().index, MAX_EVENTS_PER_USER as u64);
+}
+
+// Search
+
+#[test]
+fn search_matches_projects_and_nfts_case_insensitively() {
+    call_as(1);
+    let project_id = create_project("Midnight Sessions".to_string(), String::new(), user(1)).unwrap();
+    create_project("Daybreak".to_string(), "recorded after MIDNIGHT".to_string(), user(1)).unwrap();
+    create_project("Elsewhere".to_string(), String::new(), user(1)).unwrap();
+    let nft_id = mint_with(1, MintArgs { name: "Midnight Drive".to_string(), ..MintArgs::default() }).unwrap();
+    mint(1);
+
+    let results = search_all("  midNIGHT ".to_string()).unwrap();
+    assert_eq!(results.projects.len(), 2);
+    assert_eq!(results.projects[0].id, project_id);
+    let nfts: Vec<u64> = results.nfts.iter().map(|nft| nft.id).collect();
+    assert_eq!(nfts, vec![nft_id]);
+    assert!(search_all("nothing like it".to_string()).unwrap().nfts.is_empty());
+}
+
+#[test]
+fn search_queries_need_two_characters() {
+    assert!(search_all(String::new()).is_err());
+    assert!(search_all(" a ".to_string()).is_err());
+    assert!(search_all("ab".to_string()).is_ok());
+}
+
+// NFT media
+
+const ANIMATION: &str = "https://example.com/loop.mp4";
+const PREVIEW: &str = "ipfs://QmPreview";
+
+#[test]
+fn media_urls_are_set_at_mint_and_updated_by_the_owner() {
+    call_as(1);
+    let nft_id = block_on(mint_nft(
+        "Song".to_string(),
+        String::new(),
+        IMAGE.to_string(),
+        user(1),
+        0,
+        100,
+        "music".to_string(),
+        Some(ANIMATION.to_string()),
+        Some(PREVIEW.to_string()),
+        None,
+        None,
+        None,
+        None,
+    ))
+    .unwrap();
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!((nft.animation_url.as_deref(), nft.audio_preview_url.as_deref()), (Some(ANIMATION), Some(PREVIEW)));
+
+    assert!(matches!(
+        update_nft_media(nft_id, Some("javascript:alert(1)".to_string()), None),
+        Err(MarketError::InvalidInput(_))
+    ));
+    update_nft_media(nft_id, None, Some(ANIMATION.to_string())).unwrap();
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!((nft.animation_url, nft.audio_preview_url.as_deref()), (None, Some(ANIMATION)));
+
+    call_as(2);
+    assert!(matches!(update_nft_media(nft_id, None, None), Err(MarketError::Unauthorized(_))));
+    assert_eq!(update_nft_media(9999, None, None), Err(MarketError::NotFound));
+}
+
+// NFTs by IPFS hash
+
+#[test]
+fn every_nft_minted_from_a_hash_is_found() {
+    let hash = cid(1);
+    let first = mint_with(1, MintArgs { ipfs_hash: Some(hash.clone()), ..MintArgs::default() }).unwrap();
+    let second = mint_with(2, MintArgs { ipfs_hash: Some(format!(" {} ", hash)), ..MintArgs::default() }).unwrap();
+    mint_with(1, MintArgs { ipfs_hash: Some(cid(2)), ..MintArgs::default() }).unwrap();
+    mint(1);
+
+    let ids: Vec<u64> = get_nfts_by_ipfs_hash(format!("{}\n", hash)).iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![first, second]);
+    assert!(get_nfts_by_ipfs_hash(cid(3)).is_empty());
+    assert!(mint_with(1, MintArgs { ipfs_hash: Some("not-a-cid".to_string()), ..MintArgs::default() }).is_err());
+}
+
+// Reports and moderation
+
+fn report(reporter: u8, nft_id: u64) -> u64 {
+    call_as(reporter);
+    report_nft(nft_id, "Copyright".to_string()).unwrap()
+}
+
+// Hides an NFT through moderation, leaving ADMIN_USER as the caller; needs install()
+fn hide(nft_id: u64) {
+    let report_id = report(ADMIN_USER, nft_id);
+    resolve_report(report_id, ReportAction::HideNft).unwrap();
+}
+
+#[test]
+fn reports_are_admin_only_and_resolve_once() {
+    install();
+    let nft_id = mint(1);
+    let report_id = report(2, nft_id);
+    assert!(report_nft(9999, "Abuse".to_string()).is_err());
+    assert!(report_nft(nft_id, " ".to_string()).is_err());
+    assert!(get_reports().is_err());
+    assert!(resolve_report(report_id, ReportAction::Dismiss).is_err());
+
+    call_as(ADMIN_USER);
+    let reports = get_reports().unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!((reports[0].nft_id, reports[0].reporter.as_str()), (nft_id, user(2).as_str()));
+    assert!(reports[0].resolution.is_none());
+    resolve_report(report_id, ReportAction::Dismiss).unwrap();
+    assert!(resolve_report(report_id, ReportAction::HideNft).is_err());
+    assert!(!get_nft(nft_id).unwrap().is_hidden);
+
+    let next = report(3, nft_id);
+    assert_eq!(next, report_id + 1);
+    assert_eq!(after_upgrade(move || report_nft(nft_id, "Again".to_string())), Ok(next + 1));
+}
+
+#[test]
+fn a_hidden_nft_disappears_from_listings_and_lookups() {
+    install();
+    let hash = cid(1);
+    let hidden = mint_with(1, MintArgs { ipfs_hash: Some(hash.clone()), ..MintArgs::default() }).unwrap();
+    let visible = mint(1);
+    call_as(2);
+    add_to_wishlist(hidden).unwrap();
+    let report_id = report(2, hidden);
+    call_as(ADMIN_USER);
+    resolve_report(report_id, ReportAction::HideNft).unwrap();
+
+    let ids = |nfts: Vec<NFTMetadata>| nfts.iter().map(|nft| nft.id).collect::<Vec<_>>();
+    assert_eq!(ids(list_nfts()), vec![visible]);
+    assert!(get_nfts_by_ipfs_hash(hash).is_empty());
+    assert_eq!(ids(get_nfts_by_ids(vec![hidden, visible])), vec![visible]);
+    assert_eq!(get_category_counts(), vec![("music".to_string(), 1)]);
+    assert!(wishlist_of(2).is_empty());
+    assert!(matches!(buy(3, hidden), Err(MarketError::NotForSale)));
+    assert!(after_upgrade(move || get_nft(hidden).is_none()));
+}
+
+#[test]
+fn a_hidden_nft_records_no_views() {
+    install();
+    let nft_id = mint(1);
+    hide(nft_id);
+
+    call_as(2);
+    assert!(get_nft(nft_id).is_none());
+    assert!(get_nft_and_record_view(nft_id).is_none());
+    assert!(record_nft_view(nft_id).is_err());
+    assert_eq!(record_views(vec![nft_id]), 0);
+    assert_eq!(get_view_count(nft_id), None);
+    assert!(get_nft_transactions(nft_id).is_empty());
+    assert_eq!(NFTS.with(|nfts| nfts.borrow()[&nft_id].view_count), 0);
+}
+
+#[test]
+fn a_hidden_nft_cant_be_listed_until_unhidden() {
+    install();
+    let nft_id = mint(1);
+    hide(nft_id);
+    let listed = || get_marketplace_stats_cached().nfts_for_sale;
+    assert_eq!(listed(), 0);
+
+    call_as(1);
+    assert!(matches!(set_nft_for_sale(nft_id, true), Err(MarketError::InvalidInput(_))));
+    assert!(matches!(relist_nft(nft_id, 500), Err(MarketError::InvalidInput(_))));
+    assert!(matches!(batch_set_for_sale(vec![nft_id], true)[0].1, Err(MarketError::InvalidInput(_))));
+    assert_eq!(listed(), 0);
+    assert!(unhide_nft(nft_id).is_err());
+
+    call_as(ADMIN_USER);
+    assert_eq!(unhide_nft(nft_id), Ok(format!("NFT {} is visible again", nft_id)));
+    assert!(unhide_nft(nft_id).is_err());
+    assert!(!get_nft(nft_id).unwrap().is_for_sale);
+    call_as(1);
+    relist_nft(nft_id, 500).unwrap();
+    assert_eq!(listed(), 1);
+    buy(2, nft_id).unwrap();
+}
+
+// Blacklist
+
+#[test]
+fn blacklisted_callers_are_blocked_until_unblocked() {
+    install();
+    let project_id = project_of(1);
+    let nft_id = mint(2);
+    call_as(1);
+    assert!(blacklist_principal(user(1)).is_err());
+    call_as(ADMIN_USER);
+    blacklist_principal(user(1)).unwrap();
+    assert!(blacklist_principal(user(1)).is_err());
+    assert!(is_blacklisted(user(1)) && !is_blacklisted(user(2)) && !is_blacklisted("bad".to_string()));
+
+    assert!(mint_with(1, MintArgs::default()).is_err());
+    assert_eq!(buy(1, nft_id), Err(MarketError::Blocked));
+    call_as(1);
+    assert!(create_project("Title".to_string(), String::new(), user(1)).is_err());
+    assert!(upload(1, project_id, 10, &cid(1)).is_err());
+
+    call_as(ADMIN_USER);
+    unblacklist_principal(user(1)).unwrap();
+    assert!(unblacklist_principal(user(1)).is_err());
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    mint(1);
+    buy(1, nft_id).unwrap();
+}
+
+#[test]
+fn the_blacklist_survives_an_upgrade() {
+    install();
+    blacklist_principal(user(1)).unwrap();
+    assert!(after_upgrade(|| is_blacklisted(user(1))));
+}
+
+// Project statistics
+
+#[test]
+fn project_statistics_sum_tracks_members_and_sales() {
+    let project_id = project_of(1);
+    let other = project_of(1);
+    join(project_id, 1, 2);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(2, project_id, 11, &cid(2)).unwrap();
+    let from_project = |price| mint_with(1, MintArgs { project_id, price, ..MintArgs::default() }).unwrap();
+    let sold = from_project(100);
+    from_project(500);
+    let elsewhere = mint_with(1, MintArgs { project_id: other, ..MintArgs::default() }).unwrap();
+    buy(3, sold).unwrap();
+    relist(3, sold, 300);
+    buy(4, sold).unwrap();
+    buy(3, elsewhere).unwrap();
+
+    let stats = get_project_statistics(project_id).unwrap();
+    assert_eq!((stats.track_count, stats.contributor_count, stats.nft_count, stats.sales_volume), (2, 1, 2, 400));
+    let empty = get_project_statistics(project_of(5)).unwrap();
+    assert_eq!((empty.track_count, empty.contributor_count, empty.nft_count, empty.sales_volume), (0, 0, 0, 0));
+    assert!(get_project_statistics(9999).is_none());
+}
+
+// State export
+
+// Mints, sells and resells one NFT from a project with a track, as ADMIN_USER's canister
+fn populate() -> (u64, u64) {
+    install();
+    let project_id = project_of(1);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    let nft_id = mint_with(1, MintArgs { project_id, royalty_percentage: Some(10), ..MintArgs::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 300);
+    buy(3, nft_id).unwrap();
+    mint(2);
+    call_as(ADMIN_USER);
+    (project_id, nft_id)
+}
+
+#[test]
+fn the_export_mirrors_the_live_state() {
+    let (project_id, nft_id) = populate();
+    let snapshot = export_state().unwrap();
+    assert_eq!(snapshot.projects.len(), 1);
+    assert_eq!(snapshot.projects[0].tracks.len(), get_project_tracks(project_id).len());
+    let nft_ids: Vec<u64> = snapshot.nfts.iter().map(|nft| nft.id).collect();
+    assert_eq!(nft_ids, vec![nft_id, nft_id + 1]);
+    assert_eq!(snapshot.nfts[0].current_owner, user(3));
+    assert_eq!(snapshot.transactions.len(), TRANSACTIONS.with(|transactions| transactions.borrow().len()));
+    assert_eq!(snapshot.royalty_payments.len(), get_nft_royalty_payments(nft_id).len());
+
+    // The chunks page through the same data
+    assert_eq!(export_nfts_chunk(1, 10).unwrap()[0].id, nft_id + 1);
+    assert!(export_nfts_chunk(2, 10).unwrap().is_empty());
+    assert_eq!(export_projects_chunk(0, 1).unwrap().len(), 1);
+    assert_eq!(export_transactions_chunk(0, 2).unwrap().len(), 2);
+    assert_eq!(export_royalty_payments_chunk(0, 10).unwrap().len(), snapshot.royalty_payments.len());
+
+    call_as(1);
+    assert!(export_state().is_err() && export_nfts_chunk(0, 10).is_err());
+}
+
+#[test]
+fn the_export_leaves_out_credentials() {
+    with_credentials();
+    populate();
+    let encoded = candid::encode_one(export_state().unwrap()).unwrap();
+    assert!(!encoded.windows(SECRET.len()).any(|window| window == SECRET.as_bytes()));
+}
+
+// State import
+
+// A fresh canister on its own thread, installed by ADMIN_USER, that imports `snapshot`
+fn import_fresh<T: Send + 'static>(snapshot: StateSnapshot, then: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::spawn(move || {
+        install();
+        import_state(snapshot, None).unwrap();
+        then()
+    })
+    .join()
+    .unwrap()
+}
+
+#[test]
+fn an_import_restores_the_export_and_advances_the_ids() {
+    let (project_id, nft_id) = populate();
+    let snapshot = export_state().unwrap();
+    let encoded = candid::encode_one(&snapshot).unwrap();
+    let (reexported, next_project, next_nft) = import_fresh(snapshot, || {
+        (candid::encode_one(export_state().unwrap()).unwrap(), project_of(1), mint(1))
+    });
+    assert_eq!(reexported, encoded);
+    assert_eq!((next_project, next_nft), (project_id + 1, nft_id + 2));
+}
+
+#[test]
+fn an_import_into_a_canister_with_data_needs_force() {
+    let (_, nft_id) = populate();
+    let snapshot = export_state().unwrap();
+    call_as(1);
+    assert!(import_state(snapshot.clone(), Some(true)).is_err());
+    call_as(ADMIN_USER);
+    assert!(import_state(snapshot.clone(), None).is_err());
+    assert!(import_state(snapshot.clone(), Some(false)).is_err());
+    import_state(snapshot, Some(true)).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(3));
+}
+
+#[test]
+fn a_forced_import_drops_state_built_on_the_old_data() {
+    let (project_id, nft_id) = populate();
+    let snapshot = export_state().unwrap();
+    feature_nft(nft_id).unwrap();
+    view_as(4, nft_id);
+    call_as(4);
+    add_to_wishlist(nft_id).unwrap();
+    report_nft(nft_id, "Copyright".to_string()).unwrap();
+    let collection_id = create_collection("Faves".to_string(), String::new());
+    call_as(1);
+    invite_contributor(project_id, user(4)).unwrap();
+
+    call_as(ADMIN_USER);
+    import_state(snapshot, Some(true)).unwrap();
+    assert!(get_featured_nfts().is_empty());
+    assert!(get_reports().unwrap().is_empty());
+    assert!(VIEW_LOG.with(|log| log.borrow().is_empty()));
+    assert!(get_collection(collection_id).is_none());
+    assert!(wishlist_of(4).is_empty() && get_my_invites().is_empty() && get_events(0).is_empty());
+    // The dedup window no longer remembers the earlier view
+    assert_eq!(record_nft_view(nft_id), Ok(1));
+}
+
+#[test]
+fn a_forced_import_forgets_purchase_keys_and_fee_records() {
+    install();
+    set_platform_fee(5, Some(user(9))).unwrap();
+    let nft_id = mint(1);
+    call_as(ADMIN_USER);
+    let snapshot = export_state().unwrap();
+    call_as(2);
+    block_on(buy_nft(nft_id, Some("order-1".to_string()))).unwrap();
+    call_as(ADMIN_USER);
+    assert_eq!(get_fee_payments().unwrap().len(), 1);
+
+    import_state(snapshot, Some(true)).unwrap();
+    assert!(get_fee_payments().unwrap().is_empty());
+    // The key buys the imported NFT rather than replaying the earlier purchase
+    call_as(2);
+    block_on(buy_nft(nft_id, Some("order-1".to_string()))).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(2));
+}
+
+#[test]
+fn an_import_waits_for_queued_payouts() {
+    use_ledger();
+    let nft_id = mint(1);
+    call_as(ADMIN_USER);
+    let snapshot = export_state().unwrap();
+    mock::refuse_transfers(|transfer| transfer.from_subaccount == SETTLEMENT_SUBACCOUNT);
+    buy(2, nft_id).unwrap();
+
+    call_as(ADMIN_USER);
+    assert!(import_state(snapshot.clone(), Some(true)).is_err());
+    mock::accept_transfers();
+    block_on(retry_pending_payouts()).unwrap();
+    import_state(snapshot, Some(true)).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(1));
+}
+
+#[test]
+fn snapshots_with_duplicate_ids_are_rejected() {
+    populate();
+    let snapshot = export_state().unwrap();
+    let duplicated = |edit: fn(&mut StateSnapshot)| {
+        let mut snapshot = snapshot.clone();
+        edit(&mut snapshot);
+        import_state(snapshot, Some(true))
+    };
+    assert!(duplicated(|s| s.projects.push(s.projects[0].clone())).is_err());
+    assert!(duplicated(|s| s.nfts.push(s.nfts[0].clone())).is_err());
+    assert!(duplicated(|s| s.transactions.push(s.transactions[0].clone())).is_err());
+    assert_eq!(get_nft(1).unwrap().current_owner, user(3));
+}
+
+// Purchases racing listing changes
+
+fn is_escrow_of(account: &Account, n: u8) -> bool {
+    account.owner == canister_id() && account.subaccount == Some(escrow_subaccount(&principal(n)).to_vec())
+}
+
+// Lists NFT 1 for 1000 with user 2 as the seller, so a sale pays a royalty to user 1
+fn resale_listing() -> u64 {
+    use_ledger();
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 1000);
+    mock::take_transfers();
+    nft_id
+}
+
+#[test]
+fn a_price_change_during_payment_aborts_and_refunds() {
+    let nft_id = resale_listing();
+    mock::on_transfer(move |transfer| {
+        if into_settlement(transfer) {
+            call_as(2);
+            update_nft_price(nft_id, 1500).unwrap();
+            call_as(3);
+        }
+    });
+
+    assert_eq!(buy(3, nft_id), Err(MarketError::ListingChanged));
+    let fee = mock::ledger_fee();
+    let transfers = mock::take_transfers();
+    assert_eq!(transfers.len(), 2);
+    assert!(from_escrow_of(&transfers[0], 3) && transfers[0].amount == 1000 + 2 * fee);
+    assert_eq!(transfers[1].from_subaccount, SETTLEMENT_SUBACCOUNT);
+    assert!(is_escrow_of(&transfers[1].to, 3));
+    assert_eq!(transfers[1].amount, 1000 + fee);
+
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!((nft.current_owner, nft.price, nft.is_for_sale), (user(2), 1500, true));
+    assert_eq!(get_nft_royalty_payments(nft_id).len(), 0);
+    assert_eq!(get_marketplace_stats_cached(), get_marketplace_stats());
+}
+
+#[test]
+fn a_transfer_during_payment_aborts_and_queues_a_refused_refund() {
+    let nft_id = resale_listing();
+    mock::on_transfer(move |transfer| {
+        if into_settlement(transfer) {
+            call_as(2);
+            transfer_nft(nft_id, user(4)).unwrap();
+            call_as(3);
+            mock::refuse_transfers(|transfer| transfer.from_subaccount == SETTLEMENT_SUBACCOUNT);
+        }
+    });
+
+    assert_eq!(buy(3, nft_id), Err(MarketError::ListingChanged));
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(4));
+    call_as(ADMIN_USER);
+    let pending = get_pending_payouts().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert!(is_escrow_of(&pending[0].to, 3) && pending[0].amount == 1000 + mock::ledger_fee());
+}
+
+#[test]
+fn a_settlement_that_traps_releases_the_nft() {
+    let nft_id = resale_listing();
+    // The hook isn't put back after it panics, so only the first payment traps
+    mock::on_transfer(|_| panic!("trapped while settling"));
+    call_as(3);
+    assert!(std::panic::catch_unwind(|| block_on(buy_nft(nft_id, None))).is_err());
+
+    assert!(PURCHASES_IN_FLIGHT.with(|in_flight| in_flight.borrow().is_empty()));
+    buy(3, nft_id).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(3));
+}
+
+// View leaderboard
+
+#[test]
+fn the_leaderboard_ranks_by_views_then_id() {
+    let (a, b, c, d) = (mint(1), mint(1), mint(1), mint(1));
+    for viewer in 2..5 {
+        view_as(viewer, c);
+    }
+    view_as(2, b);
+    view_as(3, b);
+    view_as(2, d);
+    view_as(3, d);
+    view_as(2, a);
+
+    let ids: Vec<u64> = get_most_viewed_nfts(10).iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, vec![c, b, d, a]);
+    assert_eq!(get_most_viewed_nfts(2).len(), 2);
+    assert_eq!((get_view_count(c), get_view_count(9999)), (Some(3), None));
+}
+
+// Default royalty
+
+fn royalty_of(nft_id: u64) -> u8 {
+    get_nft(nft_id).unwrap().royalty_percentage
+}
+
+#[test]
+fn mints_without_a_royalty_use_the_configured_default() {
+    install();
+    let before = mint(1);
+    assert_eq!((get_default_royalty(), royalty_of(before)), (DEFAULT_ROYALTY_PERCENTAGE, DEFAULT_ROYALTY_PERCENTAGE));
+
+    call_as(ADMIN_USER);
+    set_default_royalty(25).unwrap();
+    let after = mint(1);
+    let explicit = mint_with(1, MintArgs { royalty_percentage: Some(5), ..MintArgs::default() }).unwrap();
+    assert_eq!((royalty_of(before), royalty_of(after), royalty_of(explicit)), (DEFAULT_ROYALTY_PERCENTAGE, 25, 5));
+    assert_eq!(after_upgrade(get_default_royalty), 25);
+}
+
+#[test]
+fn the_default_royalty_is_admin_only_and_capped() {
+    install();
+    set_default_royalty(MAX_ROYALTY_PERCENTAGE).unwrap();
+    assert!(set_default_royalty(MAX_ROYALTY_PERCENTAGE + 1).is_err());
+    call_as(1);
+    assert!(set_default_royalty(5).is_err());
+    assert_eq!(get_default_royalty(), MAX_ROYALTY_PERCENTAGE);
+}
+
+// Platform fee
+
+// Minted by user 1 with a 10% royalty, bought by user 2 and relisted at 1000
+fn relisted_by_collector() -> u64 {
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 1000);
+    nft_id
+}
+
+#[test]
+fn the_platform_fee_comes_out_of_the_sellers_share() {
+    use_ledger();
+    set_platform_fee(5, Some(user(9))).unwrap();
+    let nft_id = relisted_by_collector();
+    mock::take_transfers();
+    buy(3, nft_id).unwrap();
+    let transfers = mock::take_transfers();
+    assert_eq!((paid_total(&transfers, 2), paid_total(&transfers, 1), paid_total(&transfers, 9)), (850, 100, 50));
+
+    call_as(ADMIN_USER);
+    let payments = get_fee_payments().unwrap();
+    assert_eq!(payments.len(), 2);
+    assert_eq!((payments[1].nft_id, payments[1].recipient.as_str(), payments[1].amount), (nft_id, user(9).as_str(), 50));
+    call_as(1);
+    assert!(get_fee_payments().is_err());
+}
+
+#[test]
+fn royalty_and_fee_can_never_take_the_whole_price() {
+    install();
+    assert!(set_platform_fee(100 - MAX_ROYALTY_PERCENTAGE, Some(user(9))).is_err());
+    assert!(set_platform_fee(5, None).is_err());
+    set_platform_fee(99 - MAX_ROYALTY_PERCENTAGE, Some(user(9))).unwrap();
+    mint_with(1, MintArgs { royalty_percentage: Some(MAX_ROYALTY_PERCENTAGE), ..MintArgs::default() }).unwrap();
+    assert!(mint_with(1, MintArgs { royalty_percentage: Some(MAX_ROYALTY_PERCENTAGE + 1), ..MintArgs::default() }).is_err());
+
+    assert!(validate_payout_config(50, 49).is_ok());
+    assert!(validate_payout_config(50, 50).is_err());
+    assert!(validate_payout_config(51, 0).is_err());
+    assert!(validate_payout_config(0, 99).is_ok());
+    assert!(validate_payout_config(0, 100).is_err());
+    assert!(validate_payout_config(u8::MAX, u8::MAX).is_err());
+    assert_eq!(after_upgrade(|| get_platform_fee().percentage), 99 - MAX_ROYALTY_PERCENTAGE);
+}
+
+// Sales in a time range
+
+fn summary(start_ns: u64, end_ns: u64) -> (u64, u64, u64, Option<u64>, Option<u64>) {
+    let summary = get_sales_in_range(start_ns, end_ns).unwrap();
+    (summary.count, summary.total_volume, summary.average_price, summary.min_price, summary.max_price)
+}
+
+#[test]
+fn sales_summaries_cover_an_inclusive_range() {
+    let start = mock::START_TIME;
+    let first = mint_with(1, MintArgs { price: 100, ..MintArgs::default() }).unwrap();
+    let second = mint_with(1, MintArgs { price: 250, ..MintArgs::default() }).unwrap();
+    let third = mint_with(1, MintArgs { price: 400, ..MintArgs::default() }).unwrap();
+    buy(2, first).unwrap();
+    mock::advance_time(DAY);
+    buy(2, second).unwrap();
+    mock::advance_time(DAY);
+    buy(2, third).unwrap();
+
+    assert_eq!(summary(start, start + DAY), (2, 350, 175, Some(100), Some(250)));
+    assert_eq!(summary(start + DAY, start + 2 * DAY), (2, 650, 325, Some(250), Some(400)));
+    assert_eq!(summary(0, u64::MAX), (3, 750, 250, Some(100), Some(400)));
+    assert_eq!(summary(start + 1, start + DAY - 1), (0, 0, 0, None, None));
+    assert!(get_sales_in_range(start + 1, start).is_err());
+}
+
+// Identity and profiles
+
+#[test]
+fn whoami_echoes_the_caller() {
+    call_as(7);
+    assert_eq!(whoami(), user(7));
+    mock::set_caller(Principal::anonymous());
+    assert_eq!(whoami(), "2vxsx-fae");
+}
+
+#[test]
+fn profiles_round_trip_per_caller() {
+    call_as(1);
+    set_profile("  DJ One ".to_string(), Some(IMAGE.to_string()), "Beats".to_string()).unwrap();
+    assert!(set_profile(" ".to_string(), None, String::new()).is_err());
+    assert!(set_profile("Name".to_string(), Some("nope".to_string()), String::new()).is_err());
+
+    let profile = get_profile(user(1)).unwrap();
+    assert_eq!((profile.display_name.as_str(), profile.avatar_url.as_deref(), profile.bio.as_str()), ("DJ One", Some(IMAGE), "Beats"));
+    assert!(get_profile(user(2)).is_none());
+    assert!(get_profile("not a principal".to_string()).is_none());
+    assert_eq!(after_upgrade(|| get_profile(user(1)).map(|profile| profile.display_name)).as_deref(), Some("DJ One"));
+}
+
+// Storefront by price
+
+#[test]
+fn the_storefront_lists_visible_sales_by_price() {
+    install();
+    let priced = |price| mint_with(1, MintArgs { price, ..MintArgs::default() }).unwrap();
+    let (a, b, c, d) = (priced(300), priced(100), priced(200), priced(100));
+    let unlisted = mint_with(1, MintArgs { price: 50, list_immediately: Some(false), ..MintArgs::default() }).unwrap();
+    let sold = priced(60);
+    let hidden = priced(70);
+    buy(2, sold).unwrap();
+    hide(hidden);
+
+    let ids = |offset, limit, ascending| -> Vec<u64> {
+        get_nfts_for_sale(offset, limit, ascending).iter().map(|nft| nft.id).collect()
+    };
+    assert_eq!(ids(0, 10, true), vec![b, d, c, a]);
+    assert_eq!(ids(0, 10, false), vec![a, c, b, d]);
+    assert_eq!(ids(1, 2, true), vec![d, c]);
+    assert!(ids(4, 10, true).is_empty());
+    assert!(![unlisted, sold, hidden].iter().any(|id| ids(0, 10, true).contains(id)));
+}
+
+// IPFS hash validation
+
+const CID_V1: &str = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+#[test]
+fn ipfs_hashes_must_look_like_a_cid() {
+    assert!(validate_ipfs_hash(&cid(1)).is_ok());
+    assert!(validate_ipfs_hash(CID_V1).is_ok());
+    assert!(validate_ipfs_hash("").is_err());
+    assert!(validate_ipfs_hash("not-a-hash").is_err());
+    // CIDv0 is exactly 46 base58 characters, which excludes 0, O, I and l
+    assert!(validate_ipfs_hash(&cid(1)[..45]).is_err());
+    assert!(validate_ipfs_hash(&format!("{}0", &cid(1)[..45])).is_err());
+    // CIDv1 here is lowercase base32
+    assert!(validate_ipfs_hash(&CID_V1.to_uppercase()).is_err());
+    assert!(validate_ipfs_hash(&CID_V1[..40]).is_err());
+    assert!(validate_ipfs_hash(&format!("baf{}", "a".repeat(MAX_CID_LEN))).is_err());
+}
+
+#[test]
+fn tracks_need_a_valid_hash() {
+    let project_id = project_of(1);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(1, project_id, 11, CID_V1).unwrap();
+    assert!(upload(1, project_id, 12, "").is_err());
+    assert!(upload(1, project_id, 13, "garbage").is_err());
+    assert_eq!(get_project_tracks(project_id).len(), 2);
+}
+
+// Contributions per member
+
+#[test]
+fn contributions_count_tracks_per_uploader() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    join(project_id, 1, 3);
+    join(project_id, 1, 4);
+    let mut track_id = 0;
+    for (uploader, tracks) in [(2, 3), (1, 1), (3, 2)] {
+        for _ in 0..tracks {
+            track_id += 1;
+            upload(uploader, project_id, track_id, &cid(track_id as u8)).unwrap();
+        }
+    }
+    assert_eq!(get_contributor_contributions(project_id), vec![(user(2), 3), (user(3), 2), (user(1), 1)]);
+    assert!(get_contributor_contributions(project_of(5)).is_empty());
+    assert!(get_contributor_contributions(9999).is_empty());
+}
+
+// Typed marketplace errors
+
+#[test]
+fn each_failed_purchase_names_its_reason() {
+    install();
+    let nft_id = mint(1);
+    let unlisted = mint_with(1, MintArgs { list_immediately: Some(false), ..MintArgs::default() }).unwrap();
+    assert_eq!(buy(2, 9999), Err(MarketError::NotFound));
+    assert_eq!(buy(2, unlisted), Err(MarketError::NotForSale));
+    assert_eq!(buy(1, nft_id), Err(MarketError::AlreadyOwner));
+    call_as(2);
+    assert!(matches!(block_on(buy_nft(nft_id, Some(" ".to_string()))), Err(MarketError::InvalidInput(_))));
+    call_as(ADMIN_USER);
+    blacklist_principal(user(3)).unwrap();
+    assert_eq!(buy(3, nft_id), Err(MarketError::Blocked));
+    assert_eq!(MarketError::NotForSale.to_string(), "NFT is not for sale");
+}
+
+#[test]
+fn a_second_buyer_waits_for_the_payment_in_flight() {
+    let nft_id = resale_listing();
+    let second = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let result = second.clone();
+    mock::on_transfer(move |transfer| {
+        if into_settlement(transfer) && result.borrow().is_none() {
+            *result.borrow_mut() = Some(buy(4, nft_id));
+            call_as(3);
+        }
+    });
+    buy(3, nft_id).unwrap();
+    assert_eq!(second.take(), Some(Err(MarketError::PurchaseInProgress)));
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(3));
+}
+
+#[test]
+fn each_failed_price_update_names_its_reason() {
+    install();
+    let nft_id = mint(1);
+    call_as(1);
+    assert!(matches!(update_nft_price(nft_id, 0), Err(MarketError::InvalidPrice(_))));
+    assert_eq!(update_nft_price(9999, 10), Err(MarketError::NotFound));
+    call_as(2);
+    assert!(matches!(update_nft_price(nft_id, 10), Err(MarketError::Unauthorized(_))));
+
+    call_as(ADMIN_USER);
+    set_min_price_increment(Some(MinPriceIncrement::Absolute(50))).unwrap();
+    call_as(1);
+    assert!(matches!(update_nft_price(nft_id, 120), Err(MarketError::InvalidPrice(_))));
+    update_nft_price(nft_id, 150).unwrap();
+}
+
+// Similar NFTs
+
+#[test]
+fn similar_nfts_rank_shared_attributes_then_views() {
+    install();
+    let project_id = project_of(1);
+    let nft = |project_id, category: &str| {
+        mint_with(1, MintArgs { project_id, category: category.to_string(), ..MintArgs::default() }).unwrap()
+    };
+    let target = nft(project_id, "music");
+    let both = nft(project_id, "music");
+    let quiet_category = nft(0, "music");
+    let viewed_category = nft(0, "music");
+    let same_project = nft(project_id, "beat");
+    nft(0, "beat");
+    let hidden = nft(project_id, "music");
+    view_as(2, viewed_category);
+    hide(hidden);
+
+    let ids = |nft_id| get_similar_nfts(nft_id, 10).iter().map(|nft| nft.id).collect::<Vec<_>>();
+    assert_eq!(ids(target), vec![both, viewed_category, quiet_category, same_project]);
+    assert_eq!(get_similar_nfts(target, 2).len(), 2);
+    // Without a project only the category counts
+    assert_eq!(ids(quiet_category), vec![viewed_category, target, both]);
+    assert!(ids(9999).is_empty());
+}
+
+// Minimum resale price
+
+#[test]
+fn owners_cannot_relist_below_the_creators_floor() {
+    let nft_id = mint_with(1, MintArgs { price: 500, min_resale_price: Some(400), ..MintArgs::default() }).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().min_resale_price, Some(400));
+    // The creator may go lower
+    call_as(1);
+    update_nft_price(nft_id, 300).unwrap();
+    buy(2, nft_id).unwrap();
+
+    call_as(2);
+    assert!(matches!(relist_nft(nft_id, 399), Err(MarketError::InvalidPrice(_))));
+    assert!(matches!(update_nft_price(nft_id, 399), Err(MarketError::InvalidPrice(_))));
+    relist(2, nft_id, 400);
+    buy(3, nft_id).unwrap();
+}
+
+#[test]
+fn the_floor_only_binds_owners_other_than_the_creator() {
+    let nft_id = mint_with(1, MintArgs { price: 500, min_resale_price: Some(400), ..MintArgs::default() }).unwrap();
+    let mut nft = get_nft(nft_id).unwrap();
+    assert!(check_resale_floor(&nft, &user(1), 1).is_ok());
+    assert!(check_resale_floor(&nft, &user(2), 400).is_ok());
+    assert!(check_resale_floor(&nft, &user(2), 399).is_err());
+
+    // A listing that slipped under the floor still can't be bought
+    nft.current_owner = user(2);
+    nft.price = 100;
+    assert!(matches!(calculate_purchase(&nft, &user(3)), Err(MarketError::InvalidPrice(_))));
+    nft.min_resale_price = None;
+    assert!(check_resale_floor(&nft, &user(2), 1).is_ok());
+}
+
+// Track plays
+
+#[test]
+fn plays_are_counted_per_track() {
+    let project_id = project_of(1);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+    upload(1, project_id, 11, &cid(2)).unwrap();
+    call_as(2);
+    assert_eq!(record_track_play(project_id, 11), Ok(1));
+    assert_eq!(record_track_play(project_id, 11), Ok(2));
+    assert_eq!(record_track_play(project_id, 10), Ok(1));
+    assert!(record_track_play(project_id, 99).is_err());
+    assert!(record_track_play(9999, 10).is_err());
+
+    assert_eq!(get_project_play_stats(project_id), vec![(10, 1), (11, 2)]);
+    assert!(get_project_play_stats(9999).is_empty());
+    assert_eq!(after_upgrade(move || get_project_play_stats(project_id)), vec![(10, 1), (11, 2)]);
+}
+
+// Minting from a track
+
+fn mint_track(caller: u8, project_id: u64, track_id: u64) -> Result<u64, String> {
+    call_as(caller);
+    block_on(mint_nft_from_track(
+        project_id,
+        track_id,
+        "Single".to_string(),
+        String::new(),
+        IMAGE.to_string(),
+        250,
+        "music".to_string(),
+    ))
+}
+
+#[test]
+fn a_track_mints_a_listed_nft_linked_to_its_hash() {
+    install();
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    upload(2, project_id, 10, &cid(1)).unwrap();
+
+    let nft_id = mint_track(2, project_id, 10).unwrap();
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!((nft.creator, nft.current_owner), (user(2), user(2)));
+    assert_eq!((nft.project_id, nft.price, nft.is_for_sale), (project_id, 250, true));
+    assert_eq!(nft.ipfs_hash, Some(cid(1)));
+    assert_eq!(get_project_nfts(project_id).len(), 1);
+}
+
+#[test]
+fn minting_from_a_track_needs_a_member_and_an_existing_track() {
+    install();
+    let project_id = project_of(1);
+    upload(1, project_id, 10, &cid(1)).unwrap();
+
+    assert_eq!(mint_track(1, project_id, 99), Err("Track not found".to_string()));
+    assert!(mint_track(1, 9999, 10).is_err());
+    assert!(mint_track(3, project_id, 10).unwrap_err().contains("owner or a contributor"));
+    assert_eq!(get_marketplace_stats().total_nfts, 0);
+}
+
+// Project NFTs
+
+#[test]
+fn project_nfts_come_only_from_that_project() {
+    install();
+    let first = project_of(1);
+    let second = project_of(2);
+    let in_first: Vec<u64> = (0..3).map(|_| mint_with(1, MintArgs { project_id: first, ..Default::default() }).unwrap()).collect();
+    let in_second = mint_with(2, MintArgs { project_id: second, ..Default::default() }).unwrap();
+    mint(1);
+
+    let ids = |project_id| get_project_nfts(project_id).into_iter().map(|nft| nft.id).collect::<Vec<_>>();
+    assert_eq!(ids(first), in_first);
+    assert_eq!(ids(second), vec![in_second]);
+    assert_eq!((get_project_nft_count(first), get_project_nft_count(second)), (3, 1));
+    assert!(ids(9999).is_empty());
+
+    hide(in_first[1]);
+    assert_eq!(ids(first), vec![in_first[0], in_first[2]]);
+    assert_eq!(get_project_nft_count(first), 2);
+}
+
+// IPFS metadata
+
+fn gateway_headers(headers: &[(&str, &str)]) -> HttpResponse {
+    let headers = headers.iter().map(|(name, value)| HttpHeader { name: name.to_string(), value: value.to_string() }).collect();
+    HttpResponse { status: Nat::from(200u16), headers, body: vec![] }
+}
+
+#[test]
+fn gateway_headers_are_parsed_case_insensitively() {
+    let response = gateway_headers(&[("Content-Type", " audio/mpeg "), ("CONTENT-LENGTH", "4096")]);
+    let meta = ipfs_meta_from_headers(&response.headers);
+    assert_eq!(meta.content_type.as_deref(), Some("audio/mpeg"));
+    assert_eq!(meta.content_length, Some(4096));
+
+    let meta = ipfs_meta_from_headers(&gateway_headers(&[("content-length", "unknown")]).headers);
+    assert_eq!((meta.content_type, meta.content_length), (None, None));
+}
+
+#[test]
+fn the_transform_keeps_only_the_headers_that_are_read() {
+    let response = gateway_headers(&[("Content-Type", "image/png"), ("Date", "now"), ("Content-Length", "12")]);
+    let kept = transform_ipfs_headers(TransformArgs { response, context: vec![] }).headers;
+    let names: Vec<&str> = kept.iter().map(|header| header.name.as_str()).collect();
+    assert_eq!(names, vec!["content-type", "content-length"]);
+}
+
+#[test]
+fn metadata_is_fetched_from_the_gateway() {
+    install();
+    mock::respond_with(|_| gateway_headers(&[("content-type", "audio/wav"), ("content-length", "1048576")]));
+    call_as(1);
+    let meta = block_on(get_ipfs_metadata(format!(" {} ", cid(1)))).unwrap();
+    assert_eq!(meta.content_type.as_deref(), Some("audio/wav"));
+    assert_eq!(meta.content_length, Some(1048576));
+
+    let requests = mock::take_http_requests();
+    assert_eq!(requests[0].url, format!("{}{}", get_ipfs_gateway(), cid(1)));
+    assert!(matches!(requests[0].method, HttpMethod::HEAD));
+
+    mock::respond_with(|_| http_response(404, ""));
+    assert!(block_on(get_ipfs_metadata(cid(1))).err().unwrap().contains("404"));
+}
+
+#[test]
+fn metadata_lookups_validate_the_hash_and_are_rate_limited() {
+    install();
+    mock::respond_with(|_| gateway_headers(&[]));
+    call_as(1);
+    assert!(block_on(get_ipfs_metadata("../admin".to_string())).is_err());
+    assert!(mock::take_http_requests().is_empty());
+
+    for _ in 0..5 {
+        block_on(get_ipfs_metadata(cid(1))).unwrap();
+    }
+    assert!(block_on(get_ipfs_metadata(cid(1))).err().unwrap().contains("rate limit"));
+    assert_eq!(mock::take_http_requests().len(), 5);
+}
+
+// IPFS gateway
+
+#[test]
+fn the_gateway_defaults_to_pinata_and_is_admin_set() {
+    install();
+    assert_eq!(get_ipfs_gateway(), "https://gateway.pinata.cloud/ipfs/");
+    call_as(1);
+    assert!(set_ipfs_gateway("https://ipfs.io/ipfs/".to_string()).is_err());
+
+    call_as(ADMIN_USER);
+    set_ipfs_gateway(" https://ipfs.io/ipfs/ ".to_string()).unwrap();
+    assert_eq!(get_ipfs_gateway(), "https://ipfs.io/ipfs/");
+    assert_eq!(after_upgrade(get_ipfs_gateway), "https://ipfs.io/ipfs/");
+}
+
+#[test]
+fn gateway_urls_need_https_and_a_trailing_slash() {
+    install();
+    for url in ["https://ipfs.io/ipfs", "http://ipfs.io/ipfs/", "https://", "https://ipfs io/"] {
+        assert!(set_ipfs_gateway(url.to_string()).is_err(), "{}", url);
+    }
+    assert!(set_ipfs_gateway("https://ipfs.io/ipfs".to_string()).unwrap_err().contains("slash"));
+    assert_eq!(get_ipfs_gateway(), "https://gateway.pinata.cloud/ipfs/");
+}
+
+// Bulk contributors
+
+fn members(project_id: u64) -> Vec<String> {
+    get_project(project_id).unwrap().contributors
+}
+
+#[test]
+fn a_batch_adds_only_new_contributors_once() {
+    let project_id = project_of(1);
+    assert_eq!(add_contributors(project_id, vec![user(2), user(3)]), Ok(2));
+    assert_eq!(members(project_id), vec![user(2), user(3)]);
+
+    // The owner, existing members and repeats within the batch are skipped
+    let batch = vec![user(1), user(3), user(4), format!(" {} ", user(4)), user(5), String::new()];
+    assert_eq!(add_contributors(project_id, batch), Ok(2));
+    assert_eq!(members(project_id), vec![user(2), user(3), user(4), user(5)]);
+    assert_eq!(add_contributors(project_id, vec![]), Ok(0));
+}
+
+#[test]
+fn batches_are_owner_only_capped_and_all_or_nothing() {
+    let project_id = project_of(1);
+    call_as(2);
+    assert!(add_contributors(project_id, vec![user(3)]).unwrap_err().contains("owner"));
+    assert!(add_contributors(9999, vec![user(3)]).is_err());
+
+    call_as(1);
+    assert!(add_contributors(project_id, vec![user(3), "not a principal".to_string()]).is_err());
+    assert!(add_contributors(project_id, vec![user(3); 101]).unwrap_err().contains("at once"));
+    assert!(members(project_id).is_empty());
+}
+
+// Portfolio value
+
+#[test]
+fn portfolios_mix_asking_and_last_sale_prices() {
+    let kept = mint_with(1, MintArgs { price: 100, ..Default::default() }).unwrap();
+    let relisted = mint_with(1, MintArgs { price: 200, ..Default::default() }).unwrap();
+    let gifted = mint(1);
+    buy(2, kept).unwrap();
+    buy(2, relisted).unwrap();
+    relist(2, relisted, 500);
+    call_as(1);
+    set_nft_for_sale(gifted, false).unwrap();
+    transfer_nft(gifted, user(2)).unwrap();
+    mint_with(2, MintArgs { price: 700, list_immediately: Some(false), ..Default::default() }).unwrap();
+
+    // 100 as last sold, 500 as asked; the gift and the unlisted mint never sold
+    assert_eq!(get_portfolio_value(user(2)), 600);
+    assert_eq!(get_portfolio_value(user(1)), 0);
+    assert_eq!(get_portfolio_value("not a principal".to_string()), 0);
+}
+
+// Mint ids
+
+fn next_nft_id() -> u64 {
+    NEXT_NFT_ID.with(|id| *id.borrow())
+}
+
+#[test]
+fn rejected_mints_leave_no_gap_in_the_ids() {
+    install();
+    let rejected = [
+        MintArgs { category: "unknown".to_string(), ..Default::default() },
+        MintArgs { price: 0, ..Default::default() },
+        MintArgs { royalty_percentage: Some(101), ..Default::default() },
+        MintArgs { name: String::new(), ..Default::default() },
+        MintArgs { ipfs_hash: Some("not a cid".to_string()), ..Default::default() },
+    ];
+    for args in rejected {
+        assert!(mint_with(1, args).is_err());
+    }
+    assert_eq!(next_nft_id(), 1);
+
+    // A mint fee that can't be collected is refused after every other check passed
+    use_ledger();
+    call_as(ADMIN_USER);
+    set_mint_fee(50, Some(user(9))).unwrap();
+    mock::refuse_transfers(|_| true);
+    assert!(mint_with(1, MintArgs::default()).is_err());
+    assert_eq!(next_nft_id(), 1);
+
+    mock::accept_transfers();
+    assert_eq!((mint(1), mint(2)), (1, 2));
+    assert_eq!(next_nft_id(), 3);
+}
+
+// Project by track
+
+#[test]
+fn a_track_id_finds_its_project() {
+    let first = project_of(1);
+    let second = project_of(2);
+    upload(1, first, 10, &cid(1)).unwrap();
+    upload(2, second, 20, &cid(2)).unwrap();
+    assert_eq!(get_project_by_track(20).map(|project| project.id), Some(second));
+    assert_eq!(get_project_by_track(10).map(|project| project.id), Some(first));
+    assert!(get_project_by_track(99).is_none());
+
+    // A track id held by several projects resolves to the lowest project id
+    upload(2, second, 10, &cid(3)).unwrap();
+    assert_eq!(get_project_by_track(10).map(|project| project.id), Some(first));
+}
+
+// Project archiving
+
+#[test]
+fn archiving_moves_a_project_out_of_the_default_listing() {
+    let kept = project_of(1);
+    let archived = project_of(1);
+    let ids = |projects: Vec<MusicProject>| projects.into_iter().map(|project| project.id).collect::<Vec<_>>();
+    assert_eq!(archive_project(archived), Ok(format!("Project {} archived", archived)));
+    assert_eq!(ids(list_projects()), vec![kept]);
+    assert_eq!(ids(list_archived_projects()), vec![archived]);
+    assert!(get_project(archived).unwrap().is_archived);
+    assert_eq!(after_upgrade(list_archived_projects).len(), 1);
+
+    unarchive_project(archived).unwrap();
+    assert_eq!(ids(list_projects()), vec![kept, archived]);
+    assert!(list_archived_projects().is_empty());
+}
+
+#[test]
+fn only_the_owner_can_archive_a_project() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    call_as(2);
+    assert!(archive_project(project_id).unwrap_err().contains("owner"));
+    assert!(archive_project(9999).is_err());
+    assert!(list_archived_projects().is_empty());
+}
+
+// Royalty statuses
+
+fn royalty_status(nft_id: u64) -> String {
+    get_nft_royalty_payments(nft_id).last().unwrap().status.clone()
+}
+
+#[test]
+fn a_royalty_is_pending_until_its_transfer_lands() {
+    let nft_id = resale_listing();
+    let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let statuses = seen.clone();
+    mock::on_transfer(move |transfer| {
+        if paid_to(transfer, 1) {
+            statuses.borrow_mut().extend(get_pending_royalties(user(1)).into_iter().map(|p| p.status));
+        }
+    });
+
+    buy(3, nft_id).unwrap();
+    assert_eq!(*seen.borrow(), vec!["pending".to_string()]);
+    assert_eq!(royalty_status(nft_id), "paid");
+    assert!(get_pending_royalties(user(1)).is_empty());
+}
+
+#[test]
+fn a_failed_royalty_is_retried_by_its_recipient_or_the_admin() {
+    let nft_id = resale_listing();
+    mock::refuse_transfers(|transfer| paid_to(transfer, 1));
+    buy(3, nft_id).unwrap();
+    let pending = get_pending_royalties(user(1));
+    assert_eq!((pending.len(), pending[0].status.as_str(), pending[0].amount), (1, "failed", 100));
+    let transaction_id = pending[0].transaction_id;
+
+    call_as(4);
+    assert!(matches!(block_on(retry_failed_royalty(transaction_id)), Err(MarketError::Unauthorized(_))));
+    call_as(1);
+    assert!(matches!(block_on(retry_failed_royalty(transaction_id)), Err(MarketError::PaymentFailed(_))));
+    assert_eq!(royalty_status(nft_id), "failed");
+    assert!(matches!(block_on(retry_failed_royalty(9999)), Err(MarketError::InvalidInput(_))));
+
+    mock::accept_transfers();
+    mock::take_transfers();
+    assert_eq!(block_on(retry_failed_royalty(transaction_id)), Ok(format!("Royalty of 100 paid to {}", user(1))));
+    assert!(paid_to(&mock::take_transfers()[0], 1));
+    assert_eq!(royalty_status(nft_id), "paid");
+    assert!(block_on(retry_failed_royalty(transaction_id)).unwrap_err().to_string().contains("not failed"));
+}
+
+// Bounded sale history
+
+#[test]
+fn inline_history_keeps_the_latest_entries_and_the_ledger_keeps_all() {
+    let nft_id = mint(1);
+    let other = mint(1);
+    for round in 0..MAX_INLINE_HISTORY as u8 + 10 {
+        let (from, to) = if round % 2 == 0 { (1, 2) } else { (2, 1) };
+        call_as(from);
+        transfer_nft(nft_id, user(to)).unwrap();
+    }
+
+    let full = get_full_nft_history(nft_id);
+    assert_eq!(full.len(), MAX_INLINE_HISTORY + 11);
+    assert!(full.iter().all(|tx| tx.nft_id == nft_id));
+    assert!(full.windows(2).all(|pair| pair[0].id < pair[1].id));
+    assert_eq!(full[0].transaction_type, "mint");
+
+    let inline = get_nft(nft_id).unwrap().sale_history;
+    assert_eq!(inline.len(), MAX_INLINE_HISTORY);
+    let latest: Vec<u64> = full[full.len() - MAX_INLINE_HISTORY..].iter().map(|tx| tx.id).collect();
+    assert_eq!(inline.iter().map(|tx| tx.id).collect::<Vec<_>>(), latest);
+
+    assert_eq!(get_full_nft_history(other).len(), 1);
+    assert!(get_full_nft_history(9999).is_empty());
+}
+
+// Health
+
+#[test]
+fn health_counts_the_seeded_state() {
+    populate();
+    mock::advance_time(DAY);
+    let report = health();
+    assert_eq!((report.project_count, report.nft_count), (1, 2));
+    assert_eq!(report.transaction_count, 4);
+    assert_eq!(report.royalty_payment_count, 1);
+    assert_eq!(report.cycles_balance, mock::CYCLES_BALANCE);
+    assert_eq!(report.stable_memory_bytes, mock::STABLE_PAGES * 65536);
+    assert_eq!(report.uptime_ns, DAY);
+}
+
+// Principal normalization
+
+#[test]
+fn principals_are_trimmed_and_canonicalized() {
+    assert_eq!(normalize_principal("Owner", &format!("  {}\n", user(1))), Ok(user(1)));
+    assert_eq!(normalize_principal("Owner", &user(1).to_uppercase()), Ok(user(1)));
+    assert_eq!(normalize_principal("Owner", "aaaaa-aa"), Ok("aaaaa-aa".to_string()));
+    assert_eq!(normalize_principal("Owner", ""), Err("Owner is not a valid principal".to_string()));
+    assert_eq!(normalize_principal("Buyer", "abc"), Err("Buyer is not a valid principal".to_string()));
+    // One changed character breaks the checksum
+    let mangled = format!("{}{}", if user(1).starts_with('a') { 'b' } else { 'a' }, &user(1)[1..]);
+    assert!(normalize_principal("Owner", &mangled).is_err());
+}
+
+#[test]
+fn padded_principals_match_the_canonical_owner() {
+    call_as(1);
+    let project_id = create_project("Project".to_string(), String::new(), format!(" {} ", user(1))).unwrap();
+    assert_eq!(get_project(project_id).unwrap().owner, user(1));
+    assert!(create_project("Project".to_string(), String::new(), "owner".to_string()).is_err());
+
+    let nft_id = mint_with(1, MintArgs::default()).unwrap();
+    call_as(1);
+    transfer_nft(nft_id, format!("{}  ", user(2))).unwrap();
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(2));
+    assert!(transfer_nft(mint(1), "someone".to_string()).is_err());
+
+    call_as(1);
+    add_contributors(project_id, vec![format!("\t{}", user(3))]).unwrap();
+    assert!(upload(3, project_id, 10, &cid(1)).is_ok());
+}
+
+// Global activity
+
+#[test]
+fn activity_merges_every_kind_newest_first() {
+    install();
+    let first = mint_with(1, MintArgs { name: "First".to_string(), ..Default::default() }).unwrap();
+    mock::advance_time(DAY);
+    let second = mint_with(1, MintArgs { name: "Second".to_string(), ..Default::default() }).unwrap();
+    mock::advance_time(DAY);
+    buy(2, first).unwrap();
+    mock::advance_time(DAY);
+    call_as(1);
+    transfer_nft(second, user(3)).unwrap();
+
+    let feed: Vec<(String, Option<String>)> = get_global_activity(10)
+        .into_iter()
+        .map(|item| (item.transaction.transaction_type, item.nft_name))
+        .collect();
+    let entry = |kind: &str, name: &str| (kind.to_string(), Some(name.to_string()));
+    assert_eq!(
+        feed,
+        vec![entry("transfer", "Second"), entry("sale", "First"), entry("mint", "Second"), entry("mint", "First")]
+    );
+    assert_eq!(get_global_activity(2).len(), 2);
+
+    hide(second);
+    assert!(get_global_activity(10).iter().all(|item| item.transaction.nft_id == first));
+}
+
+#[test]
+fn activity_for_a_missing_nft_has_no_name() {
+    let nft_id = mint(1);
+    let mut orphan = get_full_nft_history(nft_id).remove(0);
+    orphan.nft_id = 9999;
+    orphan.id += 1;
+    orphan.timestamp += 1;
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().push(orphan));
+
+    let feed = get_global_activity(10);
+    assert_eq!((feed[0].transaction.nft_id, feed[0].nft_name.as_deref()), (9999, None));
+    assert_eq!(feed[1].nft_name.as_deref(), Some("Song"));
+}
+
+// Relisting
+
+#[test]
+fn relisting_sets_the_price_and_lists_in_one_call() {
+    let nft_id = mint(1);
+    buy(2, nft_id).unwrap();
+    assert!(!get_nft(nft_id).unwrap().is_for_sale);
+
+    call_as(2);
+    assert_eq!(relist_nft(nft_id, 450), Ok(format!("NFT {} listed for sale at 450", nft_id)));
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!((nft.price, nft.is_for_sale), (450, true));
+    assert_eq!(get_marketplace_stats_cached().nfts_for_sale, 1);
+}
+
+#[test]
+fn relisting_needs_the_owner_and_a_price() {
+    let nft_id = mint(1);
+    call_as(1);
+    assert!(matches!(relist_nft(nft_id, 0), Err(MarketError::InvalidPrice(_))));
+    call_as(2);
+    assert!(matches!(relist_nft(nft_id, 500), Err(MarketError::Unauthorized(_))));
+    assert_eq!(relist_nft(9999, 500), Err(MarketError::NotFound));
+    assert_eq!(get_nft(nft_id).unwrap().price, 100);
+}
+
+// Track limit
+
+#[test]
+fn projects_take_tracks_up_to_the_limit() {
+    install();
+    assert_eq!(get_max_tracks_per_project(), 500);
+    call_as(1);
+    assert!(set_max_tracks_per_project(3).is_err());
+    call_as(ADMIN_USER);
+    assert!(set_max_tracks_per_project(0).is_err());
+    set_max_tracks_per_project(3).unwrap();
+    assert_eq!(get_max_tracks_per_project(), 3);
+
+    let project_id = project_of(1);
+    for track_id in 1..=3 {
+        upload(1, project_id, track_id, &cid(track_id as u8)).unwrap();
+    }
+    assert_eq!(upload(1, project_id, 4, &cid(4)), Err("Project already has the maximum of 3 tracks".to_string()));
+    assert_eq!(get_project_tracks(project_id).len(), 3);
+    assert!(upload(1, project_of(1), 4, &cid(4)).is_ok());
+    assert_eq!(after_upgrade(get_max_tracks_per_project), 3);
+}
+
+// Category rank
+
+#[test]
+fn category_rank_orders_by_views_with_ties_to_the_lower_id() {
+    install();
+    let ids: Vec<u64> = (0..4).map(|_| mint_in(1, "music").unwrap()).collect();
+    let other = mint_in(1, "beat").unwrap();
+    for (nft_id, views) in [(ids[0], 1), (ids[1], 3), (ids[2], 2), (ids[3], 2), (other, 9)] {
+        for viewer in 0..views {
+            view_as(10 + viewer, nft_id);
+        }
+    }
+
+    // Views rank them ids[1], ids[2], ids[3], ids[0]; ids[2] and ids[3] tie
+    assert_eq!(get_nft_category_rank(ids[2]), Some((2, 4)));
+    assert_eq!(get_nft_category_rank(ids[3]), Some((3, 4)));
+    assert_eq!(get_nft_category_rank(ids[1]), Some((1, 4)));
+    assert_eq!(get_nft_category_rank(other), Some((1, 1)));
+    assert!(get_nft_category_rank(9999).is_none());
+
+    hide(ids[1]);
+    assert_eq!(get_nft_category_rank(ids[2]), Some((1, 3)));
+    assert!(get_nft_category_rank(ids[1]).is_none());
+}
+
+// Cursor paging
+
+fn page_ids(page: &NftPage) -> Vec<u64> {
+    page.nfts.iter().map(|nft| nft.id).collect()
+}
+
+#[test]
+fn cursor_pages_walk_every_visible_nft_once() {
+    install();
+    let minted: Vec<u64> = (0..8).map(|_| mint(1)).collect();
+    hide(minted[4]);
+
+    let mut seen = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let page = list_nfts_after(cursor, 3);
+        assert!(page.nfts.len() <= 3 && !page.truncated);
+        seen.extend(page_ids(&page));
+        pages += 1;
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let visible: Vec<u64> = minted.iter().copied().filter(|&id| id != minted[4]).collect();
+    assert_eq!(seen, visible);
+    assert_eq!(pages, 3);
+}
+
+#[test]
+fn cursor_pages_clamp_the_limit_and_end_cleanly() {
+    let minted: Vec<u64> = (0..3).map(|_| mint(1)).collect();
+    assert_eq!(page_ids(&list_nfts_after(None, 0)), vec![minted[0]]);
+    let page = list_nfts_after(Some(minted[0]), 1000);
+    assert_eq!((page_ids(&page), page.next_cursor), (vec![minted[1], minted[2]], None));
+    let past_the_end = list_nfts_after(Some(minted[2]), 10);
+    assert!(past_the_end.nfts.is_empty() && past_the_end.next_cursor.is_none());
+}
+
+// Ordered stores
+
+#[test]
+fn listings_come_back_in_ascending_id_order() {
+    install();
+    for owner in [3, 1, 2] {
+        project_of(owner);
+    }
+    for creator in [2, 1, 3, 1] {
+        mint(creator);
+    }
+    call_as(ADMIN_USER);
+    let mut snapshot = export_state().unwrap();
+    snapshot.projects.reverse();
+    snapshot.nfts.reverse();
+
+    let (projects, nfts) = import_fresh(snapshot, || {
+        let projects: Vec<u64> = list_projects().into_iter().map(|project| project.id).collect();
+        let nfts: Vec<u64> = list_nfts().into_iter().map(|nft| nft.id).collect();
+        (projects, nfts)
+    });
+    assert_eq!(projects, vec![1, 2, 3]);
+    assert_eq!(nfts, vec![1, 2, 3, 4]);
+    assert_eq!(after_upgrade(|| list_nfts().into_iter().map(|nft| nft.id).collect::<Vec<_>>()), vec![1, 2, 3, 4]);
+}
+
+// Unsold inventory
+
+#[test]
+fn unsold_inventory_is_what_never_changed_hands() {
+    install();
+    let unsold: Vec<u64> = (0..2).map(|_| mint(1)).collect();
+    let sold = mint(1);
+    let returned = mint(1);
+    let hidden = mint(1);
+    mint(2);
+    hide(hidden);
+    buy(2, sold).unwrap();
+    call_as(1);
+    transfer_nft(returned, user(3)).unwrap();
+    call_as(3);
+    transfer_nft(returned, user(1)).unwrap();
+
+    let ids: Vec<u64> = get_creator_unsold_nfts(format!(" {} ", user(1))).into_iter().map(|nft| nft.id).collect();
+    assert_eq!(ids, unsold);
+    assert!(get_creator_unsold_nfts(user(3)).is_empty());
+    assert!(get_creator_unsold_nfts("creator".to_string()).is_empty());
+}
+
+// Payout validation
+
+#[test]
+fn payout_errors_name_the_constraint_that_failed() {
+    assert_eq!(validate_payout_config(10, 5), Ok(()));
+    assert_eq!(validate_payout_config(51, 0), Err("Royalty of 51% exceeds the maximum of 50%".to_string()));
+    assert_eq!(
+        validate_payout_config(50, 50),
+        Err("A 50% royalty plus the 50% platform fee would leave the seller nothing".to_string())
+    );
+}
+
+#[test]
+fn payouts_are_validated_at_mint_and_on_every_change() {
+    install();
+    assert!(mint_with(1, MintArgs { royalty_percentage: Some(51), ..Default::default() }).unwrap_err().contains("maximum"));
+
+    call_as(ADMIN_USER);
+    assert!(set_default_royalty(51).unwrap_err().contains("maximum"));
+    let error = set_platform_fee(50, Some(user(9))).unwrap_err();
+    assert!(error.contains("leave the seller nothing") && error.contains("must be below 50%"));
+    set_platform_fee(49, Some(user(9))).unwrap();
+    set_default_royalty(50).unwrap();
+    assert!(mint(1) > 0);
+}
+
+// Time on market
+
+#[test]
+fn time_to_sale_runs_from_mint_to_the_first_sale() {
+    assert!(get_average_time_to_sale().is_none());
+    let quick = mint(1);
+    let slow = mint(1);
+    let unsold = mint(1);
+    mock::advance_time(DAY);
+    buy(2, quick).unwrap();
+    mock::advance_time(2 * DAY);
+    buy(3, slow).unwrap();
+    // A resale doesn't move the first sale
+    relist(3, slow, 200);
+    mock::advance_time(DAY);
+    buy(4, slow).unwrap();
+
+    assert_eq!(get_nft_time_to_first_sale(quick), Some(DAY));
+    assert_eq!(get_nft_time_to_first_sale(slow), Some(3 * DAY));
+    assert_eq!(get_nft_time_to_first_sale(unsold), None);
+    assert_eq!(get_nft_time_to_first_sale(9999), None);
+    assert_eq!(get_average_time_to_sale(), Some(2 * DAY));
+}
+
+// Purchase idempotency
+
+fn buy_keyed(buyer: u8, nft_id: u64, key: &str) -> Result<String, MarketError> {
+    call_as(buyer);
+    block_on(buy_nft(nft_id, Some(key.to_string())))
+}
+
+#[test]
+fn a_retried_key_replays_the_original_purchase() {
+    let nft_id = mint(1);
+    let first = buy_keyed(2, nft_id, "retry-1").unwrap();
+    assert_eq!(buy_keyed(2, nft_id, " retry-1 "), Ok(first));
+    assert_eq!(get_full_nft_history(nft_id).len(), 2);
+
+    // The key is the buyer's own; anyone else retrying it runs a purchase of their own
+    assert_eq!(buy_keyed(3, nft_id, "retry-1"), Err(MarketError::NotForSale));
+    assert!(matches!(buy_keyed(2, mint(1), "retry-1"), Err(MarketError::InvalidInput(_))));
+}
+
+#[test]
+fn a_new_key_or_an_expired_one_buys_again() {
+    let nft_id = mint(1);
+    buy_keyed(2, nft_id, "first").unwrap();
+    relist(2, nft_id, 200);
+    assert_eq!(buy_keyed(2, nft_id, "second"), Err(MarketError::AlreadyOwner));
+    let other = mint(1);
+    assert!(buy_keyed(2, other, "second").is_ok());
+
+    mock::advance_time(DAY);
+    assert!(buy_keyed(2, mint(1), "first").is_ok());
+}
+
+#[test]
+fn failed_purchases_are_not_remembered() {
+    let nft_id = mint_with(1, MintArgs { list_immediately: Some(false), ..Default::default() }).unwrap();
+    assert_eq!(buy_keyed(2, nft_id, "key"), Err(MarketError::NotForSale));
+    call_as(1);
+    set_nft_for_sale(nft_id, true).unwrap();
+    assert!(buy_keyed(2, nft_id, "key").is_ok());
+    assert_eq!(get_nft(nft_id).unwrap().current_owner, user(2));
+}
+
+// Collaborator network
+
+#[test]
+fn the_network_counts_shared_projects_per_collaborator() {
+    let first = project_of(1);
+    add_contributors(first, vec![user(2), user(3)]).unwrap();
+    let second = project_of(2);
+    add_contributors(second, vec![user(1), user(4)]).unwrap();
+    let unrelated = project_of(3);
+    add_contributors(unrelated, vec![user(4), user(5)]).unwrap();
+    let fourth = project_of(1);
+    add_contributors(fourth, vec![user(3)]).unwrap();
+
+    // Equal counts are ordered by principal text
+    let by_text = |mut entries: Vec<(String, u64)>| {
+        entries.sort();
+        entries
+    };
+    let mut expected = by_text(vec![(user(2), 2), (user(3), 2)]);
+    expected.push((user(4), 1));
+    assert_eq!(get_collaborator_network(format!(" {} ", user(1))), expected);
+    assert_eq!(get_collaborator_network(user(5)), by_text(vec![(user(3), 1), (user(4), 1)]));
+    assert!(get_collaborator_network(user(6)).is_empty());
+    assert!(get_collaborator_network("user".to_string()).is_empty());
+}
+
+// View dedup window
+
+#[test]
+fn a_repeat_view_inside_the_window_counts_once() {
+    let nft_id = mint(1);
+    call_as(2);
+    assert_eq!(record_nft_view(nft_id), Ok(1));
+    mock::advance_time(DEFAULT_VIEW_DEDUP_WINDOW_NS - 1);
+    assert_eq!(record_nft_view(nft_id), Ok(1));
+    call_as(3);
+    assert_eq!(record_nft_view(nft_id), Ok(2));
+    mock::advance_time(1);
+    call_as(2);
+    assert_eq!(record_nft_view(nft_id), Ok(3));
+}
+
+#[test]
+fn the_dedup_window_is_admin_set_and_can_be_turned_off() {
+    install();
+    assert_eq!(get_view_dedup_window(), DEFAULT_VIEW_DEDUP_WINDOW_NS);
+    call_as(1);
+    assert!(set_view_dedup_window(0).is_err());
+
+    call_as(ADMIN_USER);
+    set_view_dedup_window(0).unwrap();
+    let nft_id = mint(1);
+    call_as(2);
+    record_nft_view(nft_id).unwrap();
+    assert_eq!(record_nft_view(nft_id), Ok(2));
+    assert_eq!(after_upgrade(get_view_dedup_window), 0);
+}
+
+// Category floors
+
+#[test]
+fn floors_are_the_cheapest_listing_per_category() {
+    install();
+    let priced = |category: &str, price| mint_with(1, MintArgs { category: category.to_string(), price, ..Default::default() }).unwrap();
+    priced("music", 300);
+    let cheapest_music = priced("music", 120);
+    priced("beat", 900);
+    let unlisted_beat = mint_with(1, MintArgs { category: "beat".to_string(), price: 50, list_immediately: Some(false), ..Default::default() }).unwrap();
+    let cheapest_beat = priced("beat", 400);
+
+    assert_eq!(get_category_floor_prices(), vec![("beat".to_string(), 400), ("music".to_string(), 120)]);
+    assert_eq!(get_category_floor(" Music ".to_string()), Some(120));
+    assert_eq!(get_category_floor("video".to_string()), None);
+
+    // Selling or hiding the floor NFT raises the floor
+    buy(2, cheapest_music).unwrap();
+    hide(cheapest_beat);
+    assert_eq!(get_category_floor_prices(), vec![("beat".to_string(), 900), ("music".to_string(), 300)]);
+    assert!(get_nft(unlisted_beat).is_some_and(|nft| !nft.is_for_sale));
+}
+
+// Provenance
+
+fn corrupt_transaction(transaction_id: u64, corrupt: impl FnOnce(&mut Transaction)) {
+    TRANSACTIONS.with(|transactions| {
+        corrupt(transactions.borrow_mut().iter_mut().find(|tx| tx.id == transaction_id).unwrap());
+    });
+}
+
+#[test]
+fn a_clean_chain_verifies() {
+    let nft_id = mint(1);
+    buy(2, nft_id).unwrap();
+    call_as(2);
+    transfer_nft(nft_id, user(3)).unwrap();
+    let report = verify_nft_provenance(nft_id).unwrap();
+    assert!(report.is_consistent, "{:?}", report.issues);
+    assert_eq!(report.transactions_checked, 3);
+    assert!(verify_nft_provenance(9999).is_none());
+}
+
+#[test]
+fn corrupted_chains_flag_each_break() {
+    let nft_id = mint(1);
+    buy(2, nft_id).unwrap();
+    call_as(2);
+    transfer_nft(nft_id, user(3)).unwrap();
+    let ids: Vec<u64> = get_full_nft_history(nft_id).iter().map(|tx| tx.id).collect();
+
+    corrupt_transaction(ids[2], |tx| tx.from = user(4));
+    let report = verify_nft_provenance(nft_id).unwrap();
+    assert!(!report.is_consistent);
+    assert_eq!(
+        report.issues,
+        vec![format!("Transaction {} ends with {} but transaction {} starts from {}", ids[1], user(2), ids[2], user(4))]
+    );
+
+    corrupt_transaction(ids[2], |tx| tx.from = user(2));
+    corrupt_transaction(ids[0], |tx| tx.transaction_type = "sale".to_string());
+    NFTS.with(|nfts| nfts.borrow_mut().get_mut(&nft_id).unwrap().current_owner = user(5));
+    let issues = verify_nft_provenance(nft_id).unwrap().issues;
+    assert_eq!(issues.len(), 2);
+    assert!(issues[0].contains("must start with a mint"));
+    assert!(issues[1].contains(&format!("the current owner is {}", user(5))));
+}
+
+// Mint fee
+
+#[test]
+fn minting_is_free_by_default() {
+    use_ledger();
+    assert_eq!(get_mint_fee().amount, 0);
+    assert!(mint_with(1, MintArgs::default()).is_ok());
+    assert!(mock::take_transfers().is_empty());
+}
+
+#[test]
+fn a_mint_fee_is_taken_from_the_minters_escrow() {
+    use_ledger();
+    set_mint_fee(50, Some(user(9))).unwrap();
+    mint(1);
+    let transfers = mock::take_transfers();
+    assert_eq!(transfers.len(), 1);
+    assert!(from_escrow_of(&transfers[0], 1) && paid_to(&transfers[0], 9));
+    assert_eq!(transfers[0].amount, 50);
+
+    // An unfunded escrow means the fee isn't paid, and nothing is minted
+    mock::refuse_transfers(|transfer| from_escrow_of(transfer, 2));
+    let error = mint_with(2, MintArgs::default()).unwrap_err();
+    assert!(error.contains("Mint fee of 50 could not be collected"));
+    assert_eq!(get_marketplace_stats().total_nfts, 1);
+    assert_eq!(after_upgrade(|| get_mint_fee().amount), 50);
+}
+
+#[test]
+fn mint_fees_need_a_recipient_and_a_ledger() {
+    install();
+    assert!(set_mint_fee(50, Some(user(9))).unwrap_err().contains("ledger"));
+    use_ledger();
+    assert!(set_mint_fee(50, None).unwrap_err().contains("recipient"));
+    call_as(1);
+    assert!(set_mint_fee(50, Some(user(9))).is_err());
+    assert_eq!(get_mint_fee().amount, 0);
+}
+
+// User summary
+
+fn counts_of(n: u8) -> (u64, u64, u64, u64, u64) {
+    let summary = get_user_summary(format!(" {} ", user(n)));
+    (summary.created, summary.owned, summary.owned_for_sale, summary.royalties_earned, summary.projects_owned)
+}
+
+#[test]
+fn the_summary_counts_each_kind_of_holding() {
+    // User 1 created NFT 1, sold it to 2, who resold it to 3 at 300 with a 10% royalty
+    populate();
+    mint(1);
+    mint_with(1, MintArgs { list_immediately: Some(false), ..Default::default() }).unwrap();
+    project_of(1);
+
+    assert_eq!(counts_of(1), (3, 2, 1, 30, 2));
+    assert_eq!(counts_of(2), (1, 1, 1, 0, 0));
+    assert_eq!(counts_of(3), (0, 1, 0, 0, 0));
+    assert_eq!(counts_of(4), (0, 0, 0, 0, 0));
+}
+
+#[test]
+fn unpaid_royalties_are_not_counted_as_earned() {
+    let nft_id = resale_listing();
+    mock::refuse_transfers(|transfer| paid_to(transfer, 1));
+    buy(3, nft_id).unwrap();
+    assert_eq!(counts_of(1).3, 0);
+    mock::accept_transfers();
+    call_as(1);
+    let transaction_id = get_pending_royalties(user(1))[0].transaction_id;
+    block_on(retry_failed_royalty(transaction_id)).unwrap();
+    assert_eq!(counts_of(1).3, 100);
+}
+
+// Track replacement
+
+#[test]
+fn a_replaced_track_keeps_its_id_and_position() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    for (uploader, track_id) in [(1, 10), (2, 11), (1, 12)] {
+        upload(uploader, project_id, track_id, &cid(track_id as u8)).unwrap();
+    }
+    mock::advance_time(DAY);
+    call_as(2);
+    assert_eq!(replace_track(project_id, 11, format!(" {} ", cid(99)), "Fixed".to_string()), Ok("Track 11 replaced".to_string()));
+
+    let tracks = get_project_tracks(project_id);
+    assert_eq!(tracks.iter().map(|track| track.id).collect::<Vec<_>>(), vec![10, 11, 12]);
+    assert_eq!((tracks[1].name.as_str(), &tracks[1].ipfs_hash), ("Fixed", &cid(99)));
+    assert_eq!(tracks[1].timestamp, (mock::START_TIME + DAY) / 1_000_000);
+    assert_eq!(tracks[0].ipfs_hash, cid(10));
+}
+
+#[test]
+fn only_the_owner_or_uploader_can_replace_a_track() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    join(project_id, 1, 3);
+    upload(2, project_id, 10, &cid(1)).unwrap();
+
+    call_as(3);
+    assert!(replace_track(project_id, 10, cid(2), "Mine".to_string()).unwrap_err().contains("owner or the track uploader"));
+    call_as(1);
+    assert!(replace_track(project_id, 10, cid(2), "Owner's".to_string()).is_ok());
+    assert!(replace_track(project_id, 99, cid(2), "Missing".to_string()).is_err());
+    assert!(replace_track(project_id, 10, "bad hash".to_string(), "Bad".to_string()).is_err());
+    assert!(replace_track(project_id, 10, cid(3), String::new()).is_err());
+    assert_eq!(get_project_tracks(project_id)[0].ipfs_hash, cid(2));
+}
+
+// Ownership distribution
+
+#[test]
+fn the_distribution_counts_holdings_per_owner() {
+    assert!(get_ownership_distribution().is_empty());
+    assert_eq!(get_unique_owner_count(), 0);
+    for creator in [1, 1, 1, 2, 3] {
+        mint(creator);
+    }
+    buy(2, 1).unwrap();
+    buy(4, 5).unwrap();
+
+    // Users 1 and 2 hold two each, so they are ordered by principal text
+    let mut expected = vec![(user(1), 2), (user(2), 2)];
+    expected.sort();
+    expected.push((user(4), 1));
+    assert_eq!(get_ownership_distribution(), expected);
+    assert_eq!(get_unique_owner_count(), 3);
+}
+
+// Pinata credential check
+
+#[test]
+fn working_credentials_report_pinatas_message() {
+    with_credentials();
+    mock::respond_with(|_| http_response(200, r#"{"message":"Congratulations! You are communicating with the Pinata API!"}"#));
+    assert_eq!(
+        block_on(test_pinata_credentials()),
+        Ok("Congratulations! You are communicating with the Pinata API!".to_string())
+    );
+    let requests = mock::take_http_requests();
+    assert_eq!(requests[0].url, "https://api.pinata.cloud/data/testAuthentication");
+    assert_eq!((header(&requests[0], "pinata_api_key"), header(&requests[0], "pinata_secret_api_key")), (Some("key"), Some(SECRET)));
+    assert!(requests[0].transform.is_some());
+}
+
+#[test]
+fn rejected_credentials_report_the_parsed_reason() {
+    with_credentials();
+    mock::respond_with(|_| http_response(401, r#"{"error":{"reason":"INVALID_API_KEYS","details":"Invalid API key provided"}}"#));
+    assert_eq!(block_on(test_pinata_credentials()), Err("Pinata API error 401: Invalid API key provided".to_string()));
+    mock::respond_with(|_| http_response(401, "Unauthorized"));
+    assert_eq!(block_on(test_pinata_credentials()), Err("Pinata API error: 401".to_string()));
+}
+
+#[test]
+fn the_credential_check_is_admin_only_and_needs_credentials() {
+    install();
+    assert!(block_on(test_pinata_credentials()).unwrap_err().contains("not been configured"));
+    set_pinata_credentials("key".to_string(), SECRET.to_string()).unwrap();
+    call_as(1);
+    assert!(block_on(test_pinata_credentials()).is_err());
+    assert!(mock::take_http_requests().is_empty());
+}
+
+// Batch listing
+
+#[test]
+fn a_mixed_batch_reports_each_id() {
+    let unlisted = |price| mint_with(1, MintArgs { price, list_immediately: Some(false), ..Default::default() }).unwrap();
+    let first = unlisted(100);
+    let second = unlisted(200);
+    let unpriced = unlisted(0);
+    let theirs = mint(2);
+
+    call_as(1);
+    let results = batch_set_for_sale(vec![first, theirs, 9999, unpriced, second], true);
+    let ids: Vec<u64> = results.iter().map(|(nft_id, _)| *nft_id).collect();
+    assert_eq!(ids, vec![first, theirs, 9999, unpriced, second]);
+    assert_eq!(results[0].1, Ok(()));
+    assert!(matches!(results[1].1, Err(MarketError::Unauthorized(_))));
+    assert_eq!(results[2].1, Err(MarketError::NotFound));
+    assert!(matches!(results[3].1, Err(MarketError::InvalidPrice(_))));
+    assert_eq!(results[4].1, Ok(()));
+
+    let listed = |nft_id| get_nft(nft_id).unwrap().is_for_sale;
+    assert!(listed(first) && listed(second) && !listed(unpriced) && listed(theirs));
+    assert_eq!(get_marketplace_stats_cached(), get_marketplace_stats());
+
+    // Delisting needs no price
+    let results = batch_set_for_sale(vec![first, unpriced], false);
+    assert!(results.iter().all(|(_, result)| result.is_ok()));
+    assert!(!listed(first));
+}
+
+#[test]
+fn entries_past_the_batch_cap_are_refused() {
+    let nft_id = mint(1);
+    call_as(1);
+    let results = batch_set_for_sale(vec![nft_id; 101], false);
+    assert!(results[..100].iter().all(|(_, result)| result.is_ok()));
+    assert!(matches!(results[100].1, Err(MarketError::InvalidInput(_))));
+}
+
+// Name lookup
+
+fn named(name: &str) -> u64 {
+    mint_with(1, MintArgs { name: name.to_string(), ..Default::default() }).unwrap()
+}
+
+fn found(query: &str, exact: bool) -> Vec<u64> {
+    find_nfts_by_name(query.to_string(), exact).unwrap().into_iter().map(|nft| nft.id).collect()
+}
+
+#[test]
+fn exact_and_partial_name_lookups() {
+    install();
+    let night = named("Night Drive");
+    let remix = named("Night Drive (Remix)");
+    let day = named("Day Drive");
+
+    assert_eq!(found(" night drive ", true), vec![night]);
+    assert!(found("Night", true).is_empty());
+    assert_eq!(found("NIGHT", false), vec![night, remix]);
+    assert_eq!(found("drive", false), vec![night, remix, day]);
+
+    hide(remix);
+    assert_eq!(found("night", false), vec![night]);
+}
+
+#[test]
+fn name_lookups_reject_empty_and_short_queries() {
+    named("A");
+    assert_eq!(find_nfts_by_name("  ".to_string(), true).err().as_deref(), Some("Name query cannot be empty"));
+    assert!(find_nfts_by_name(String::new(), false).is_err());
+    assert!(find_nfts_by_name("a".to_string(), false).is_err());
+    assert_eq!(found("a", true).len(), 1);
+}
+
+// User transactions
+
+fn history_of(n: u8) -> Vec<(u64, String)> {
+    get_user_transactions(user(n)).into_iter().map(|tx| (tx.nft_id, tx.transaction_type)).collect()
+}
+
+#[test]
+fn a_users_history_is_their_own_newest_first() {
+    let bought = mint(1);
+    mock::advance_time(DAY);
+    let own = mint(2);
+    mock::advance_time(DAY);
+    buy(2, bought).unwrap();
+    mock::advance_time(DAY);
+    let unrelated = mint(3);
+    buy(4, unrelated).unwrap();
+    call_as(2);
+    transfer_nft(own, user(5)).unwrap();
+
+    let entry = |nft_id, kind: &str| (nft_id, kind.to_string());
+    assert_eq!(history_of(2), vec![entry(own, "transfer"), entry(bought, "sale"), entry(own, "mint")]);
+    assert_eq!(history_of(3), vec![entry(unrelated, "sale"), entry(unrelated, "mint")]);
+    assert!(history_of(6).is_empty());
+    assert!(get_user_transactions("user".to_string()).is_empty());
+}
+
+#[test]
+fn user_history_pages_carry_the_total() {
+    for _ in 0..5 {
+        mint(1);
+        mock::advance_time(1);
+    }
+    let newest_first: Vec<u64> = get_user_transactions(user(1)).iter().map(|tx| tx.nft_id).collect();
+    assert_eq!(newest_first, vec![5, 4, 3, 2, 1]);
+    let page = get_user_transactions_paged(user(1), 1, 2);
+    assert_eq!(page.total, 5);
+    assert_eq!(page.transactions.iter().map(|tx| tx.nft_id).collect::<Vec<_>>(), vec![4, 3]);
+    assert!(get_user_transactions_paged(user(1), 10, 2).transactions.is_empty());
+}
+
+// Royalties after a gift
+
+#[test]
+fn a_gifted_nft_still_pays_its_creator_on_resale() {
+    use_ledger();
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    call_as(1);
+    transfer_nft(nft_id, user(2)).unwrap();
+    relist(2, nft_id, 1000);
+    mock::take_transfers();
+
+    buy(3, nft_id).unwrap();
+    let nft = get_nft(nft_id).unwrap();
+    assert_eq!((nft.creator, nft.royalty_percentage, nft.current_owner), (user(1), 10, user(3)));
+
+    let payments = get_nft_royalty_payments(nft_id);
+    assert_eq!(payments.len(), 1);
+    assert_eq!((payments[0].recipient.clone(), payments[0].amount, payments[0].status.as_str()), (user(1), 100, "paid"));
+    let transfers = mock::take_transfers();
+    assert_eq!((paid_total(&transfers, 1), paid_total(&transfers, 2)), (100, 900));
+}
+
+// Stale listings
+
+fn stale(older_than_ns: u64) -> Vec<u64> {
+    get_stale_listings(older_than_ns).into_iter().map(|nft| nft.id).collect()
+}
+
+#[test]
+fn only_listings_untouched_past_the_threshold_are_stale() {
+    let old = mint(1);
+    let repriced = mint(1);
+    let delisted = mint(1);
+    let listed_late = mint_with(1, MintArgs { list_immediately: Some(false), ..Default::default() }).unwrap();
+    call_as(1);
+    set_nft_for_sale(delisted, false).unwrap();
+
+    mock::advance_time(2 * DAY);
+    call_as(1);
+    update_nft_price(repriced, 150).unwrap();
+    set_nft_for_sale(listed_late, true).unwrap();
+    let fresh = mint(1);
+    mock::advance_time(DAY);
+
+    assert_eq!(stale(2 * DAY), vec![old]);
+    assert_eq!(stale(DAY / 2), vec![old, repriced, listed_late, fresh]);
+    assert!(stale(4 * DAY).is_empty());
+    assert_eq!(get_nft(repriced).unwrap().last_listed_at, mock::START_TIME + 2 * DAY);
+}
+
+// Contributor invites
+
+fn invites_of(n: u8) -> Vec<u64> {
+    call_as(n);
+    get_my_invites()
+}
+
+#[test]
+fn an_accepted_invite_joins_the_project() {
+    let project_id = project_of(1);
+    assert_eq!(invite_contributor(project_id, format!(" {} ", user(2))), Ok(format!("Invited {} to project {}", user(2), project_id)));
+    assert!(invite_contributor(project_id, user(2)).unwrap_err().contains("already been invited"));
+    assert!(members(project_id).is_empty());
+    assert_eq!(invites_of(2), vec![project_id]);
+    assert_eq!(after_upgrade(move || invites_of(2)), vec![project_id]);
+
+    call_as(2);
+    assert_eq!(accept_invite(project_id), Ok(format!("Joined project {}", project_id)));
+    assert_eq!(members(project_id), vec![user(2)]);
+    assert!(invites_of(2).is_empty());
+    assert!(accept_invite(project_id).is_err());
+    call_as(1);
+    assert!(invite_contributor(project_id, user(2)).unwrap_err().contains("Already a member"));
+}
+
+#[test]
+fn a_declined_invite_is_dropped() {
+    let project_id = project_of(1);
+    invite_contributor(project_id, user(2)).unwrap();
+    call_as(2);
+    assert!(decline_invite(project_id).is_ok());
+    assert!(invites_of(2).is_empty());
+    call_as(2);
+    assert!(accept_invite(project_id).is_err());
+    assert!(decline_invite(project_id).is_err());
+    assert!(members(project_id).is_empty());
+}
+
+#[test]
+fn only_owners_invite_and_only_invitees_accept() {
+    let project_id = project_of(1);
+    join(project_id, 1, 2);
+    call_as(2);
+    assert!(invite_contributor(project_id, user(3)).unwrap_err().contains("owner"));
+    call_as(1);
+    assert!(invite_contributor(project_id, user(1)).is_err());
+    assert!(invite_contributor(9999, user(3)).is_err());
+    invite_contributor(project_id, user(3)).unwrap();
+
+    call_as(4);
+    assert_eq!(accept_invite(project_id), Err("No pending invite for this project".to_string()));
+    assert_eq!(members(project_id), vec![user(2)]);
+}
+
+// Engagement
+
+#[test]
+fn engagement_weights_views_wishlists_and_sales() {
+    let nft_id = mint(1);
+    let quiet = mint(1);
+    for viewer in 2..5 {
+        view_as(viewer, nft_id);
+    }
+    for fan in [2, 3] {
+        call_as(fan);
+        add_to_wishlist(nft_id).unwrap();
+    }
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 200);
+    buy(3, nft_id).unwrap();
+
+    let engagement = get_nft_engagement(nft_id).unwrap();
+    assert_eq!((engagement.views, engagement.favorites, engagement.sales), (3, 2, 2));
+    let expected = 3 * ENGAGEMENT_VIEW_WEIGHT + 2 * ENGAGEMENT_FAVORITE_WEIGHT + 2 * ENGAGEMENT_SALE_WEIGHT;
+    assert_eq!(engagement.score, expected);
+    assert_eq!(expected, 53);
+
+    assert_eq!(get_nft_engagement(quiet).map(|engagement| engagement.score), Some(0));
+    assert!(get_nft_engagement(9999).is_none());
+}
+
+// Response size budget
+
+fn inflate(nft_id: u64, bytes: usize) {
+    NFTS.with(|nfts| nfts.borrow_mut().get_mut(&nft_id).unwrap().description = "x".repeat(bytes));
+}
+
+#[test]
+fn oversized_pages_stop_early_with_a_usable_cursor() {
+    let ids: Vec<u64> = (0..6).map(|_| mint(1)).collect();
+    for &nft_id in &ids {
+        inflate(nft_id, RESPONSE_SIZE_BUDGET / 4);
+    }
+
+    let first = list_nfts_after(None, 10);
+    assert!(first.truncated);
+    assert_eq!(page_ids(&first), ids[..3].to_vec());
+    assert_eq!(first.next_cursor, Some(ids[2]));
+
+    let second = list_nfts_after(first.next_cursor, 10);
+    assert!(!second.truncated);
+    assert_eq!(page_ids(&second), ids[3..].to_vec());
+    assert_eq!(second.next_cursor, None);
+}
+
+#[test]
+fn a_record_over_the_budget_still_moves_the_cursor() {
+    let big = mint(1);
+    let small = mint(1);
+    inflate(big, RESPONSE_SIZE_BUDGET + 1);
+
+    let page = list_nfts_after(None, 10);
+    assert_eq!((page_ids(&page), page.truncated, page.next_cursor), (vec![big], true, Some(big)));
+    assert_eq!(page_ids(&list_nfts_after(page.next_cursor, 10)), vec![small]);
+}
+
+#[test]
+fn unpaged_listings_stop_at_the_budget() {
+    let ids: Vec<u64> = (0..6).map(|_| mint(1)).collect();
+    for &nft_id in &ids {
+        inflate(nft_id, RESPONSE_SIZE_BUDGET / 4);
+    }
+
+    let listed: Vec<u64> = list_nfts().iter().map(|nft| nft.id).collect();
+    assert_eq!(listed, ids[..3].to_vec());
+    assert_eq!(get_project_nfts(0).len(), 3);
+    assert_eq!(get_nfts_by_ids(ids.clone()).len(), 3);
+}
+
+#[test]
+fn offset_listings_continue_after_a_short_page() {
+    let ids: Vec<u64> = (0..6).map(|_| mint(1)).collect();
+    for &nft_id in &ids {
+        inflate(nft_id, RESPONSE_SIZE_BUDGET / 4);
+    }
+
+    let sale_ids = |offset| -> Vec<u64> { get_nfts_for_sale(offset, 10, true).iter().map(|nft| nft.id).collect() };
+    let first = sale_ids(0);
+    assert_eq!(first, ids[..3].to_vec());
+    assert_eq!(sale_ids(first.len() as u64), ids[3..].to_vec());
+    assert_eq!(get_nfts_by_category("music".to_string(), 3, 10).len(), 3);
+}
+
+// Royalties per NFT
+
+#[test]
+fn every_resale_royalty_is_listed_and_summed() {
+    let nft_id = mint_with(1, MintArgs { price: 1000, royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    let other = mint_with(1, MintArgs { royalty_percentage: Some(10), ..Default::default() }).unwrap();
+    buy(2, nft_id).unwrap();
+    relist(2, nft_id, 2000);
+    buy(3, nft_id).unwrap();
+    relist(3, nft_id, 500);
+    buy(4, nft_id).unwrap();
+    buy(2, other).unwrap();
+
+    // The creator's own first sale pays no royalty
+    let payments = get_nft_royalty_payments(nft_id);
+    let amounts: Vec<(String, u64)> = payments.iter().map(|p| (p.recipient.clone(), p.amount)).collect();
+    assert_eq!(amounts, vec![(user(1), 200), (user(1), 50)]);
+    assert!(payments.iter().all(|p| p.nft_id == nft_id));
+    assert_eq!(get_nft_total_royalties(nft_id), 250);
+
+    assert!(get_nft_royalty_payments(other).is_empty());
+    assert_eq!(get_nft_total_royalties(other), 0);
+    assert_eq!(get_nft_total_royalties(9999), 0);
+}
+
+// Initial sale state
+
+#[test]
+fn mints_are_listed_unless_asked_otherwise() {
+    let listed = mint_with(1, MintArgs { price: 300, ..Default::default() }).unwrap();
+    let explicit = mint_with(1, MintArgs { list_immediately: Some(true), ..Default::default() }).unwrap();
+    let private = mint_with(1, MintArgs { price: 0, list_immediately: Some(false), ..Default::default() }).unwrap();
+    let state = |nft_id| get_nft(nft_id).map(|nft| (nft.is_for_sale, nft.price)).unwrap();
+    assert_eq!((state(listed), state(explicit), state(private)), ((true, 300), (true, 100), (false, 0)));
+    assert_eq!(get_marketplace_stats_cached().nfts_for_sale, 2);
+
+    assert!(mint_with(1, MintArgs { price: 0, ..Default::default() }).unwrap_err().contains("greater than zero"));
+    // An unpriced NFT needs a price before it can be listed or bought
+    call_as(1);
+    assert!(set_nft_for_sale(private, true).is_err());
+    assert_eq!(buy(2, private), Err(MarketError::NotForSale));
+    relist(1, private, 250);
+    assert_eq!(state(private), (true, 250));
+}
+
+// Orphaned NFTs
+
+#[test]
+fn orphans_are_found_and_reassigned() {
+    install();
+    let project_id = project_of(1);
+    let linked = mint_with(1, MintArgs { project_id, ..Default::default() }).unwrap();
+    let orphan = mint_with(1, MintArgs { project_id: 9999, ..Default::default() }).unwrap();
+    let detached = mint_with(1, MintArgs { project_id: 8888, ..Default::default() }).unwrap();
+    mint(1);
+
+    call_as(ADMIN_USER);
+    assert_eq!(find_orphaned_nfts(), Ok(vec![orphan, detached]));
+    assert_eq!(reassign_nft_project(orphan, project_id), Ok(format!("NFT {} moved to project {}", orphan, project_id)));
+    reassign_nft_project(detached, 0).unwrap();
+    assert_eq!(find_orphaned_nfts(), Ok(vec![]));
+    assert_eq!(get_project_nfts(project_id).iter().map(|nft| nft.id).collect::<Vec<_>>(), vec![linked, orphan]);
+}
+
+#[test]
+fn orphan_repair_is_admin_only_and_checks_the_target() {
+    install();
+    let orphan = mint_with(1, MintArgs { project_id: 9999, ..Default::default() }).unwrap();
+    call_as(1);
+    assert!(find_orphaned_nfts().is_err());
+    assert!(reassign_nft_project(orphan, 0).is_err());
+
+    call_as(ADMIN_USER);
+    assert_eq!(reassign_nft_project(orphan, 7777), Err("Project not found".to_string()));
+    assert_eq!(reassign_nft_project(4444, 0), Err("NFT not found".to_string()));
+    assert_eq!(find_orphaned_nfts(), Ok(vec![orphan]));
+}
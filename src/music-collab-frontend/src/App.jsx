@@ -137,11 +137,12 @@ const initializeApp = async (userInfo) => {
       const actor = authService.getActor();
       if (!actor) throw new Error('No authenticated actor available');
       
-      const projectId = await actor.create_project(
+      const result = await actor.create_project(
         projectData.title,
         projectData.description,
-        projectData.owner
+        user.principal
       );
+      if ('Err' in result) throw new Error(result.Err);
       await loadProjects();
       setCurrentView('projects');
       
@@ -176,14 +177,23 @@ const initializeApp = async (userInfo) => {
       const actor = authService.getActor();
       if (!actor) throw new Error('No authenticated actor available');
       
-      const nftId = await actor.mint_nft(
+      const result = await actor.mint_nft(
         nftData.name,
         nftData.description,
         nftData.image_url,
         nftData.creator,
         BigInt(nftData.project_id),
-        BigInt(nftData.price)
+        BigInt(nftData.price),
+        nftData.category || 'music',
+        nftData.animation_url ? [nftData.animation_url] : [],
+        nftData.audio_preview_url ? [nftData.audio_preview_url] : [],
+        nftData.ipfs_hash ? [nftData.ipfs_hash] : [],
+        nftData.royalty_percentage != null ? [Number(nftData.royalty_percentage)] : [],
+        nftData.min_resale_price != null ? [BigInt(nftData.min_resale_price)] : [],
+        nftData.list_immediately != null ? [Boolean(nftData.list_immediately)] : []
       );
+      if ('Err' in result) throw new Error(result.Err);
+      const nftId = result.Ok;
       await loadNFTs();
       
       // Show success toast
@@ -22,15 +22,15 @@ const CollaborationHub = ({ project, onBack, user }) => {
       if (!actor) throw new Error('No authenticated actor available');
       
       // Add track to the project using the existing backend function
-      const success = await actor.add_track(
+      const result = await actor.add_track(
         BigInt(project.id),
         trackData.name,
         trackData.ipfsHash,
-        trackData.uploadedBy,
-        BigInt(Date.now()) // Convert to BigInt for IC
+        BigInt(Date.now()), // Convert to BigInt for IC
+        []
       );
       
-      if (success) {
+      if ('Ok' in result) {
         // Show success toast instead of alert
         if (window.showToast) {
           window.showToast(`🎵 Track "${trackData.name}" added to project successfully!`, 'creation');
@@ -38,7 +38,7 @@ const CollaborationHub = ({ project, onBack, user }) => {
         // Reset the active tab to show the session view
         setActiveTab('session');
       } else {
-        throw new Error('Failed to add track to project');
+        throw new Error(result.Err);
       }
     } catch (error) {
       console.error('Error uploading track:', error);
@@ -1,5 +1,5 @@
 import React, { useState } from 'react';
-import { music_collab_backend } from 'declarations/music-collab-backend';
+import { authService } from '../services/auth';
 import TrackUpload from './TrackUpload';
 import TrackList from './TrackList';
 import './ProjectDetail.css';
@@ -37,19 +37,18 @@ const ProjectDetail = ({ project, onBack, onUpdate, onStartCollaboration }) => {
       if (!trackData?.ipfsHash) {
         throw new Error('IPFS hash is missing');
       }
-      if (!trackData?.uploadedBy) {
-        throw new Error('Uploaded by field is missing');
-      }
 
-      const result = await music_collab_backend.add_track(
+      // The backend records the caller as uploader, so this must be the signed-in actor
+      const actor = authService.getActor();
+      const result = await actor.add_track(
         BigInt(project.id),
         String(trackData.name),
         String(trackData.ipfsHash),
-        String(trackData.uploadedBy),
-        BigInt(trackData.timestamp || Date.now())
+        BigInt(trackData.timestamp || Date.now()),
+        []
       );
       
-      if (result) {
+      if ('Ok' in result) {
         await onUpdate();
         setShowUpload(false);
         
@@ -57,7 +56,7 @@ const ProjectDetail = ({ project, onBack, onUpdate, onStartCollaboration }) => {
           window.showToast(`Track "${trackData.name}" added successfully!`, 'creation');
         }
       } else {
-        throw new Error('Failed to add track');
+        throw new Error(result.Err);
       }
     } catch (error) {
       console.error('Error adding track:', error);
@@ -74,7 +73,9 @@ const ProjectDetail = ({ project, onBack, onUpdate, onStartCollaboration }) => {
       try {
         // Convert string trackId to BigInt if necessary
         const trackIdNum = typeof trackId === 'string' ? BigInt(trackId.replace(/\D/g, '') || 0) : BigInt(trackId);
-        const success = await music_collab_backend.remove_track(BigInt(project.id), trackIdNum);
+        // Only the owner or the uploader may remove a track, so this must be the signed-in actor
+        const actor = authService.getActor();
+        const success = await actor.remove_track(BigInt(project.id), trackIdNum, []);
         if (success) {
           await onUpdate();
         }
@@ -85,15 +86,22 @@ const ProjectDetail = ({ project, onBack, onUpdate, onStartCollaboration }) => {
   };
 
   const handleAddContributor = async () => {
-    const contributor = prompt('Enter contributor name:');
+    const contributor = prompt('Enter contributor principal:');
     if (contributor) {
       try {
-        const success = await music_collab_backend.add_contributor(BigInt(project.id), contributor);
-        if (success) {
+        // Only the project owner may add contributors, so this must be the signed-in actor
+        const actor = authService.getActor();
+        const result = await actor.add_contributor(BigInt(project.id), contributor);
+        if ('Ok' in result) {
           await onUpdate();
+        } else {
+          throw new Error(result.Err);
         }
       } catch (error) {
         console.error('Error adding contributor:', error);
+        if (window.showToast) {
+          window.showToast(`Failed to add contributor: ${error.message}`, 'error');
+        }
       }
     }
   };
@@ -4,8 +4,7 @@ import './ProjectForm.css';
 const ProjectForm = ({ onSubmit, onCancel, loading }) => {
   const [formData, setFormData] = useState({
     title: '',
-    description: '',
-    owner: ''
+    description: ''
   });
 
   const handleChange = (e) => {
@@ -17,7 +16,7 @@ const ProjectForm = ({ onSubmit, onCancel, loading }) => {
 
   const handleSubmit = (e) => {
     e.preventDefault();
-    if (formData.title && formData.description && formData.owner) {
+    if (formData.title && formData.description) {
       onSubmit(formData);
     }
   };
@@ -58,19 +57,6 @@ const ProjectForm = ({ onSubmit, onCancel, loading }) => {
           />
         </div>
 
-        <div className="form-group">
-          <label htmlFor="owner">Owner Name *</label>
-          <input
-            type="text"
-            id="owner"
-            name="owner"
-            value={formData.owner}
-            onChange={handleChange}
-            placeholder="Your name"
-            required
-          />
-        </div>
-
         <div className="form-actions">
           <button type="button" className="btn-secondary" onClick={onCancel}>
             Cancel
@@ -78,7 +64,7 @@ const ProjectForm = ({ onSubmit, onCancel, loading }) => {
           <button 
             type="submit" 
             className="btn-primary"
-            disabled={loading || !formData.title || !formData.description || !formData.owner}
+            disabled={loading || !formData.title || !formData.description}
           >
             {loading ? 'Creating...' : 'Create Project'}
           </button>